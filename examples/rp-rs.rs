@@ -1,31 +1,50 @@
 use std::path::PathBuf;
 
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, ArgGroup, Parser, Subcommand, ValueEnum};
+use colored::Colorize;
 use log::{info, LevelFilter};
 
 use ropgadget_rs::common::GenericResult;
 use ropgadget_rs::cpu;
 
-use ropgadget_rs::collect_all_gadgets;
+use ropgadget_rs::diff::diff_files;
+use ropgadget_rs::engine::DisassemblyEngineType;
+use ropgadget_rs::format::SectionFilter;
 use ropgadget_rs::gadget::InstructionGroup;
-use ropgadget_rs::session::RopGadgetOutput;
+use ropgadget_rs::multi::{collect_shared_gadgets, MultiFileMode, MultiSession};
+use ropgadget_rs::render::{LetterCase, NumberBase, RenderOptions, Syntax};
+use ropgadget_rs::search::{
+    parse_bytes, search_bytes, search_constants, search_instructions, search_strings,
+    search_writable, StringEncoding,
+};
+use ropgadget_rs::section::Permission;
+use ropgadget_rs::session::{RopGadgetOrder, RopGadgetOutput};
 use ropgadget_rs::session::{RopProfileStrategy, Session};
+use ropgadget_rs::write_all_gadgets;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about)] // Read from `Cargo.toml`
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The file to parse
-    #[arg(value_name = "FILE")]
-    filepath: PathBuf,
+    #[arg(value_name = "FILE", required = true)]
+    filepath: Option<PathBuf>,
 
     /// The number of threads to use
     #[arg(short, long = "number-of-threads", default_value_t = 4)]
     thread_num: u8,
 
-    /// Write gadget to file (optional, defaults to stdout)
+    /// Write gadget to file (optional, defaults to stdout, or to `gadgets.db` for SQLite)
     #[arg(short, long = "output-file", value_name = "OUTPUT")]
     output_file: Option<PathBuf>,
 
+    /// The format of the output
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+
     /// The verbosity level
     #[arg(short, long = "verbose", action = clap::ArgAction::Count)]
     verbosity: u8,
@@ -34,6 +53,10 @@ pub struct Args {
     #[arg(short, long, action = ArgAction::SetTrue)]
     unique: bool,
 
+    /// Group the gadgets which are semantically equivalent (implies --unique)
+    #[arg(long)]
+    merge_equivalent: bool,
+
     /// Force the architecture to given value
     #[arg(long, value_enum)]
     architecture: Option<cpu::CpuType>,
@@ -49,26 +72,339 @@ pub struct Args {
     #[arg(long)]
     no_color: bool,
 
+    /// The assembly syntax of the gadgets (AT&T and MASM for x86 and x64 only)
+    #[arg(long, value_enum, default_value_t = Syntax::Intel)]
+    syntax: Syntax,
+
+    /// The base of the immediate values
+    #[arg(long, value_enum, default_value_t = NumberBase::Hex)]
+    immediates: NumberBase,
+
+    /// Write the instructions in uppercase
+    #[arg(long)]
+    uppercase: bool,
+
+    /// Show the bytes of each instruction
+    #[arg(long)]
+    raw_bytes: bool,
+
     /// The maximum number of instructions in a gadget
     #[arg(long, default_value_t = 6)]
     max_insn_per_gadget: u8,
 
-    /// The maximum size of the gadget
+    /// The maximum size of the gadget, in bytes
     #[arg(long, default_value_t = 32)]
     max_size: u8,
 
-    /// The type of gadgets to focus on (default - return only)
+    /// The type of gadgets to focus on (default - return only, also when none is given). With the
+    /// fast profile, only the first terminating instruction of each type is looked for
     #[arg(long, value_enum)]
     rop_types: Vec<InstructionGroup>,
 
     /// The profile type (default - fast)
     #[arg(long, value_enum, default_value_t = RopProfileStrategy::Fast)]
     profile_type: RopProfileStrategy,
+
+    /// The disassembly engine (the others than capstone must be enabled as features)
+    #[arg(long, value_enum, default_value_t = DisassemblyEngineType::Capstone)]
+    engine: DisassemblyEngineType,
+
+    /// How to sort the gadgets (`found` streams them to the output without keeping them in memory)
+    #[arg(long, value_enum, default_value_t = RopGadgetOrder::Address)]
+    sort: RopGadgetOrder,
+
+    /// Emulate the gadgets and discard those which fault or loop. The gadgets with instructions
+    /// the emulator does not model are kept (see --emulate-strict)
+    #[arg(long)]
+    emulate: bool,
+
+    /// Emulate the gadgets, and also discard those with instructions the emulator does not model
+    #[arg(long)]
+    emulate_strict: bool,
+
+    /// Only keep the gadgets in the functions matching the pattern (`*` and `?` are wildcards),
+    /// e.g. `__libc_csu_*`
+    #[arg(long, value_name = "PATTERN")]
+    symbol: Option<String>,
+
+    /// Cache the gadgets on disk, to speed up the next runs on the same file
+    #[arg(long)]
+    cache: bool,
+
+    /// The cache directory (implies --cache)
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+}
+
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
+enum OutputFormat {
+    /// One `address | gadget` per line
+    #[default]
+    Text,
+    /// A single JSON document
+    Json,
+    /// One JSON object per gadget and per line
+    Ndjson,
+    /// A Python module for exploit scripts (e.g. pwntools)
+    Python,
+    /// Same as ROPgadget
+    Ropgadget,
+    /// Same as rp++
+    Rpp,
+    /// A SQLite database, written to `gadgets.db` unless an output file is given
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare the gadgets of two files
+    Diff {
+        /// The reference file
+        #[arg(value_name = "OLD")]
+        old: PathBuf,
+
+        /// The file to compare against the reference
+        #[arg(value_name = "NEW")]
+        new: PathBuf,
+
+        /// The number of threads to use
+        #[arg(short, long = "number-of-threads", default_value_t = 4)]
+        thread_num: u8,
+
+        /// Only show the gadgets which were removed or moved
+        #[arg(long)]
+        broken_only: bool,
+    },
+
+    /// Look for the gadgets at the same RVA with the same bytes across several files
+    Shared {
+        /// The files to parse
+        #[arg(value_name = "FILES", required = true, num_args = 2..)]
+        files: Vec<PathBuf>,
+
+        /// Whether to show only the common gadgets, or all of them with their origin
+        #[arg(long, value_enum, default_value_t = MultiFileMode::Intersection)]
+        mode: MultiFileMode,
+
+        /// The number of threads to use
+        #[arg(short, long = "number-of-threads", default_value_t = 4)]
+        thread_num: u8,
+    },
+
+    /// Look for a byte pattern or a sequence of instructions in the executable sections
+    Search {
+        /// The file to parse
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[command(flatten)]
+        query: SearchQuery,
+
+        /// The encoding of the string to look for
+        #[arg(long, value_enum, default_value_t = StringEncoding::Ascii)]
+        encoding: StringEncoding,
+
+        /// Hexadecimal bytes which must not appear in the addresses of the data, e.g. `00 0a`
+        #[arg(long, value_name = "BYTES")]
+        bad_chars: Option<String>,
+
+        /// Only keep the code matches in the functions matching the pattern (`*` and `?` are
+        /// wildcards)
+        #[arg(long, value_name = "PATTERN")]
+        symbol: Option<String>,
+
+        /// Force the architecture to given value
+        #[arg(long, value_enum)]
+        architecture: Option<cpu::CpuType>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+#[command(group(
+    ArgGroup::new("query")
+        .required(true)
+        .args(["bytes", "insns", "string", "constant", "writable"])
+))]
+struct SearchQuery {
+    /// Hexadecimal bytes in the executable sections, where `?` matches any nibble, e.g.
+    /// `ff e? ?? c3`
+    #[arg(long)]
+    bytes: Option<String>,
+
+    /// Instructions separated by `;`, where `*` and `?` are wildcards, e.g. `pop r?? ; ret`
+    #[arg(long)]
+    insns: Option<String>,
+
+    /// A NUL-terminated string in any section, e.g. `/bin/sh`
+    #[arg(long)]
+    string: Option<String>,
+
+    /// An integer of the size of a pointer in any section, in decimal or hexadecimal (`0x`)
+    #[arg(long, value_parser = parse_integer)]
+    constant: Option<u64>,
+
+    /// At least SIZE zero bytes in a writable section
+    #[arg(long, value_name = "SIZE", value_parser = parse_integer)]
+    writable: Option<u64>,
+}
+
+fn parse_integer(value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn shared(files: Vec<PathBuf>, mode: MultiFileMode, thread_num: u8) -> GenericResult<()> {
+    let multi = MultiSession::new(files)?.mode(mode).nb_thread(thread_num);
+    let names: Vec<String> = multi
+        .filepaths()
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect();
+
+    for g in collect_shared_gadgets(multi)? {
+        match mode {
+            MultiFileMode::Intersection => {
+                println!("{:#x} | {}", g.rva, g.gadget.text(false));
+            }
+            MultiFileMode::Union => {
+                let origins: Vec<&str> = g.files.iter().map(|i| names[*i].as_str()).collect();
+                println!(
+                    "{:#x} | {} | {}",
+                    g.rva,
+                    g.gadget.text(false),
+                    origins.join(", ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn search(
+    file: PathBuf,
+    query: SearchQuery,
+    encoding: StringEncoding,
+    bad_chars: Option<String>,
+    symbol: Option<String>,
+    architecture: Option<cpu::CpuType>,
+) -> GenericResult<()> {
+    let mut sess = Session::load(file, architecture, SectionFilter::Mapped)?;
+    if let Some(pattern) = symbol {
+        sess = sess.symbol_filter(&pattern);
+    }
+    let bad_chars = match bad_chars {
+        Some(bad_chars) => parse_bytes(&bad_chars)?,
+        None => Vec::new(),
+    };
+
+    //
+    // the code matches are shown with their disassembly, the data ones with their section
+    //
+    let code_matches = match (&query.bytes, &query.insns) {
+        (Some(pattern), _) => Some(search_bytes(&sess, pattern)?),
+        (None, Some(insns)) => Some(search_instructions(&sess, insns)?),
+        (None, None) => None,
+    };
+
+    if let Some(matches) = code_matches {
+        for m in &matches {
+            let preview = match m.preview.is_empty() {
+                true => String::from("(invalid instruction)"),
+                false => m.preview_text(false),
+            };
+            let symbol = match &m.symbol {
+                Some(symbol) => format!(" <{}>", symbol),
+                None => String::new(),
+            };
+            println!(
+                "{:#x}{} | {} | {}",
+                m.address,
+                symbol,
+                m.section.as_deref().unwrap_or("N/A"),
+                preview
+            );
+        }
+        println!("{} match(es)", matches.len());
+        return Ok(());
+    }
+
+    let matches = match (query.string, query.constant, query.writable) {
+        (Some(text), _, _) => search_strings(&sess, &text, encoding, &bad_chars)?,
+        (None, Some(value), _) => search_constants(&sess, value, &bad_chars)?,
+        (None, None, Some(size)) => search_writable(&sess, size as usize, &bad_chars)?,
+        (None, None, None) => Vec::new(),
+    };
+
+    for m in &matches {
+        let rwx: String = [
+            (Permission::READABLE, 'r'),
+            (Permission::WRITABLE, 'w'),
+            (Permission::EXECUTABLE, 'x'),
+        ]
+        .iter()
+        .map(|(p, c)| if m.permission.contains(*p) { *c } else { '-' })
+        .collect();
+        println!(
+            "{:#x} | {} | {} | {:#x} byte(s)",
+            m.address,
+            m.section.as_deref().unwrap_or("N/A"),
+            rwx,
+            m.size
+        );
+    }
+    println!("{} match(es)", matches.len());
+    Ok(())
 }
 
-fn main() -> GenericResult<()> {
-    let args = Args::parse();
+fn diff(old: PathBuf, new: PathBuf, thread_num: u8, broken_only: bool) -> GenericResult<()> {
+    let old_sess = Session::new(old)?.nb_thread(thread_num);
+    let new_sess = Session::new(new)?.nb_thread(thread_num);
+    let res = diff_files(old_sess, new_sess)?;
 
+    for gadget in &res.removed {
+        println!("- {:#x} | {}", gadget.address, gadget.text(false));
+    }
+
+    for (old_gadget, new_gadget) in &res.moved {
+        println!(
+            "~ {:#x} -> {:#x} | {}",
+            old_gadget.address,
+            new_gadget.address,
+            new_gadget.text(false)
+        );
+    }
+
+    if !broken_only {
+        for gadget in &res.added {
+            println!("+ {:#x} | {}", gadget.address, gadget.text(false));
+        }
+    }
+
+    println!(
+        "{} added, {} removed, {} moved, {} unchanged",
+        res.added.len(),
+        res.removed.len(),
+        res.moved.len(),
+        res.unchanged
+    );
+    Ok(())
+}
+
+fn main() {
+    //
+    // report the errors with their context rather than their debug representation
+    //
+    if let Err(e) = run(Args::parse()) {
+        eprintln!("{}: {}", "error".red().bold(), e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> GenericResult<()> {
     let verbosity = match args.verbosity {
         4 => LevelFilter::Trace, // -vvvv
         3 => LevelFilter::Debug, // -vvv
@@ -77,21 +413,87 @@ fn main() -> GenericResult<()> {
         _ => LevelFilter::Error,
     };
 
-    let _output = match args.output_file {
-        None => RopGadgetOutput::Console,
-        Some(fpath) => RopGadgetOutput::File(fpath),
+    match args.command {
+        Some(Command::Diff {
+            old,
+            new,
+            thread_num,
+            broken_only,
+        }) => return diff(old, new, thread_num, broken_only),
+        Some(Command::Shared {
+            files,
+            mode,
+            thread_num,
+        }) => return shared(files, mode, thread_num),
+        Some(Command::Search {
+            file,
+            query,
+            encoding,
+            bad_chars,
+            symbol,
+            architecture,
+        }) => return search(file, query, encoding, bad_chars, symbol, architecture),
+        None => {}
+    }
+
+    let _output = match (args.output_format, args.output_file) {
+        (OutputFormat::Json, fpath) => RopGadgetOutput::Json(fpath),
+        (OutputFormat::Ndjson, fpath) => RopGadgetOutput::Ndjson(fpath),
+        (OutputFormat::Python, fpath) => RopGadgetOutput::Python(fpath),
+        (OutputFormat::Ropgadget, fpath) => RopGadgetOutput::RopGadget(fpath),
+        (OutputFormat::Rpp, fpath) => RopGadgetOutput::RpPlusPlus(fpath),
+        #[cfg(feature = "sqlite")]
+        (OutputFormat::Sqlite, Some(fpath)) => RopGadgetOutput::Sqlite(fpath),
+        #[cfg(feature = "sqlite")]
+        (OutputFormat::Sqlite, None) => RopGadgetOutput::Sqlite(PathBuf::from("gadgets.db")),
+        (OutputFormat::Text, None) => RopGadgetOutput::Console,
+        (OutputFormat::Text, Some(fpath)) => RopGadgetOutput::File(fpath),
+    };
+
+    let filepath = args.filepath.unwrap();
+    let sess = match args.architecture {
+        Some(cpu_type) => Session::with_architecture(filepath, cpu_type),
+        None => Session::new(filepath),
+    }?;
+
+    let case = match args.uppercase {
+        true => LetterCase::Upper,
+        false => LetterCase::Lower,
     };
+    let render_options = RenderOptions::default()
+        .syntax(args.syntax)
+        .immediates(args.immediates)
+        .case(case)
+        .raw_bytes(args.raw_bytes);
 
-    let sess = Session::new(args.filepath)
-        .nb_thread(args.thread_num.into())
+    let mut sess = sess
+        .nb_thread(args.thread_num)
         .output(_output)
         .unique_only(args.unique)
+        .merge_equivalent(args.merge_equivalent)
         .verbosity(verbosity)
-        .use_color(!args.no_color);
+        .use_color(!args.no_color)
+        .render_options(render_options)
+        .emulate(args.emulate || args.emulate_strict)
+        .emulate_strict(args.emulate_strict)
+        .order(args.sort)
+        .max_gadget_length(args.max_insn_per_gadget as usize)
+        .max_gadget_size(args.max_size as usize)
+        .gadget_types(args.rop_types)
+        .profile_type(args.profile_type)
+        .engine_type(args.engine);
 
-    info!("Created session: {:?}", sess);
-    match collect_all_gadgets(sess) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
+    if let Some(pattern) = &args.symbol {
+        sess = sess.symbol_filter(pattern);
+    }
+
+    if args.cache || args.cache_dir.is_some() {
+        let dir = args
+            .cache_dir
+            .unwrap_or_else(ropgadget_rs::cache::GadgetCache::default_directory);
+        sess = sess.cache(dir);
     }
+
+    info!("Created session: {:?}", sess);
+    write_all_gadgets(sess).map(|_| ())
 }
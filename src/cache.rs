@@ -0,0 +1,427 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::common::GenericResult;
+use crate::error::Error;
+use crate::gadget::Gadget;
+use crate::session::Session;
+
+///
+/// Bumped whenever the layout of the index or the gadget finding logic changes, so that stale
+/// indexes get discarded
+///
+const CACHE_VERSION: u32 = 5;
+
+const CACHE_MAGIC: &[u8; 8] = b"RPRSIDX\0";
+
+///
+/// Everything the result of a scan depends on, besides the content of the file. Two sessions
+/// with the same parameters on the same content yield the same gadgets, before grouping and
+/// sorting (which are cheap, and done after loading).
+///
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ScanParameters {
+    version: u32,
+    path: PathBuf,
+    cpu: String,
+    engine: String,
+    max_gadget_length: usize,
+    max_gadget_size: usize,
+    gadget_types: Vec<String>,
+    profile: String,
+    emulate: bool,
+    emulate_strict: bool,
+    symbol_filter: Option<String>,
+}
+
+///
+/// Cheap identity of the content of the file. When it matches the one of an entry, the entry is
+/// used without reading the file; otherwise (e.g. the file was touched) the file is hashed and
+/// compared to the hash of the entry.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    modified: Option<(u64, u32)>,
+}
+
+#[derive(Debug)]
+pub struct CacheParameters {
+    scan: ScanParameters,
+    stamp: FileStamp,
+}
+
+impl CacheParameters {
+    pub fn new(session: &Session) -> GenericResult<Self> {
+        let mut gadget_types: Vec<String> =
+            session.gadget_types.iter().map(|t| t.to_string()).collect();
+        gadget_types.sort();
+
+        let metadata = fs::metadata(session.filepath())?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| (since.as_secs(), since.subsec_nanos()));
+
+        Ok(Self {
+            scan: ScanParameters {
+                version: CACHE_VERSION,
+                path: fs::canonicalize(session.filepath())?,
+                cpu: format!("{:?}", session.info.cpu.cpu_type()),
+                engine: format!("{:?}", session.engine_type),
+                max_gadget_length: session.max_gadget_length,
+                max_gadget_size: session.max_gadget_size,
+                gadget_types,
+                profile: session.profile_type.to_string(),
+                emulate: session.emulate,
+                emulate_strict: session.emulate_strict,
+                symbol_filter: session.symbol_filter.clone(),
+            },
+            stamp: FileStamp {
+                size: metadata.len(),
+                modified,
+            },
+        })
+    }
+
+    ///
+    /// Name of the index in the cache directory
+    ///
+    pub fn key(&self) -> String {
+        let encoded = bincode::serialize(&self.scan).unwrap_or_default();
+        Sha256::digest(encoded)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+fn hash_file(path: &Path) -> GenericResult<Vec<u8>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+///
+/// What an index is checked against before its gadgets are read
+///
+#[derive(Serialize, Deserialize)]
+struct CacheHeader {
+    scan: ScanParameters,
+    stamp: FileStamp,
+    file_hash: Vec<u8>,
+}
+
+//
+// the header is read on its own first, and the gadgets only if the entry is valid. the gadgets
+// are borrowed when storing, so that they are not copied
+//
+#[derive(Serialize)]
+struct CacheIndex<'a> {
+    header: CacheHeader,
+    gadgets: Cow<'a, [Gadget]>,
+}
+
+///
+/// On-disk cache of the gadgets found in a file, keyed by the path of the file and the
+/// parameters of the session, and checked against the content of the file
+///
+#[derive(Debug, Clone)]
+pub struct GadgetCache {
+    directory: PathBuf,
+}
+
+impl GadgetCache {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+
+    ///
+    /// `$XDG_CACHE_HOME/ropgadget-rs`, `~/.cache/ropgadget-rs` or a temporary directory
+    ///
+    pub fn default_directory() -> PathBuf {
+        let base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => match std::env::var_os("HOME").or_else(|| std::env::var_os("LOCALAPPDATA")) {
+                Some(home) => PathBuf::from(home).join(".cache"),
+                None => std::env::temp_dir(),
+            },
+        };
+        base.join("ropgadget-rs")
+    }
+
+    pub fn directory(&self) -> &PathBuf {
+        &self.directory
+    }
+
+    fn index_path(&self, params: &CacheParameters) -> PathBuf {
+        self.directory.join(format!("{}.idx", params.key()))
+    }
+
+    ///
+    /// Load the gadgets of the session, if they were previously stored with the same parameters
+    ///
+    pub fn load(&self, params: &CacheParameters) -> GenericResult<Option<Vec<Gadget>>> {
+        let path = self.index_path(params);
+        if !path.exists() {
+            debug!("No cache entry at '{}'", path.display());
+            return Ok(None);
+        }
+
+        let mut reader = BufReader::new(fs::File::open(&path)?);
+        let mut magic = [0u8; 8];
+        if reader.read_exact(&mut magic).is_err() || &magic != CACHE_MAGIC {
+            warn!("Invalid cache entry '{}', discarding", path.display());
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+
+        let header: CacheHeader = match bincode::deserialize_from(&mut reader) {
+            Ok(header) => header,
+            Err(e) => {
+                warn!(
+                    "Corrupted cache entry '{}' ({}), discarding",
+                    path.display(),
+                    e
+                );
+                fs::remove_file(&path)?;
+                return Ok(None);
+            }
+        };
+
+        //
+        // the file is only hashed if its stamp changed since the entry was stored
+        //
+        let fresh = header.scan == params.scan
+            && (header.stamp == params.stamp || hash_file(&params.scan.path)? == header.file_hash);
+        if !fresh {
+            warn!("Stale cache entry '{}', discarding", path.display());
+            fs::remove_file(&path)?;
+            return Ok(None);
+        }
+
+        let gadgets: Vec<Gadget> = match bincode::deserialize_from(&mut reader) {
+            Ok(gadgets) => gadgets,
+            Err(e) => {
+                warn!(
+                    "Corrupted cache entry '{}' ({}), discarding",
+                    path.display(),
+                    e
+                );
+                fs::remove_file(&path)?;
+                return Ok(None);
+            }
+        };
+
+        info!(
+            "Loaded {} gadgets from cache '{}'",
+            gadgets.len(),
+            path.display()
+        );
+        Ok(Some(gadgets))
+    }
+
+    ///
+    /// Store the gadgets found with the given parameters
+    ///
+    pub fn store(&self, params: CacheParameters, gadgets: &[Gadget]) -> GenericResult<()> {
+        fs::create_dir_all(&self.directory)?;
+        let path = self.index_path(&params);
+
+        //
+        // write to a temporary file first, so concurrent readers never see a partial index
+        //
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        {
+            let mut writer = BufWriter::new(fs::File::create(&tmp)?);
+            writer.write_all(CACHE_MAGIC)?;
+            let index = CacheIndex {
+                header: CacheHeader {
+                    file_hash: hash_file(&params.scan.path)?,
+                    scan: params.scan,
+                    stamp: params.stamp,
+                },
+                gadgets: Cow::Borrowed(gadgets),
+            };
+            bincode::serialize_into(&mut writer, &index)
+                .map_err(|e| Error::IoError(std::io::Error::other(e)))?;
+            writer.flush()?;
+        }
+        fs::rename(&tmp, &path)?;
+
+        info!("Stored gadgets in cache '{}'", path.display());
+        Ok(())
+    }
+
+    ///
+    /// Remove the entry matching the given parameters, if any
+    ///
+    pub fn invalidate(&self, params: &CacheParameters) -> GenericResult<()> {
+        let path = self.index_path(params);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Remove all the entries of the cache
+    ///
+    pub fn clear(&self) -> GenericResult<()> {
+        if !self.directory.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "idx") {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect_all_gadgets;
+    use crate::gadget::InstructionGroup;
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ropgadget-rs-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn cache_roundtrip() {
+        let dir = cache_dir("roundtrip");
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+
+        let first =
+            collect_all_gadgets(Session::new(fname.clone()).unwrap().cache(dir.clone())).unwrap();
+        let params = CacheParameters::new(&Session::new(fname.clone()).unwrap()).unwrap();
+        assert!(GadgetCache::new(dir.clone())
+            .load(&params)
+            .unwrap()
+            .is_some());
+
+        let second = collect_all_gadgets(Session::new(fname).unwrap().cache(dir.clone())).unwrap();
+        assert_eq!(first, second);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parameters_change_the_key() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let base = CacheParameters::new(&Session::new(fname.clone()).unwrap()).unwrap();
+
+        let mut sess = Session::new(fname.clone()).unwrap();
+        sess.gadget_types = vec![InstructionGroup::Ret, InstructionGroup::Jump];
+        assert_ne!(base.key(), CacheParameters::new(&sess).unwrap().key());
+
+        let sess = Session::new(fname.clone()).unwrap().emulate(true);
+        assert_ne!(base.key(), CacheParameters::new(&sess).unwrap().key());
+
+        let other =
+            CacheParameters::new(&Session::new(PathBuf::from("tests/bin/small-x86.pe")).unwrap());
+        assert_ne!(base.key(), other.unwrap().key());
+
+        assert_eq!(
+            base.key(),
+            CacheParameters::new(&Session::new(fname).unwrap())
+                .unwrap()
+                .key()
+        );
+    }
+
+    #[test]
+    fn only_touched_files_are_hashed() {
+        let dir = cache_dir("touched");
+        let cache = GadgetCache::new(dir.join("cache"));
+        let fname = dir.join("small-x64.pe");
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("tests/bin/small-x64.pe", &fname).unwrap();
+        let params = || CacheParameters::new(&Session::new(fname.clone()).unwrap()).unwrap();
+        let set_modified = |secs: u64| {
+            let time = UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            fs::File::options()
+                .write(true)
+                .open(&fname)
+                .unwrap()
+                .set_modified(time)
+                .unwrap();
+        };
+
+        set_modified(1_000_000);
+        cache.store(params(), &[]).unwrap();
+
+        //
+        // same stamp: the entry is used as is, even though the content changed
+        //
+        let mut content = fs::read(&fname).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 0xff;
+        fs::write(&fname, &content).unwrap();
+        set_modified(1_000_000);
+        assert!(cache.load(&params()).unwrap().is_some());
+
+        //
+        // new stamp: the file is hashed, and the entry discarded as the content changed
+        //
+        set_modified(2_000_000);
+        assert!(cache.load(&params()).unwrap().is_none());
+
+        //
+        // new stamp, same content: the entry is still valid
+        //
+        cache.store(params(), &[]).unwrap();
+        set_modified(3_000_000);
+        assert_eq!(cache.load(&params()).unwrap(), Some(Vec::new()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_entries_are_discarded() {
+        let dir = cache_dir("stale");
+        let cache = GadgetCache::new(dir.clone());
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let params = CacheParameters::new(&Session::new(fname.clone()).unwrap()).unwrap();
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(cache.index_path(&params), b"garbage").unwrap();
+        assert!(cache.load(&params).unwrap().is_none());
+        assert!(!cache.index_path(&params).exists());
+
+        cache.store(params, &[]).unwrap();
+        let params = CacheParameters::new(&Session::new(fname).unwrap()).unwrap();
+        assert_eq!(cache.load(&params).unwrap(), Some(Vec::new()));
+
+        cache.clear().unwrap();
+        assert!(cache.load(&params).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
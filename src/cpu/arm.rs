@@ -9,33 +9,41 @@ impl cpu::Cpu for Arm {
 
     fn ptrsize(&self) -> usize {
         4
-        // TODO: thumb
     }
 
     fn ret_insns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
-        vec![(
-            vec![0xd6, 0x5f, 0x03, 0xc0].into_iter().rev().collect(),
-            vec![0xff, 0xff, 0xff, 0xff].into_iter().rev().collect(),
-        )]
+        vec![
+            (
+                vec![0xe1, 0x2f, 0xff, 0x1e].into_iter().rev().collect(),
+                vec![0xff, 0xff, 0xff, 0xff].into_iter().rev().collect(),
+            ), // BX LR
+            (
+                vec![0xe8, 0xbd, 0x80, 0x00].into_iter().rev().collect(),
+                vec![0xff, 0xff, 0x80, 0x00].into_iter().rev().collect(),
+            ), // POP {..., PC}
+            (
+                vec![0xe4, 0x9d, 0xf0, 0x04].into_iter().rev().collect(),
+                vec![0xff, 0xff, 0xff, 0xff].into_iter().rev().collect(),
+            ), // POP {PC}
+        ]
     }
 
     fn call_insns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
         vec![
             (
-                vec![0b1101_0001, 0b0010_1111, 0b1111_1111, 0b0001_0000]
-                    .into_iter()
-                    .rev()
-                    .collect(),
-                vec![0b1111_1111, 0b1111_1111, 0b1111_1111, 0b1111_0000]
-                    .into_iter()
-                    .rev()
-                    .collect(),
-            ), // 4.3 Branch and Exchange (BX)
+                vec![0xe1, 0x2f, 0xff, 0x30].into_iter().rev().collect(),
+                vec![0xff, 0xff, 0xff, 0xf0].into_iter().rev().collect(),
+            ), // BLX REG
         ]
     }
 
     fn jmp_insns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
-        vec![]
+        vec![
+            (
+                vec![0xe1, 0x2f, 0xff, 0x10].into_iter().rev().collect(),
+                vec![0xff, 0xff, 0xff, 0xf0].into_iter().rev().collect(),
+            ), // 4.3 Branch and Exchange (BX)
+        ]
     }
 
     fn insn_step(&self) -> usize {
@@ -49,6 +57,47 @@ impl std::fmt::Debug for Arm {
     }
 }
 
+pub struct Thumb;
+
+impl cpu::Cpu for Thumb {
+    fn cpu_type(&self) -> cpu::CpuType {
+        cpu::CpuType::Thumb
+    }
+
+    fn ptrsize(&self) -> usize {
+        4
+    }
+
+    fn ret_insns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (vec![0x70, 0x47], vec![0xff, 0xff]), // BX LR
+            (vec![0x00, 0xbd], vec![0x00, 0xff]), // POP {..., PC}
+        ]
+    }
+
+    fn call_insns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (vec![0x80, 0x47], vec![0x87, 0xff]), // BLX REG
+        ]
+    }
+
+    fn jmp_insns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (vec![0x00, 0x47], vec![0x87, 0xff]), // BX REG
+        ]
+    }
+
+    fn insn_step(&self) -> usize {
+        2
+    }
+}
+
+impl std::fmt::Debug for Thumb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Thumb").finish()
+    }
+}
+
 pub struct Arm64;
 
 impl cpu::Cpu for Arm64 {
@@ -11,6 +11,8 @@ pub enum CpuType {
     X86,
     X64,
     ARM,
+    /// ARM in Thumb mode
+    Thumb,
     ARM64,
 }
 
@@ -38,6 +40,7 @@ impl std::fmt::Display for CpuType {
             CpuType::X86 => "x86-32",
             CpuType::X64 => "x86-64",
             CpuType::ARM => "ARM",
+            CpuType::Thumb => "Thumb",
             CpuType::ARM64 => "ARM64",
             CpuType::Unknown => "Unknown",
         };
@@ -55,7 +58,7 @@ impl From<&goblin::elf::header::Header> for CpuType {
             goblin::elf::header::EM_X86_64 => CpuType::X64,
             goblin::elf::header::EM_ARM => CpuType::ARM,
             goblin::elf::header::EM_AARCH64 => CpuType::ARM64,
-            _ => panic!("ELF machine format is unsupported"),
+            _ => CpuType::Unknown,
         }
     }
 }
@@ -67,7 +70,7 @@ impl From<&goblin::mach::header::Header> for CpuType {
             goblin::mach::constants::cputype::CPU_TYPE_X86_64 => CpuType::X64,
             goblin::mach::constants::cputype::CPU_TYPE_ARM => CpuType::ARM,
             goblin::mach::constants::cputype::CPU_TYPE_ARM64 => CpuType::ARM64,
-            _ => panic!("MachO is corrupted"),
+            _ => CpuType::Unknown,
         }
     }
 }
@@ -78,9 +81,9 @@ impl From<&goblin::pe::header::CoffHeader> for CpuType {
             goblin::pe::header::COFF_MACHINE_X86 => CpuType::X86,
             goblin::pe::header::COFF_MACHINE_X86_64 => CpuType::X64,
             goblin::pe::header::COFF_MACHINE_ARM => CpuType::ARM,
-            goblin::pe::header::COFF_MACHINE_ARMNT => CpuType::ARM,
+            goblin::pe::header::COFF_MACHINE_ARMNT => CpuType::Thumb,
             goblin::pe::header::COFF_MACHINE_ARM64 => CpuType::ARM64,
-            _ => panic!("Unsupported format"),
+            _ => CpuType::Unknown,
         }
     }
 }
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use log::info;
+
+use crate::collect_all_gadgets;
+use crate::common::GenericResult;
+use crate::cpu::CpuType;
+use crate::error::Error;
+use crate::gadget::{Gadget, GadgetKey};
+use crate::session::{RopGadgetOutput, Session};
+
+///
+/// Differences between the gadgets of two binaries. Gadgets are matched on their semantic
+/// summary (or normalized instructions when no summary is available), not on their address.
+///
+#[derive(Debug, Default)]
+pub struct GadgetDiff {
+    /// Gadgets only found in the new binary
+    pub added: Vec<Gadget>,
+    /// Gadgets only found in the old binary
+    pub removed: Vec<Gadget>,
+    /// Gadgets found in both, but whose addresses changed, as (old, new)
+    pub moved: Vec<(Gadget, Gadget)>,
+    /// Number of gadgets found at the same addresses in both binaries
+    pub unchanged: usize,
+}
+
+impl GadgetDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.moved.is_empty()
+    }
+}
+
+fn index_gadgets(cpu: CpuType, gadgets: &[Gadget]) -> BTreeMap<String, (GadgetKey, Gadget)> {
+    let mut groups: HashMap<GadgetKey, Gadget> = HashMap::new();
+
+    for gadget in gadgets {
+        let key = gadget.key(cpu, true);
+        match groups.get_mut(&key) {
+            Some(canonical) => {
+                canonical.addresses.extend(&gadget.addresses);
+                canonical.addresses.sort_unstable();
+                canonical.addresses.dedup();
+            }
+            None => {
+                groups.insert(key, gadget.clone());
+            }
+        }
+    }
+
+    //
+    // index by normalized text so the result is ordered deterministically
+    //
+    groups
+        .into_iter()
+        .map(|(key, gadget)| {
+            (
+                format!("{}@{:x}", gadget.normalized(), gadget.address),
+                (key, gadget),
+            )
+        })
+        .collect()
+}
+
+///
+/// Compare two sets of gadgets
+///
+pub fn diff_gadgets(cpu: CpuType, old: &[Gadget], new: &[Gadget]) -> GadgetDiff {
+    let old = index_gadgets(cpu, old);
+    let new = index_gadgets(cpu, new);
+
+    let new_by_key: HashMap<&GadgetKey, &Gadget> = new.values().map(|(k, g)| (k, g)).collect();
+    let old_by_key: HashMap<&GadgetKey, &Gadget> = old.values().map(|(k, g)| (k, g)).collect();
+
+    let mut diff = GadgetDiff::default();
+
+    for (key, old_gadget) in old.values() {
+        match new_by_key.get(key) {
+            Some(new_gadget) if new_gadget.addresses == old_gadget.addresses => {
+                diff.unchanged += 1;
+            }
+            Some(new_gadget) => diff.moved.push((old_gadget.clone(), (*new_gadget).clone())),
+            None => diff.removed.push(old_gadget.clone()),
+        }
+    }
+
+    for (key, new_gadget) in new.values() {
+        if !old_by_key.contains_key(key) {
+            diff.added.push(new_gadget.clone());
+        }
+    }
+
+    diff
+}
+
+///
+/// Collect and compare the gadgets of the executables of two sessions
+///
+pub fn diff_files(old: Session, new: Session) -> GenericResult<GadgetDiff> {
+    //
+    // the gadgets of both files are summarized for the same cpu, so that their keys compare
+    //
+    let cpu = old.info.cpu.cpu_type();
+    if cpu != new.info.cpu.cpu_type() {
+        return Err(Error::ArchitectureMismatch {
+            old: (old.filepath().clone(), cpu),
+            new: (new.filepath().clone(), new.info.cpu.cpu_type()),
+        });
+    }
+
+    let old_gadgets = collect_all_gadgets(old.output(RopGadgetOutput::None))?;
+    let new_gadgets = collect_all_gadgets(new.output(RopGadgetOutput::None))?;
+
+    let diff = diff_gadgets(cpu, &old_gadgets, &new_gadgets);
+    info!(
+        "{} gadget(s) added, {} removed, {} moved, {} unchanged",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.moved.len(),
+        diff.unchanged
+    );
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    fn session(path: &str) -> Session {
+        Session::new(PathBuf::from(path)).unwrap().nb_thread(2)
+    }
+
+    #[test]
+    fn same_file_has_no_difference() {
+        let fname = "tests/bin/small-x64.elf";
+        let diff = diff_files(session(fname), session(fname)).unwrap();
+        assert!(diff.is_empty());
+        assert!(diff.unchanged > 0);
+    }
+
+    #[test]
+    fn different_files() {
+        let (old_fname, new_fname) = ("tests/bin/small-x64.elf", "tests/bin/big-x64.elf");
+        let diff = diff_files(session(old_fname), session(new_fname)).unwrap();
+
+        let cpu = CpuType::X64;
+        let old = collect_all_gadgets(session(old_fname)).unwrap();
+        let new = collect_all_gadgets(session(new_fname)).unwrap();
+        let keys = |gadgets: &[Gadget]| -> HashSet<GadgetKey> {
+            gadgets.iter().map(|g| g.key(cpu, true)).collect()
+        };
+        let (old_keys, new_keys) = (keys(&old), keys(&new));
+
+        assert!(!diff.added.is_empty() && !diff.removed.is_empty() && !diff.moved.is_empty());
+        for gadget in &diff.added {
+            let key = gadget.key(cpu, true);
+            assert!(new_keys.contains(&key) && !old_keys.contains(&key));
+        }
+        for gadget in &diff.removed {
+            let key = gadget.key(cpu, true);
+            assert!(old_keys.contains(&key) && !new_keys.contains(&key));
+        }
+        for (old_gadget, new_gadget) in &diff.moved {
+            let key = old_gadget.key(cpu, true);
+            assert_eq!(key, new_gadget.key(cpu, true));
+            assert!(old_keys.contains(&key) && new_keys.contains(&key));
+            assert_ne!(old_gadget.addresses, new_gadget.addresses);
+        }
+
+        //
+        // each gadget of either file is in exactly one of the sets
+        //
+        let common = diff.moved.len() + diff.unchanged;
+        assert_eq!(diff.removed.len() + common, old_keys.len());
+        assert_eq!(diff.added.len() + common, new_keys.len());
+    }
+
+    #[test]
+    fn different_architectures() {
+        let res = diff_files(
+            session("tests/bin/small-x64.elf"),
+            session("tests/bin/small-x86.elf"),
+        );
+        assert!(matches!(
+            res,
+            Err(Error::ArchitectureMismatch {
+                old: (_, CpuType::X64),
+                new: (_, CpuType::X86)
+            })
+        ));
+    }
+}
@@ -0,0 +1,1424 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cpu::CpuType;
+use crate::gadget::{Gadget, Instruction, InstructionGroup, Operand};
+
+/**
+ *
+ * Lightweight emulator used to verify the effects of a gadget, without relying
+ * on its text.
+ *
+ * The instruction semantics are written once against the `Machine` trait, so
+ * the same code can run over a concrete register file (`CpuState`) or any other
+ * value domain.
+ *
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Sar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum UnOp {
+    Not,
+    Neg,
+    /// Sign-extend from the given number of bits
+    SignExtend(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    InvalidMemoryAccess(u64),
+    Privileged(String),
+    Trap(String),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::InvalidMemoryAccess(addr) => write!(f, "invalid memory access at {:#x}", addr),
+            Fault::Privileged(m) => write!(f, "privileged instruction '{}'", m),
+            Fault::Trap(m) => write!(f, "trapping instruction '{}'", m),
+        }
+    }
+}
+
+///
+/// Result of the execution of a gadget
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome<V> {
+    /// The gadget ran to completion and transfers execution to `next_pc`
+    Completed { next_pc: V },
+    /// The instruction at `index` faulted
+    Fault { index: usize, fault: Fault },
+    /// The gadget transfers execution back into itself
+    Loop { index: usize, target: u64 },
+    /// The instruction at `index` is not covered by the emulator
+    Unsupported { index: usize, mnemonic: String },
+}
+
+impl<V> Outcome<V> {
+    pub fn is_valid(&self) -> bool {
+        !matches!(self, Outcome::Fault { .. } | Outcome::Loop { .. })
+    }
+}
+
+///
+/// All the value domains the emulator can run on must implement this trait
+///
+pub trait Machine {
+    type Value: Clone + fmt::Debug + PartialEq;
+
+    fn constant(&self, value: u64) -> Self::Value;
+    fn concrete(&self, value: &Self::Value) -> Option<u64>;
+
+    /// Read a full-width register, designated by its canonical name
+    fn register(&mut self, name: &str) -> Self::Value;
+    fn set_register(&mut self, name: &str, value: Self::Value);
+
+    fn load(&mut self, address: &Self::Value, size: usize) -> Result<Self::Value, Fault>;
+    fn store(
+        &mut self,
+        address: &Self::Value,
+        value: &Self::Value,
+        size: usize,
+    ) -> Result<(), Fault>;
+
+    /// Operations are computed on `bits` bits, and the result is truncated to that width
+    fn unop(&self, op: UnOp, a: &Self::Value, bits: u32) -> Self::Value;
+    fn binop(&self, op: BinOp, a: &Self::Value, b: &Self::Value, bits: u32) -> Self::Value;
+}
+
+pub fn mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    }
+}
+
+///
+/// Where a register name lives in the register file
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RegisterSlot {
+    full: &'static str,
+    shift: u32,
+    bits: u32,
+    zero_extend: bool,
+}
+
+const X64_GPRS: [(&str, &str, &str, &str, &str); 16] = [
+    ("rax", "eax", "ax", "al", "ah"),
+    ("rbx", "ebx", "bx", "bl", "bh"),
+    ("rcx", "ecx", "cx", "cl", "ch"),
+    ("rdx", "edx", "dx", "dl", "dh"),
+    ("rsi", "esi", "si", "sil", ""),
+    ("rdi", "edi", "di", "dil", ""),
+    ("rbp", "ebp", "bp", "bpl", ""),
+    ("rsp", "esp", "sp", "spl", ""),
+    ("r8", "r8d", "r8w", "r8b", ""),
+    ("r9", "r9d", "r9w", "r9b", ""),
+    ("r10", "r10d", "r10w", "r10b", ""),
+    ("r11", "r11d", "r11w", "r11b", ""),
+    ("r12", "r12d", "r12w", "r12b", ""),
+    ("r13", "r13d", "r13w", "r13b", ""),
+    ("r14", "r14d", "r14w", "r14b", ""),
+    ("r15", "r15d", "r15w", "r15b", ""),
+];
+
+const ARM64_GPRS: [&str; 31] = [
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13", "x14",
+    "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27",
+    "x28", "x29", "x30",
+];
+
+const ARM_GPRS: [&str; 16] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr",
+    "pc",
+];
+
+fn slot(full: &'static str, shift: u32, bits: u32, zero_extend: bool) -> Option<RegisterSlot> {
+    Some(RegisterSlot {
+        full,
+        shift,
+        bits,
+        zero_extend,
+    })
+}
+
+///
+/// Locate a register name for a given architecture, `None` designates an unknown
+/// register, the zero register is mapped to an empty `full` name.
+///
+fn locate_register(cpu: CpuType, name: &str) -> Option<RegisterSlot> {
+    match cpu {
+        CpuType::X64 => {
+            if name == "rip" {
+                return slot("rip", 0, 64, false);
+            }
+            for (r64, r32, r16, r8l, r8h) in X64_GPRS.iter() {
+                if name == *r64 {
+                    return slot(r64, 0, 64, false);
+                } else if name == *r32 {
+                    return slot(r64, 0, 32, true);
+                } else if name == *r16 {
+                    return slot(r64, 0, 16, false);
+                } else if name == *r8l {
+                    return slot(r64, 0, 8, false);
+                } else if !r8h.is_empty() && name == *r8h {
+                    return slot(r64, 8, 8, false);
+                }
+            }
+            None
+        }
+
+        CpuType::X86 => {
+            if name == "eip" {
+                return slot("eip", 0, 32, false);
+            }
+            for (_, r32, r16, r8l, r8h) in X64_GPRS.iter().take(8) {
+                if name == *r32 {
+                    return slot(r32, 0, 32, false);
+                } else if name == *r16 {
+                    return slot(r32, 0, 16, false);
+                } else if name == *r8l && r8h.is_empty() {
+                    // sil, dil, bpl and spl do not exist in 32-bit mode
+                    return None;
+                } else if name == *r8l {
+                    return slot(r32, 0, 8, false);
+                } else if name == *r8h {
+                    return slot(r32, 8, 8, false);
+                }
+            }
+            None
+        }
+
+        CpuType::ARM64 => {
+            match name {
+                "xzr" | "wzr" => return slot("", 0, 64, false),
+                "sp" => return slot("sp", 0, 64, false),
+                "wsp" => return slot("sp", 0, 32, true),
+                "fp" => return slot("x29", 0, 64, false),
+                "lr" => return slot("x30", 0, 64, false),
+                _ => {}
+            };
+
+            if name.len() < 2 || !name.is_ascii() {
+                return None;
+            }
+            let (prefix, idx) = name.split_at(1);
+            let idx: usize = idx.parse().ok()?;
+            let full = ARM64_GPRS.get(idx)?;
+            match prefix {
+                "x" => slot(full, 0, 64, false),
+                "w" => slot(full, 0, 32, true),
+                _ => None,
+            }
+        }
+
+        CpuType::ARM | CpuType::Thumb => {
+            let name = match name {
+                "sb" => "r9",
+                "sl" => "r10",
+                "fp" => "r11",
+                "ip" => "r12",
+                "r13" => "sp",
+                "r14" => "lr",
+                "r15" => "pc",
+                _ => name,
+            };
+            let full = ARM_GPRS.iter().find(|r| **r == name)?;
+            slot(full, 0, 32, false)
+        }
+
+        CpuType::Unknown => None,
+    }
+}
+
+pub fn ptrsize(cpu: CpuType) -> usize {
+    match cpu {
+        CpuType::X64 | CpuType::ARM64 => 8,
+        _ => 4,
+    }
+}
+
+pub fn stack_pointer(cpu: CpuType) -> &'static str {
+    match cpu {
+        CpuType::X64 => "rsp",
+        CpuType::X86 => "esp",
+        _ => "sp",
+    }
+}
+
+///
+/// Canonical names of the general purpose registers of the architecture
+///
+pub fn registers(cpu: CpuType) -> Vec<&'static str> {
+    match cpu {
+        CpuType::X64 => X64_GPRS.iter().map(|r| r.0).collect(),
+        CpuType::X86 => X64_GPRS.iter().take(8).map(|r| r.1).collect(),
+        CpuType::ARM64 => {
+            let mut regs = ARM64_GPRS.to_vec();
+            regs.push("sp");
+            regs
+        }
+        CpuType::ARM | CpuType::Thumb => ARM_GPRS.iter().take(15).copied().collect(),
+        CpuType::Unknown => Vec::new(),
+    }
+}
+
+///
+/// Flow control after the execution of one instruction
+///
+enum Flow<V> {
+    Next,
+    Branch(V),
+}
+
+type StepResult<V> = Result<Flow<V>, Stop>;
+
+enum Stop {
+    Fault(Fault),
+    Unsupported,
+}
+
+impl From<Fault> for Stop {
+    fn from(fault: Fault) -> Self {
+        Stop::Fault(fault)
+    }
+}
+
+///
+/// Execute instructions from a `Gadget` over any `Machine`
+///
+struct Executor<'a, M: Machine> {
+    cpu: CpuType,
+    m: &'a mut M,
+    insn: &'a Instruction,
+}
+
+impl<'a, M: Machine> Executor<'a, M> {
+    fn full_bits(&self) -> u32 {
+        ptrsize(self.cpu) as u32 * 8
+    }
+
+    fn op(&self, idx: usize) -> Result<&'a Operand, Stop> {
+        match self.insn.ops.get(idx) {
+            Some(Operand::Unknown) | None => Err(Stop::Unsupported),
+            Some(op) => Ok(op),
+        }
+    }
+
+    fn constant(&self, value: i64) -> M::Value {
+        self.m.constant(value as u64 & mask(self.full_bits()))
+    }
+
+    fn read_reg(&mut self, name: &str) -> Result<(M::Value, u32), Stop> {
+        let slot = locate_register(self.cpu, name).ok_or(Stop::Unsupported)?;
+        if slot.full.is_empty() {
+            return Ok((self.m.constant(0), slot.bits));
+        }
+
+        if slot.full == "pc" {
+            // reading pc gives the address of the current instruction + 8 in ARM state, + 4 in
+            // Thumb state
+            let offset = match self.cpu {
+                CpuType::Thumb => 4,
+                _ => 8,
+            };
+            return Ok((self.m.constant(self.insn.address + offset), 32));
+        }
+
+        let mut value = self.m.register(slot.full);
+        if slot.shift > 0 {
+            let shift = self.m.constant(slot.shift as u64);
+            value = self.m.binop(BinOp::Shr, &value, &shift, self.full_bits());
+        }
+        if slot.bits < self.full_bits() {
+            let m = self.m.constant(mask(slot.bits));
+            value = self.m.binop(BinOp::And, &value, &m, self.full_bits());
+        }
+        Ok((value, slot.bits))
+    }
+
+    fn write_reg(&mut self, name: &str, value: M::Value) -> Result<(), Stop> {
+        let slot = locate_register(self.cpu, name).ok_or(Stop::Unsupported)?;
+        let full_bits = self.full_bits();
+
+        if slot.full.is_empty() {
+            return Ok(());
+        }
+
+        if slot.bits >= full_bits {
+            self.m.set_register(slot.full, value);
+            return Ok(());
+        }
+
+        let m = self.m.constant(mask(slot.bits));
+        let value = self.m.binop(BinOp::And, &value, &m, full_bits);
+
+        if slot.zero_extend {
+            self.m.set_register(slot.full, value);
+            return Ok(());
+        }
+
+        //
+        // partial write, merge with the current value
+        //
+        let shift = self.m.constant(slot.shift as u64);
+        let keep = self
+            .m
+            .constant(!(mask(slot.bits) << slot.shift) & mask(full_bits));
+        let current = self.m.register(slot.full);
+        let current = self.m.binop(BinOp::And, &current, &keep, full_bits);
+        let value = self.m.binop(BinOp::Shl, &value, &shift, full_bits);
+        let value = self.m.binop(BinOp::Or, &current, &value, full_bits);
+        self.m.set_register(slot.full, value);
+        Ok(())
+    }
+
+    fn register_bits(&self, name: &str) -> Result<u32, Stop> {
+        locate_register(self.cpu, name)
+            .map(|slot| slot.bits)
+            .ok_or(Stop::Unsupported)
+    }
+
+    ///
+    /// Compute the effective address of a memory operand (without writeback)
+    ///
+    fn address(&mut self, op: &Operand) -> Result<M::Value, Stop> {
+        let (base, index, scale, disp) = match op {
+            Operand::Mem {
+                base,
+                index,
+                scale,
+                disp,
+                ..
+            } => (base, index, *scale, *disp),
+            _ => return Err(Stop::Unsupported),
+        };
+
+        let bits = self.full_bits();
+        let mut address = match base.as_deref() {
+            Some("rip") => self.m.constant(self.insn.address + self.insn.size as u64),
+            Some(reg) => self.read_reg(reg)?.0,
+            None => self.m.constant(0),
+        };
+
+        if let Some(reg) = index {
+            let (value, _) = self.read_reg(reg)?;
+            let scale = self.m.constant(scale as u64);
+            let value = self.m.binop(BinOp::Mul, &value, &scale, bits);
+            address = self.m.binop(BinOp::Add, &address, &value, bits);
+        }
+
+        if disp != 0 {
+            let disp = self.constant(disp);
+            address = self.m.binop(BinOp::Add, &address, &disp, bits);
+        }
+
+        Ok(address)
+    }
+
+    fn operand_bits(&self, op: &Operand) -> Result<u32, Stop> {
+        match op {
+            Operand::Reg(r) => self.register_bits(r),
+            Operand::Mem { size, .. } if *size > 0 => Ok(*size as u32 * 8),
+            Operand::Mem { .. } => Ok(self.full_bits()),
+            Operand::Imm(_) => Ok(self.full_bits()),
+            Operand::Unknown => Err(Stop::Unsupported),
+        }
+    }
+
+    fn read(&mut self, op: &Operand) -> Result<M::Value, Stop> {
+        match op {
+            Operand::Reg(r) => Ok(self.read_reg(r)?.0),
+            Operand::Imm(imm) => Ok(self.constant(*imm)),
+            Operand::Mem { .. } => {
+                let address = self.address(op)?;
+                let size = self.operand_bits(op)? as usize / 8;
+                Ok(self.m.load(&address, size)?)
+            }
+            Operand::Unknown => Err(Stop::Unsupported),
+        }
+    }
+
+    fn write(&mut self, op: &Operand, value: M::Value) -> Result<(), Stop> {
+        match op {
+            Operand::Reg(r) => self.write_reg(r, value),
+            Operand::Mem { .. } => {
+                let address = self.address(op)?;
+                let size = self.operand_bits(op)? as usize / 8;
+                Ok(self.m.store(&address, &value, size)?)
+            }
+            _ => Err(Stop::Unsupported),
+        }
+    }
+
+    fn push(&mut self, value: M::Value) -> Result<(), Stop> {
+        let sp = stack_pointer(self.cpu);
+        let size = ptrsize(self.cpu);
+        let (cur, _) = self.read_reg(sp)?;
+        let delta = self.m.constant(size as u64);
+        let new = self.m.binop(BinOp::Sub, &cur, &delta, self.full_bits());
+        self.m.store(&new, &value, size)?;
+        self.write_reg(sp, new)
+    }
+
+    fn pop(&mut self) -> Result<M::Value, Stop> {
+        let sp = stack_pointer(self.cpu);
+        let size = ptrsize(self.cpu);
+        let (cur, _) = self.read_reg(sp)?;
+        let value = self.m.load(&cur, size)?;
+        let delta = self.m.constant(size as u64);
+        let new = self.m.binop(BinOp::Add, &cur, &delta, self.full_bits());
+        self.write_reg(sp, new)?;
+        Ok(value)
+    }
+
+    fn adjust_stack(&mut self, delta: i64) -> Result<(), Stop> {
+        if delta == 0 {
+            return Ok(());
+        }
+        let sp = stack_pointer(self.cpu);
+        let (cur, _) = self.read_reg(sp)?;
+        let delta = self.constant(delta);
+        let new = self.m.binop(BinOp::Add, &cur, &delta, self.full_bits());
+        self.write_reg(sp, new)
+    }
+
+    fn return_address(&self) -> M::Value {
+        self.m.constant(self.insn.address + self.insn.size as u64)
+    }
+
+    ///
+    /// dst = dst `op` src
+    ///
+    fn binary(&mut self, op: BinOp) -> StepResult<M::Value> {
+        let (dst, src) = match self.insn.ops.len() {
+            1 if matches!(op, BinOp::Shl | BinOp::Shr | BinOp::Sar) => {
+                (self.op(0)?, &Operand::Imm(1))
+            }
+            2 => (self.op(0)?, self.op(1)?),
+            _ => return Err(Stop::Unsupported),
+        };
+
+        let bits = self.operand_bits(dst)?;
+        let a = self.read(dst)?;
+        let mut b = self.read(src)?;
+        if matches!(op, BinOp::Shl | BinOp::Shr | BinOp::Sar) {
+            let count_mask = self.m.constant(if bits == 64 { 0x3f } else { 0x1f });
+            b = self.m.binop(BinOp::And, &b, &count_mask, bits);
+        }
+        let value = self.m.binop(op, &a, &b, bits);
+        self.write(dst, value)?;
+        Ok(Flow::Next)
+    }
+
+    ///
+    /// dst = src1 `op` src2 (three-operand form on ARM)
+    ///
+    fn ternary(&mut self, op: BinOp) -> StepResult<M::Value> {
+        if self.insn.ops.len() != 3 {
+            return Err(Stop::Unsupported);
+        }
+        let (dst, src1, src2) = (self.op(0)?, self.op(1)?, self.op(2)?);
+        let bits = self.operand_bits(dst)?;
+        let a = self.read(src1)?;
+        let b = self.read(src2)?;
+        let value = self.m.binop(op, &a, &b, bits);
+        self.write_dst(dst, value)
+    }
+
+    fn unary(&mut self, op: UnOp) -> StepResult<M::Value> {
+        let (dst, src) = match self.insn.ops.len() {
+            1 => (self.op(0)?, self.op(0)?),
+            2 => (self.op(0)?, self.op(1)?),
+            _ => return Err(Stop::Unsupported),
+        };
+        let bits = self.operand_bits(dst)?;
+        let a = self.read(src)?;
+        let value = self.m.unop(op, &a, bits);
+        self.write_dst(dst, value)
+    }
+
+    ///
+    /// Write a register on ARM, which turns into a branch when the destination is pc
+    ///
+    fn write_dst(&mut self, dst: &Operand, value: M::Value) -> StepResult<M::Value> {
+        if matches!(dst, Operand::Reg(r) if r == "pc") {
+            return Ok(Flow::Branch(value));
+        }
+        self.write(dst, value)?;
+        Ok(Flow::Next)
+    }
+
+    ///
+    /// Read, and throw away, the memory operands of an instruction which only sets the flags
+    /// (e.g. `cmp`), so that it faults where the actual instruction would
+    ///
+    fn read_memory_operands(&mut self) -> StepResult<M::Value> {
+        let insn = self.insn;
+        for op in insn.ops.iter() {
+            if let Operand::Mem { .. } = op {
+                self.read(op)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn step(&mut self) -> StepResult<M::Value> {
+        if self.insn.group == InstructionGroup::Privileged {
+            return Err(Fault::Privileged(self.insn.mnemonic.clone()).into());
+        }
+
+        match self.cpu {
+            CpuType::X86 | CpuType::X64 => self.step_x86(),
+            CpuType::ARM64 => self.step_arm64(),
+            CpuType::ARM | CpuType::Thumb => self.step_arm(),
+            CpuType::Unknown => Err(Stop::Unsupported),
+        }
+    }
+
+    fn step_x86(&mut self) -> StepResult<M::Value> {
+        let ops = &self.insn.ops;
+
+        match self.insn.mnemonic.as_str() {
+            "nop" | "endbr32" | "endbr64" | "clc" | "stc" | "cmc" | "cld" | "std" | "pause"
+            | "fwait" | "wait" => Ok(Flow::Next),
+
+            "cmp" | "test" | "bt" => self.read_memory_operands(),
+
+            "int3" | "int" | "int1" | "into" | "ud0" | "ud1" | "ud2" | "hlt" => {
+                Err(Fault::Trap(self.insn.mnemonic.clone()).into())
+            }
+
+            "mov" | "movabs" | "movzx" => {
+                let (dst, src) = (self.op(0)?, self.op(1)?);
+                let value = self.read(src)?;
+                self.write(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "movsx" | "movsxd" => {
+                let (dst, src) = (self.op(0)?, self.op(1)?);
+                let src_bits = self.operand_bits(src)?;
+                let dst_bits = self.operand_bits(dst)?;
+                let value = self.read(src)?;
+                let value = self.m.unop(UnOp::SignExtend(src_bits), &value, dst_bits);
+                self.write(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "lea" => {
+                let (dst, src) = (self.op(0)?, self.op(1)?);
+                let value = self.address(src)?;
+                self.write(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "xchg" => {
+                let (a, b) = (self.op(0)?, self.op(1)?);
+                let va = self.read(a)?;
+                let vb = self.read(b)?;
+                self.write(a, vb)?;
+                self.write(b, va)?;
+                Ok(Flow::Next)
+            }
+
+            "push" => {
+                let value = self.read(self.op(0)?)?;
+                self.push(value)?;
+                Ok(Flow::Next)
+            }
+
+            "pop" => {
+                let value = self.pop()?;
+                self.write(self.op(0)?, value)?;
+                Ok(Flow::Next)
+            }
+
+            "leave" => {
+                let (sp, bp) = match self.cpu {
+                    CpuType::X64 => ("rsp", "rbp"),
+                    _ => ("esp", "ebp"),
+                };
+                let (frame, _) = self.read_reg(bp)?;
+                self.write_reg(sp, frame)?;
+                let value = self.pop()?;
+                self.write_reg(bp, value)?;
+                Ok(Flow::Next)
+            }
+
+            "add" => self.binary(BinOp::Add),
+            "sub" => self.binary(BinOp::Sub),
+            "and" => self.binary(BinOp::And),
+            "or" => self.binary(BinOp::Or),
+            "xor" => self.binary(BinOp::Xor),
+            "shl" | "sal" => self.binary(BinOp::Shl),
+            "shr" => self.binary(BinOp::Shr),
+            "sar" => self.binary(BinOp::Sar),
+
+            "imul" if ops.len() == 2 => self.binary(BinOp::Mul),
+            "imul" if ops.len() == 3 => {
+                let (dst, src1, src2) = (self.op(0)?, self.op(1)?, self.op(2)?);
+                let bits = self.operand_bits(dst)?;
+                let a = self.read(src1)?;
+                let b = self.read(src2)?;
+                let value = self.m.binop(BinOp::Mul, &a, &b, bits);
+                self.write(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "inc" | "dec" => {
+                let dst = self.op(0)?;
+                let dst_bits = self.operand_bits(dst)?;
+                let a = self.read(dst)?;
+                let one = self.m.constant(1);
+                let op = match self.insn.mnemonic.as_str() {
+                    "inc" => BinOp::Add,
+                    _ => BinOp::Sub,
+                };
+                let value = self.m.binop(op, &a, &one, dst_bits);
+                self.write(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "neg" => self.unary(UnOp::Neg),
+            "not" => self.unary(UnOp::Not),
+
+            "cwde" | "cdqe" => {
+                let (dst, src, from) = match self.insn.mnemonic.as_str() {
+                    "cwde" => ("eax", "ax", 16),
+                    _ => ("rax", "eax", 32),
+                };
+                let (value, _) = self.read_reg(src)?;
+                let dst_bits = self.register_bits(dst)?;
+                let value = self.m.unop(UnOp::SignExtend(from), &value, dst_bits);
+                self.write_reg(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "cdq" | "cqo" => {
+                let (hi, lo) = match self.insn.mnemonic.as_str() {
+                    "cdq" => ("edx", "eax"),
+                    _ => ("rdx", "rax"),
+                };
+                let (value, lo_bits) = self.read_reg(lo)?;
+                let shift = self.m.constant(lo_bits as u64 - 1);
+                let value = self.m.binop(BinOp::Sar, &value, &shift, lo_bits);
+                self.write_reg(hi, value)?;
+                Ok(Flow::Next)
+            }
+
+            "ret" | "retf" => {
+                let target = self.pop()?;
+                if self.insn.mnemonic == "retf" {
+                    // also pop the code segment
+                    self.pop()?;
+                }
+                if let Some(Operand::Imm(imm)) = ops.first() {
+                    self.adjust_stack(*imm)?;
+                }
+                Ok(Flow::Branch(target))
+            }
+
+            "jmp" => {
+                let target = self.read(self.op(0)?)?;
+                Ok(Flow::Branch(target))
+            }
+
+            "call" => {
+                let target = self.read(self.op(0)?)?;
+                let ret = self.return_address();
+                self.push(ret)?;
+                Ok(Flow::Branch(target))
+            }
+
+            _ => Err(Stop::Unsupported),
+        }
+    }
+
+    ///
+    /// Load/store on ARM architectures, handling pre-index and post-index writeback
+    ///
+    fn load_store(
+        &mut self,
+        regs: &[&'a Operand],
+        mem_idx: usize,
+        store: bool,
+        size: Option<usize>,
+        signed: bool,
+    ) -> StepResult<M::Value> {
+        let mem = self.op(mem_idx)?;
+        let post_index = match self.insn.ops.get(mem_idx + 1) {
+            Some(Operand::Imm(imm)) => Some(*imm),
+            Some(_) => return Err(Stop::Unsupported),
+            None => None,
+        };
+
+        let writeback = matches!(
+            mem,
+            Operand::Mem {
+                writeback: true,
+                ..
+            }
+        );
+        let base = match mem {
+            Operand::Mem {
+                base: Some(base), ..
+            } => base.clone(),
+            _ => return Err(Stop::Unsupported),
+        };
+
+        let mut address = match post_index {
+            Some(_) => self.read_reg(&base)?.0,
+            None => self.address(mem)?,
+        };
+
+        let bits = self.full_bits();
+        let mut flow = Flow::Next;
+        for reg in regs {
+            let reg = match reg {
+                Operand::Reg(r) => r,
+                _ => return Err(Stop::Unsupported),
+            };
+            let reg_bits = self.register_bits(reg)?;
+            let access = size.unwrap_or(reg_bits as usize / 8);
+
+            if store {
+                let (value, _) = self.read_reg(reg)?;
+                self.m.store(&address, &value, access)?;
+            } else {
+                let mut value = self.m.load(&address, access)?;
+                if signed {
+                    value = self
+                        .m
+                        .unop(UnOp::SignExtend(access as u32 * 8), &value, reg_bits);
+                }
+                if let Flow::Branch(target) = self.write_dst(&Operand::Reg(reg.clone()), value)? {
+                    flow = Flow::Branch(target);
+                }
+            }
+
+            let delta = self.m.constant(access as u64);
+            address = self.m.binop(BinOp::Add, &address, &delta, bits);
+        }
+
+        if let Some(imm) = post_index {
+            let (cur, _) = self.read_reg(&base)?;
+            let imm = self.constant(imm);
+            let value = self.m.binop(BinOp::Add, &cur, &imm, bits);
+            self.write_reg(&base, value)?;
+        } else if writeback {
+            let value = self.address(mem)?;
+            self.write_reg(&base, value)?;
+        }
+
+        Ok(flow)
+    }
+
+    fn step_arm64(&mut self) -> StepResult<M::Value> {
+        let ops = &self.insn.ops;
+
+        match self.insn.mnemonic.as_str() {
+            "nop" | "hint" | "prfm" | "bti" | "paciasp" | "autiasp" | "pacibsp" | "autibsp" => {
+                Ok(Flow::Next)
+            }
+
+            "cmp" | "cmn" | "tst" => self.read_memory_operands(),
+
+            "brk" | "svc" | "hvc" | "smc" | "hlt" | "udf" => {
+                Err(Fault::Trap(self.insn.mnemonic.clone()).into())
+            }
+
+            "msr" | "mrs" | "eret" => Err(Fault::Privileged(self.insn.mnemonic.clone()).into()),
+
+            "mov" if ops.len() == 2 => {
+                let (dst, src) = (self.op(0)?, self.op(1)?);
+                let value = self.read(src)?;
+                self.write(dst, value)?;
+                Ok(Flow::Next)
+            }
+
+            "add" => self.ternary(BinOp::Add),
+            "sub" => self.ternary(BinOp::Sub),
+            "and" => self.ternary(BinOp::And),
+            "orr" => self.ternary(BinOp::Or),
+            "eor" => self.ternary(BinOp::Xor),
+            "mul" => self.ternary(BinOp::Mul),
+            "lsl" => self.ternary(BinOp::Shl),
+            "lsr" => self.ternary(BinOp::Shr),
+            "asr" => self.ternary(BinOp::Sar),
+            "neg" => self.unary(UnOp::Neg),
+            "mvn" => self.unary(UnOp::Not),
+
+            "ldr" | "ldur" if ops.len() >= 2 => {
+                self.load_store(&[self.op(0)?], 1, false, None, false)
+            }
+            "ldrb" | "ldurb" => self.load_store(&[self.op(0)?], 1, false, Some(1), false),
+            "ldrh" | "ldurh" => self.load_store(&[self.op(0)?], 1, false, Some(2), false),
+            "ldrsb" | "ldursb" => self.load_store(&[self.op(0)?], 1, false, Some(1), true),
+            "ldrsh" | "ldursh" => self.load_store(&[self.op(0)?], 1, false, Some(2), true),
+            "ldrsw" | "ldursw" => self.load_store(&[self.op(0)?], 1, false, Some(4), true),
+            "str" | "stur" => self.load_store(&[self.op(0)?], 1, true, None, false),
+            "strb" | "sturb" => self.load_store(&[self.op(0)?], 1, true, Some(1), false),
+            "strh" | "sturh" => self.load_store(&[self.op(0)?], 1, true, Some(2), false),
+            "ldp" => self.load_store(&[self.op(0)?, self.op(1)?], 2, false, None, false),
+            "stp" => self.load_store(&[self.op(0)?, self.op(1)?], 2, true, None, false),
+
+            "ret" => {
+                let target = match ops.first() {
+                    Some(op) => self.read(op)?,
+                    None => self.read_reg("x30")?.0,
+                };
+                Ok(Flow::Branch(target))
+            }
+
+            "br" => {
+                let target = self.read(self.op(0)?)?;
+                Ok(Flow::Branch(target))
+            }
+
+            "blr" => {
+                let target = self.read(self.op(0)?)?;
+                let ret = self.return_address();
+                self.write_reg("x30", ret)?;
+                Ok(Flow::Branch(target))
+            }
+
+            _ => Err(Stop::Unsupported),
+        }
+    }
+
+    fn step_arm(&mut self) -> StepResult<M::Value> {
+        let ops = &self.insn.ops;
+
+        match self.insn.mnemonic.as_str() {
+            "nop" => Ok(Flow::Next),
+
+            "cmp" | "cmn" | "tst" | "teq" => self.read_memory_operands(),
+
+            "bkpt" | "svc" | "udf" => Err(Fault::Trap(self.insn.mnemonic.clone()).into()),
+
+            "mrs" | "msr" | "cps" => Err(Fault::Privileged(self.insn.mnemonic.clone()).into()),
+
+            "mov" if ops.len() == 2 => {
+                let (dst, src) = (self.op(0)?, self.op(1)?);
+                let value = self.read(src)?;
+                self.write_dst(dst, value)
+            }
+
+            "add" => self.ternary(BinOp::Add),
+            "sub" => self.ternary(BinOp::Sub),
+            "and" => self.ternary(BinOp::And),
+            "orr" => self.ternary(BinOp::Or),
+            "eor" => self.ternary(BinOp::Xor),
+            "mul" => self.ternary(BinOp::Mul),
+            "lsl" => self.ternary(BinOp::Shl),
+            "lsr" => self.ternary(BinOp::Shr),
+            "asr" => self.ternary(BinOp::Sar),
+            "mvn" => self.unary(UnOp::Not),
+
+            "ldr" => self.load_store(&[self.op(0)?], 1, false, None, false),
+            "ldrb" => self.load_store(&[self.op(0)?], 1, false, Some(1), false),
+            "ldrh" => self.load_store(&[self.op(0)?], 1, false, Some(2), false),
+            "str" => self.load_store(&[self.op(0)?], 1, true, None, false),
+            "strb" => self.load_store(&[self.op(0)?], 1, true, Some(1), false),
+            "strh" => self.load_store(&[self.op(0)?], 1, true, Some(2), false),
+
+            "pop" => {
+                let mut flow = Flow::Next;
+                for op in ops.iter() {
+                    let value = self.pop()?;
+                    if let Flow::Branch(target) = self.write_dst(op, value)? {
+                        flow = Flow::Branch(target);
+                    }
+                }
+                Ok(flow)
+            }
+
+            "push" => {
+                for op in ops.iter().rev() {
+                    let value = self.read(op)?;
+                    self.push(value)?;
+                }
+                Ok(Flow::Next)
+            }
+
+            "bx" => {
+                let target = self.read(self.op(0)?)?;
+                Ok(Flow::Branch(target))
+            }
+
+            "blx" => {
+                let target = self.read(self.op(0)?)?;
+                let ret = self.return_address();
+                self.write_reg("lr", ret)?;
+                Ok(Flow::Branch(target))
+            }
+
+            _ => Err(Stop::Unsupported),
+        }
+    }
+}
+
+///
+/// Run all the instructions of a gadget on the given machine
+///
+pub fn execute<M: Machine>(cpu: CpuType, machine: &mut M, gadget: &Gadget) -> Outcome<M::Value> {
+    let start = gadget.address;
+    let end = gadget.address + gadget.size as u64;
+
+    for (index, insn) in gadget.insns.iter().enumerate() {
+        let mut executor = Executor {
+            cpu,
+            m: &mut *machine,
+            insn,
+        };
+
+        match executor.step() {
+            Ok(Flow::Next) => {}
+
+            Ok(Flow::Branch(target)) => {
+                if let Some(addr) = machine.concrete(&target) {
+                    if addr >= start && addr < end {
+                        return Outcome::Loop {
+                            index,
+                            target: addr,
+                        };
+                    }
+                }
+                return Outcome::Completed { next_pc: target };
+            }
+
+            Err(Stop::Fault(fault)) => return Outcome::Fault { index, fault },
+
+            Err(Stop::Unsupported) => {
+                return Outcome::Unsupported {
+                    index,
+                    mnemonic: insn.mnemonic.clone(),
+                }
+            }
+        }
+    }
+
+    Outcome::Completed {
+        next_pc: machine.constant(end),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub address: u64,
+    pub size: usize,
+    pub value: u64,
+}
+
+///
+/// A concrete register file and sparse memory. Memory accesses outside of the mapped
+/// regions fault.
+///
+#[derive(Debug, Clone, Default)]
+pub struct CpuState {
+    pub registers: BTreeMap<String, u64>,
+    memory: BTreeMap<u64, u8>,
+    mapped: Vec<(u64, u64)>,
+    reads: Vec<MemoryAccess>,
+    writes: Vec<MemoryAccess>,
+}
+
+const DEFAULT_STACK_BASE: u64 = 0x7ff0_0000;
+const DEFAULT_SCRATCH_BASE: u64 = 0x1000_0000;
+const DEFAULT_REGION_SIZE: u64 = 0x10_0000;
+
+impl CpuState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Build a state where the stack pointer and all the registers point to mapped memory,
+    /// and the stack holds distinct values, such that only genuinely faulty gadgets fail.
+    ///
+    pub fn default_for(cpu: CpuType) -> Self {
+        let mut state = Self::new()
+            .map(DEFAULT_STACK_BASE, DEFAULT_REGION_SIZE)
+            .map(DEFAULT_SCRATCH_BASE, DEFAULT_REGION_SIZE);
+
+        let ptrsize = ptrsize(cpu) as u64;
+        let sp = DEFAULT_STACK_BASE + DEFAULT_REGION_SIZE / 2;
+        for i in 0..0x100 {
+            let value = 0x4141_0000 + i * ptrsize;
+            state = state.write(sp + i * ptrsize, &value.to_le_bytes()[..ptrsize as usize]);
+        }
+
+        for (i, reg) in registers(cpu).iter().enumerate() {
+            let value = DEFAULT_SCRATCH_BASE + DEFAULT_REGION_SIZE / 2 + (i as u64) * 0x1000;
+            state = state.register(reg, value);
+        }
+
+        state.register(stack_pointer(cpu), sp)
+    }
+
+    pub fn register(mut self, name: &str, value: u64) -> Self {
+        self.registers.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn map(mut self, address: u64, size: u64) -> Self {
+        self.mapped.push((address, address + size));
+        self
+    }
+
+    pub fn write(mut self, address: u64, data: &[u8]) -> Self {
+        for (i, b) in data.iter().enumerate() {
+            self.memory.insert(address + i as u64, *b);
+        }
+        self
+    }
+
+    pub fn is_mapped(&self, address: u64, size: usize) -> bool {
+        let end = match address.checked_add(size as u64) {
+            Some(end) => end,
+            None => return false,
+        };
+        self.mapped
+            .iter()
+            .any(|(start, stop)| address >= *start && end <= *stop)
+    }
+
+    pub fn read_bytes(&self, address: u64, size: usize) -> Option<Vec<u8>> {
+        if !self.is_mapped(address, size) {
+            return None;
+        }
+        Some(
+            (0..size as u64)
+                .map(|i| *self.memory.get(&(address + i)).unwrap_or(&0))
+                .collect(),
+        )
+    }
+
+    pub fn reads(&self) -> &Vec<MemoryAccess> {
+        &self.reads
+    }
+
+    pub fn writes(&self) -> &Vec<MemoryAccess> {
+        &self.writes
+    }
+}
+
+impl Machine for CpuState {
+    type Value = u64;
+
+    fn constant(&self, value: u64) -> u64 {
+        value
+    }
+
+    fn concrete(&self, value: &u64) -> Option<u64> {
+        Some(*value)
+    }
+
+    fn register(&mut self, name: &str) -> u64 {
+        *self.registers.get(name).unwrap_or(&0)
+    }
+
+    fn set_register(&mut self, name: &str, value: u64) {
+        self.registers.insert(name.to_string(), value);
+    }
+
+    fn load(&mut self, address: &u64, size: usize) -> Result<u64, Fault> {
+        let bytes = self
+            .read_bytes(*address, size)
+            .ok_or(Fault::InvalidMemoryAccess(*address))?;
+        let mut buf = [0u8; 8];
+        buf[..size.min(8)].copy_from_slice(&bytes[..size.min(8)]);
+        let value = u64::from_le_bytes(buf);
+        self.reads.push(MemoryAccess {
+            address: *address,
+            size,
+            value,
+        });
+        Ok(value)
+    }
+
+    fn store(&mut self, address: &u64, value: &u64, size: usize) -> Result<(), Fault> {
+        if !self.is_mapped(*address, size) {
+            return Err(Fault::InvalidMemoryAccess(*address));
+        }
+        for (i, b) in value.to_le_bytes().iter().take(size).enumerate() {
+            self.memory.insert(address + i as u64, *b);
+        }
+        self.writes.push(MemoryAccess {
+            address: *address,
+            size,
+            value: *value & mask(size as u32 * 8),
+        });
+        Ok(())
+    }
+
+    fn unop(&self, op: UnOp, a: &u64, bits: u32) -> u64 {
+        let value = match op {
+            UnOp::Not => !a,
+            UnOp::Neg => a.wrapping_neg(),
+            UnOp::SignExtend(from) => {
+                let shift = 64 - from.min(64);
+                (((a << shift) as i64) >> shift) as u64
+            }
+        };
+        value & mask(bits)
+    }
+
+    fn binop(&self, op: BinOp, a: &u64, b: &u64, bits: u32) -> u64 {
+        let (a, b) = (a & mask(bits), *b);
+        let value = match op {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Shl => a.checked_shl(b as u32).unwrap_or(0),
+            BinOp::Shr => a.checked_shr(b as u32).unwrap_or(0),
+            BinOp::Sar => {
+                let shift = 64 - bits.min(64);
+                let signed = ((a << shift) as i64) >> shift;
+                (signed >> (b as u32).min(63)) as u64
+            }
+        };
+        value & mask(bits)
+    }
+}
+
+///
+/// Concrete effects of a gadget
+///
+#[derive(Debug, Clone)]
+pub struct Emulation {
+    pub outcome: Outcome<u64>,
+    /// Registers whose value changed, with their (initial, final) values
+    pub registers: BTreeMap<String, (u64, u64)>,
+    pub stack_delta: i64,
+    pub reads: Vec<MemoryAccess>,
+    pub writes: Vec<MemoryAccess>,
+    pub state: CpuState,
+}
+
+pub struct Emulator {
+    cpu: CpuType,
+    /// The initial state of `verify`, built once as it fills the whole stack
+    default_state: CpuState,
+    keep_unsupported: bool,
+}
+
+impl Emulator {
+    pub fn new(cpu: CpuType) -> Self {
+        Self {
+            cpu,
+            default_state: CpuState::default_for(cpu),
+            keep_unsupported: true,
+        }
+    }
+
+    ///
+    /// Whether `verify` accepts the gadgets with an instruction the emulator does not model (the
+    /// default), as they could not be shown to fail
+    ///
+    pub fn keep_unsupported(self, keep_unsupported: bool) -> Self {
+        Self {
+            keep_unsupported,
+            ..self
+        }
+    }
+
+    ///
+    /// Emulate the gadget from the given initial state, and report its effects
+    ///
+    pub fn run(&self, gadget: &Gadget, initial: CpuState) -> Emulation {
+        let before = initial.registers.clone();
+        let mut state = initial;
+        state.reads.clear();
+        state.writes.clear();
+
+        let outcome = execute(self.cpu, &mut state, gadget);
+
+        let mut registers = BTreeMap::new();
+        for (name, after) in state.registers.iter() {
+            let before = *before.get(name).unwrap_or(&0);
+            if before != *after {
+                registers.insert(name.clone(), (before, *after));
+            }
+        }
+
+        let sp = stack_pointer(self.cpu);
+        let sp_before = *before.get(sp).unwrap_or(&0);
+        let sp_after = *state.registers.get(sp).unwrap_or(&0);
+
+        Emulation {
+            outcome,
+            registers,
+            stack_delta: sp_after.wrapping_sub(sp_before) as i64,
+            reads: state.reads.clone(),
+            writes: state.writes.clone(),
+            state,
+        }
+    }
+
+    ///
+    /// Check that the gadget neither faults nor loops from a default initial state. The gadgets
+    /// the emulator cannot run to completion are accepted unless `keep_unsupported` is unset.
+    ///
+    pub fn verify(&self, gadget: &Gadget) -> bool {
+        match self.run(gadget, self.default_state.clone()).outcome {
+            Outcome::Unsupported { .. } => self.keep_unsupported,
+            outcome => outcome.is_valid(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+    use crate::engine::{DisassemblyEngine, DisassemblyEngineType};
+
+    fn gadget(cpu: &dyn cpu::Cpu, code: &[u8]) -> Gadget {
+        let engine = DisassemblyEngine::new(&DisassemblyEngineType::Capstone, cpu).unwrap();
+        Gadget::new(engine.disassembler.disassemble(code, 0x1000).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn x64_pop_ret() {
+        // pop rdi ; ret
+        let g = gadget(&cpu::x86::X64 {}, &[0x5f, 0xc3]);
+        let state = CpuState::new()
+            .map(0x8000, 0x100)
+            .write(0x8000, &0xdeadbeef_u64.to_le_bytes())
+            .write(0x8008, &0x4000_u64.to_le_bytes())
+            .register("rsp", 0x8000);
+
+        let res = Emulator::new(CpuType::X64).run(&g, state);
+        assert_eq!(res.outcome, Outcome::Completed { next_pc: 0x4000 });
+        assert_eq!(res.registers.get("rdi"), Some(&(0, 0xdeadbeef)));
+        assert_eq!(res.stack_delta, 16);
+        assert!(res.writes.is_empty());
+    }
+
+    #[test]
+    fn x64_partial_registers() {
+        // mov al, 0x41 ; xor ecx, ecx ; ret
+        let g = gadget(&cpu::x86::X64 {}, &[0xb0, 0x41, 0x31, 0xc9, 0xc3]);
+        let state = CpuState::default_for(CpuType::X64)
+            .register("rax", 0x1122_3344_5566_7788)
+            .register("rcx", u64::MAX);
+
+        let res = Emulator::new(CpuType::X64).run(&g, state);
+        assert!(matches!(res.outcome, Outcome::Completed { .. }));
+        assert_eq!(res.state.registers["rax"], 0x1122_3344_5566_7741);
+        assert_eq!(res.state.registers["rcx"], 0);
+    }
+
+    #[test]
+    fn faults_and_loops() {
+        let emu = Emulator::new(CpuType::X64);
+
+        // mov rax, qword ptr [0] ; ret
+        let g = gadget(
+            &cpu::x86::X64 {},
+            &[0x48, 0x8b, 0x04, 0x25, 0, 0, 0, 0, 0xc3],
+        );
+        assert!(!emu.verify(&g));
+
+        // int3 ; ret
+        let g = gadget(&cpu::x86::X64 {}, &[0xcc, 0xc3]);
+        assert!(!emu.verify(&g));
+
+        // cmp byte ptr [rax], 0 ; ret
+        let g = gadget(&cpu::x86::X64 {}, &[0x80, 0x38, 0x00, 0xc3]);
+        assert!(emu.verify(&g));
+        let state = CpuState::default_for(CpuType::X64).register("rax", 0);
+        assert!(matches!(
+            emu.run(&g, state).outcome,
+            Outcome::Fault { index: 0, .. }
+        ));
+
+        // test qword ptr [0], rax ; ret
+        let g = gadget(
+            &cpu::x86::X64 {},
+            &[0x48, 0x85, 0x04, 0x25, 0, 0, 0, 0, 0xc3],
+        );
+        assert!(!emu.verify(&g));
+
+        // lea rax, [rip - 7] ; jmp rax
+        let g = gadget(
+            &cpu::x86::X64 {},
+            &[0x48, 0x8d, 0x05, 0xf9, 0xff, 0xff, 0xff, 0xff, 0xe0],
+        );
+        assert!(matches!(
+            emu.run(&g, CpuState::default_for(CpuType::X64)).outcome,
+            Outcome::Loop { target: 0x1000, .. }
+        ));
+    }
+
+    #[test]
+    fn unsupported_instructions() {
+        // cpuid ; ret
+        let g = gadget(&cpu::x86::X64 {}, &[0x0f, 0xa2, 0xc3]);
+        let emu = Emulator::new(CpuType::X64);
+        assert!(matches!(
+            emu.run(&g, CpuState::default_for(CpuType::X64)).outcome,
+            Outcome::Unsupported { index: 0, .. }
+        ));
+        assert!(emu.verify(&g));
+        assert!(!emu.keep_unsupported(false).verify(&g));
+    }
+
+    #[test]
+    fn arm_and_thumb_pc() {
+        // mov r0, pc ; bx lr
+        let g = gadget(
+            &cpu::arm::Arm {},
+            &[0x0f, 0x00, 0xa0, 0xe1, 0x1e, 0xff, 0x2f, 0xe1],
+        );
+        let res = Emulator::new(CpuType::ARM).run(&g, CpuState::default_for(CpuType::ARM));
+        assert!(matches!(res.outcome, Outcome::Completed { .. }));
+        assert_eq!(res.state.registers["r0"], 0x1008);
+
+        // mov r0, pc ; bx lr
+        let g = gadget(&cpu::arm::Thumb {}, &[0x78, 0x46, 0x70, 0x47]);
+        let res = Emulator::new(CpuType::Thumb).run(&g, CpuState::default_for(CpuType::Thumb));
+        assert!(matches!(res.outcome, Outcome::Completed { .. }));
+        assert_eq!(res.state.registers["r0"], 0x1004);
+    }
+
+    #[test]
+    fn arm64_ldp_ret() {
+        // ldp x29, x30, [sp], #0x10 ; ret
+        let g = gadget(
+            &cpu::arm::Arm64 {},
+            &[0xfd, 0x7b, 0xc1, 0xa8, 0xc0, 0x03, 0x5f, 0xd6],
+        );
+        let state = CpuState::new()
+            .map(0x8000, 0x100)
+            .write(0x8000, &0x1111_u64.to_le_bytes())
+            .write(0x8008, &0x2222_u64.to_le_bytes())
+            .register("sp", 0x8000);
+
+        let res = Emulator::new(CpuType::ARM64).run(&g, state);
+        assert_eq!(res.outcome, Outcome::Completed { next_pc: 0x2222 });
+        assert_eq!(res.state.registers["x29"], 0x1111);
+        assert_eq!(res.stack_delta, 16);
+    }
+}
@@ -0,0 +1,286 @@
+use std::cell::RefCell;
+
+use iced_x86::{
+    Decoder, DecoderOptions, FlowControl, FormatMnemonicOptions, Formatter, IntelFormatter,
+    MemorySizeOptions, Mnemonic, OpKind, Register,
+};
+
+use crate::common::GenericResult;
+use crate::cpu::{Cpu, CpuType};
+use crate::error::Error;
+use crate::gadget::{Instruction, InstructionGroup, Operand};
+
+use super::{rop_sequence, Disassembler, DisassemblyEngineType};
+
+//
+// iced-x86 disassembler implementation, for x86 and x64 only
+//
+
+pub struct IcedDisassembler {
+    bitness: u32,
+    formatter: RefCell<IntelFormatter>,
+}
+
+impl IcedDisassembler {
+    pub fn new(cpu: &dyn Cpu) -> GenericResult<Self> {
+        let bitness = match cpu.cpu_type() {
+            CpuType::X86 => 32,
+            CpuType::X64 => 64,
+            cpu_type => {
+                return Err(Error::EngineError {
+                    engine: String::from("iced-x86"),
+                    cpu: cpu_type,
+                    reason: String::from("only x86 and x64 are supported"),
+                })
+            }
+        };
+
+        Ok(Self {
+            bitness,
+            formatter: RefCell::new(Self::formatter()),
+        })
+    }
+
+    ///
+    /// The formatter is set up to print the instructions the way capstone does, so the text of
+    /// the gadgets does not depend on the engine
+    ///
+    fn formatter() -> IntelFormatter {
+        let mut formatter = IntelFormatter::new();
+        let options = formatter.options_mut();
+        options.set_hex_prefix("0x");
+        options.set_hex_suffix("");
+        options.set_uppercase_hex(false);
+        options.set_space_after_operand_separator(true);
+        options.set_space_between_memory_add_operators(true);
+        options.set_memory_size_options(MemorySizeOptions::Always);
+        options.set_rip_relative_addresses(true);
+        options.set_show_branch_size(false);
+        options.set_branch_leading_zeros(false);
+        formatter
+    }
+
+    fn decoder<'a>(&self, code: &'a [u8], address: u64) -> Decoder<'a> {
+        Decoder::with_ip(self.bitness, code, address, DecoderOptions::NONE)
+    }
+
+    ///
+    /// Convert an iced instruction to an `Instruction`
+    ///
+    fn iced_instruction(
+        &self,
+        insn: &iced_x86::Instruction,
+        code: &[u8],
+        address: u64,
+    ) -> Instruction {
+        let mut formatter = self.formatter.borrow_mut();
+
+        let group = Self::group(insn);
+
+        //
+        // the prefixes of the branches (`bnd`, `notrack`, `rep`...) are dropped, like capstone
+        // does, and the far returns are written `retf`
+        //
+        let mut mnemonic = String::new();
+        let options = match group {
+            InstructionGroup::Ret | InstructionGroup::Jump | InstructionGroup::Call => {
+                FormatMnemonicOptions::NO_PREFIXES
+            }
+            _ => FormatMnemonicOptions::NONE,
+        };
+        formatter.format_mnemonic_options(insn, &mut mnemonic, options);
+        if mnemonic == "ret far" {
+            mnemonic = String::from("retf");
+        }
+
+        let mut operands = String::new();
+        formatter.format_all_operands(insn, &mut operands);
+
+        let offset = (insn.ip() - address) as usize;
+        let raw = code[offset..offset + insn.len()].to_vec();
+
+        Instruction {
+            size: raw.len(),
+            raw,
+            mnemonic,
+            operands: Some(operands),
+            address: insn.ip(),
+            group,
+            ops: self.operands(insn),
+        }
+    }
+
+    fn group(insn: &iced_x86::Instruction) -> InstructionGroup {
+        //
+        // capstone considers neither the loops as jumps, nor the system calls and returns as
+        // branches
+        //
+        match insn.mnemonic() {
+            Mnemonic::Loop | Mnemonic::Loope | Mnemonic::Loopne => {
+                return InstructionGroup::Undefined
+            }
+            Mnemonic::Syscall | Mnemonic::Sysenter => return InstructionGroup::Int,
+            Mnemonic::Iret | Mnemonic::Iretd | Mnemonic::Iretq => return InstructionGroup::Iret,
+            _ => {}
+        }
+
+        match insn.flow_control() {
+            _ if insn.is_privileged() => InstructionGroup::Privileged,
+            FlowControl::UnconditionalBranch
+            | FlowControl::IndirectBranch
+            | FlowControl::ConditionalBranch => InstructionGroup::Jump,
+            FlowControl::Call | FlowControl::IndirectCall => InstructionGroup::Call,
+            FlowControl::Return => InstructionGroup::Ret,
+            FlowControl::Interrupt => InstructionGroup::Int,
+            _ => InstructionGroup::Undefined,
+        }
+    }
+
+    fn reg_name(reg: Register) -> Option<String> {
+        let name = match reg {
+            Register::None => return None,
+            reg => format!("{:?}", reg).to_lowercase(),
+        };
+
+        //
+        // use the same names as capstone for the low bytes of r8-r15
+        //
+        match name.strip_suffix('l') {
+            Some(prefix) if reg.is_gpr8() && prefix.starts_with('r') => {
+                Some(format!("{}b", prefix))
+            }
+            _ => Some(name),
+        }
+    }
+
+    ///
+    /// Convert the explicit operands from iced to `Operand`
+    ///
+    fn operands(&self, insn: &iced_x86::Instruction) -> Vec<Operand> {
+        let string_mem = |base: &str, size: usize| Operand::Mem {
+            base: Some(base.to_string()),
+            index: None,
+            scale: 1,
+            disp: 0,
+            size,
+            writeback: false,
+        };
+
+        (0..insn.op_count())
+            .map(|i| match insn.op_kind(i) {
+                OpKind::Register => match Self::reg_name(insn.op_register(i)) {
+                    Some(name) => Operand::Reg(name),
+                    None => Operand::Unknown,
+                },
+                OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => {
+                    Operand::Imm(insn.near_branch_target() as i64)
+                }
+                OpKind::Immediate8
+                | OpKind::Immediate8_2nd
+                | OpKind::Immediate16
+                | OpKind::Immediate32
+                | OpKind::Immediate64
+                | OpKind::Immediate8to64
+                | OpKind::Immediate32to64 => Operand::Imm(insn.immediate(i) as i64),
+
+                //
+                // like capstone, the immediates sign-extended to 16 or 32 bits are not further
+                // extended to 64 bits
+                //
+                OpKind::Immediate8to16 => Operand::Imm(insn.immediate(i) as u16 as i64),
+                OpKind::Immediate8to32 => Operand::Imm(insn.immediate(i) as u32 as i64),
+                OpKind::MemorySegSI => string_mem("si", insn.memory_size().size()),
+                OpKind::MemorySegESI => string_mem("esi", insn.memory_size().size()),
+                OpKind::MemorySegRSI => string_mem("rsi", insn.memory_size().size()),
+                OpKind::MemorySegDI | OpKind::MemoryESDI => {
+                    string_mem("di", insn.memory_size().size())
+                }
+                OpKind::MemorySegEDI | OpKind::MemoryESEDI => {
+                    string_mem("edi", insn.memory_size().size())
+                }
+                OpKind::MemorySegRDI | OpKind::MemoryESRDI => {
+                    string_mem("rdi", insn.memory_size().size())
+                }
+                OpKind::Memory => {
+                    //
+                    // iced resolves the rip-relative displacements, capstone does not
+                    //
+                    let disp = match insn.memory_base() {
+                        Register::RIP | Register::EIP => {
+                            insn.memory_displacement64().wrapping_sub(insn.next_ip()) as i64
+                        }
+                        Register::None if insn.memory_index() == Register::None => {
+                            insn.memory_displacement64() as i64
+                        }
+                        _ if self.bitness == 32 => insn.memory_displacement32() as i32 as i64,
+                        _ => insn.memory_displacement64() as i64,
+                    };
+
+                    //
+                    // `lea` does not access memory, capstone gives it the size of its result
+                    //
+                    let size = match insn.mnemonic() {
+                        Mnemonic::Lea => insn.op0_register().size(),
+                        _ => insn.memory_size().size(),
+                    };
+
+                    Operand::Mem {
+                        base: Self::reg_name(insn.memory_base()),
+                        index: Self::reg_name(insn.memory_index()),
+                        scale: insn.memory_index_scale() as i32,
+                        disp,
+                        size,
+                        writeback: false,
+                    }
+                }
+                _ => Operand::Unknown,
+            })
+            .collect()
+    }
+}
+
+impl Disassembler for IcedDisassembler {
+    fn disassemble(&self, code: &[u8], address: u64) -> Option<Vec<Instruction>> {
+        let mut decoder = self.decoder(code, address);
+        let mut candidates = Vec::new();
+
+        //
+        // like capstone, stop at the first invalid (or truncated) instruction
+        //
+        while decoder.can_decode() {
+            let insn = decoder.decode();
+            if insn.is_invalid() {
+                break;
+            }
+            candidates.push(self.iced_instruction(&insn, code, address));
+        }
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        Some(rop_sequence(candidates))
+    }
+
+    fn disassemble_one(&self, code: &[u8], address: u64) -> Option<Instruction> {
+        let insn = self.decoder(code, address).decode();
+        if insn.is_invalid() {
+            return None;
+        }
+        Some(self.iced_instruction(&insn, code, address))
+    }
+
+    fn name(&self) -> String {
+        String::from("iced-x86")
+    }
+
+    fn id(&self) -> DisassemblyEngineType {
+        DisassemblyEngineType::Iced
+    }
+}
+
+impl std::fmt::Display for IcedDisassembler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Disassembler({})", self.name())
+    }
+}
@@ -0,0 +1,388 @@
+use capstone::arch::arm::{ArmOperandType, ArmShift};
+use capstone::arch::arm64::{Arm64Extender, Arm64OperandType, Arm64Shift};
+use capstone::arch::x86::X86OperandType;
+use capstone::arch::ArchDetail;
+use capstone::prelude::*;
+use clap::ValueEnum;
+
+use crate::common::GenericResult;
+use crate::cpu::{Cpu, CpuType};
+use crate::error::Error;
+use crate::gadget::{Instruction, InstructionGroup, Operand};
+
+#[cfg(feature = "iced")]
+mod iced;
+
+#[cfg(feature = "iced")]
+pub use self::iced::IcedDisassembler;
+
+/**
+ *
+ * capstone is always available, other engines are behind a feature of the same name
+ *
+ */
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DisassemblyEngineType {
+    #[default]
+    #[value(skip)]
+    Invalid,
+    Capstone,
+    /// iced-x86, for x86 and x64 only
+    #[cfg(feature = "iced")]
+    Iced,
+}
+
+// impl Default for DisassemblyEngineType {
+//     fn default() -> Self {
+//         DisassemblyEngineType::Invalid
+//     }
+// }
+
+//
+// All disassembler must implement this trait
+//
+pub trait Disassembler {
+    fn disassemble(&self, code: &[u8], address: u64) -> Option<Vec<Instruction>>;
+
+    ///
+    /// Decode only the first instruction of `code`, if valid
+    ///
+    fn disassemble_one(&self, code: &[u8], address: u64) -> Option<Instruction>;
+
+    fn name(&self) -> String;
+    fn id(&self) -> DisassemblyEngineType;
+}
+
+pub struct DisassemblyEngine {
+    pub disassembler: Box<dyn Disassembler>,
+}
+
+impl DisassemblyEngine {
+    pub fn new(engine_type: &DisassemblyEngineType, cpu: &dyn Cpu) -> GenericResult<Self> {
+        match engine_type {
+            DisassemblyEngineType::Capstone => Ok(Self {
+                disassembler: Box::new(CapstoneDisassembler::new(cpu)?),
+            }),
+            #[cfg(feature = "iced")]
+            DisassemblyEngineType::Iced => Ok(Self {
+                disassembler: Box::new(IcedDisassembler::new(cpu)?),
+            }),
+            DisassemblyEngineType::Invalid => Err(Error::EngineError {
+                engine: format!("{:?}", engine_type),
+                cpu: cpu.cpu_type(),
+                reason: String::from("no disassembly engine was selected"),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for DisassemblyEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Engine({})", self.disassembler.name())
+    }
+}
+
+//
+// Capstone disassembler implementation
+//
+
+// https://github.com/aquynh/capstone/blob/1b5014515d0d671048e2b43ce483d38d85a2bc83/bindings/python/capstone/__init__.py#L216
+const INSN_GRP_JUMP: u8 = 0x01;
+const INSN_GRP_CALL: u8 = 0x02;
+const INSN_GRP_RET: u8 = 0x03;
+const INSN_GRP_INT: u8 = 0x04;
+const INSN_GRP_IRET: u8 = 0x05;
+const INSN_GRP_PRIV: u8 = 0x06;
+
+pub struct CapstoneDisassembler {
+    cs: Capstone,
+    cpu_type: CpuType,
+}
+
+impl Disassembler for CapstoneDisassembler {
+    fn disassemble(&self, code: &[u8], address: u64) -> Option<Vec<Instruction>> {
+        self.cs_disassemble(code, address)
+    }
+
+    fn disassemble_one(&self, code: &[u8], address: u64) -> Option<Instruction> {
+        let cs_insns = self.cs.disasm_count(code, address, 1).ok()?;
+        cs_insns
+            .iter()
+            .next()
+            .and_then(|cs_insn| self.cs_instruction(cs_insn))
+    }
+
+    fn name(&self) -> String {
+        // todo: add version strings
+        let (major, minor) = Capstone::lib_version();
+        format!("Capstone-Engine({}.{})", major, minor)
+    }
+
+    fn id(&self) -> DisassemblyEngineType {
+        DisassemblyEngineType::Capstone
+    }
+}
+
+impl std::fmt::Display for CapstoneDisassembler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Disassembler({})", self.name())
+    }
+}
+
+///
+/// Keep only the instructions after the last branch of `candidates` (except the final one), as a
+/// rop sequence cannot go through the others
+///
+fn rop_sequence(candidates: Vec<Instruction>) -> Vec<Instruction> {
+    let mut insns: Vec<Instruction> = Vec::new();
+
+    for insn in candidates.into_iter().rev() {
+        match insn.group {
+            InstructionGroup::Jump | InstructionGroup::Call | InstructionGroup::Ret
+                if !insns.is_empty() =>
+            {
+                break;
+            }
+            _ => {}
+        };
+
+        insns.insert(0, insn);
+    }
+
+    insns
+}
+
+///
+/// Capstone does not flag the returns of ARM (which are loads or moves to `pc`) as such, and
+/// flags `blx` as a jump
+///
+fn arm_group(mnemonic: &str, operands: &str, group: InstructionGroup) -> InstructionGroup {
+    match mnemonic {
+        "bx" if operands == "lr" => InstructionGroup::Ret,
+        "pop" | "pop.w" if operands.contains("pc}") => InstructionGroup::Ret,
+        "ldm" | "ldm.w" | "ldmia" | "ldmia.w" | "ldmfd"
+            if operands.starts_with("sp!") && operands.contains("pc}") =>
+        {
+            InstructionGroup::Ret
+        }
+        "ldr" | "ldr.w" if operands.starts_with("pc, [sp]") => InstructionGroup::Ret,
+        "blx" => InstructionGroup::Call,
+        _ => group,
+    }
+}
+
+impl CapstoneDisassembler {
+    fn new(cpu: &dyn Cpu) -> GenericResult<Self> {
+        let engine_error = |reason: String| Error::EngineError {
+            engine: String::from("Capstone"),
+            cpu: cpu.cpu_type(),
+            reason,
+        };
+
+        let cs = match cpu.cpu_type() {
+            CpuType::X86 => Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode32)
+                .syntax(arch::x86::ArchSyntax::Intel)
+                .detail(true)
+                .build(),
+
+            CpuType::X64 => Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode64)
+                .syntax(arch::x86::ArchSyntax::Intel)
+                .detail(true)
+                .build(),
+
+            CpuType::ARM => Capstone::new()
+                .arm()
+                .mode(arch::arm::ArchMode::Arm)
+                .detail(true)
+                .build(),
+
+            CpuType::Thumb => Capstone::new()
+                .arm()
+                .mode(arch::arm::ArchMode::Thumb)
+                .detail(true)
+                .build(),
+
+            CpuType::ARM64 => Capstone::new()
+                .arm64()
+                .mode(arch::arm64::ArchMode::Arm)
+                .detail(true)
+                .build(),
+
+            CpuType::Unknown => {
+                return Err(engine_error(String::from("unknown architecture")));
+            }
+        };
+
+        Ok(Self {
+            cs: cs.map_err(|e| engine_error(e.to_string()))?,
+            cpu_type: cpu.cpu_type(),
+        })
+    }
+
+    fn cs_disassemble(&self, code: &[u8], address: u64) -> Option<Vec<Instruction>> {
+        let cs_insns = self.cs.disasm_all(code, address).ok()?;
+
+        //
+        // Any instruction?
+        //
+        if cs_insns.is_empty() {
+            return None;
+        }
+
+        //
+        // Otherwise we're good to proceed
+        //
+        let mut candidates: Vec<Instruction> = Vec::new();
+
+        for cs_insn in cs_insns.iter() {
+            candidates.push(self.cs_instruction(cs_insn)?);
+        }
+
+        Some(rop_sequence(candidates))
+    }
+
+    ///
+    /// Convert a capstone instruction to an `Instruction`, if capstone could describe it
+    ///
+    fn cs_instruction(&self, cs_insn: &capstone::Insn) -> Option<Instruction> {
+        let detail: InsnDetail = self.cs.insn_detail(cs_insn).ok()?;
+
+        let mut insn_group = InstructionGroup::Undefined;
+
+        for cs_insn_group in detail.groups() {
+            insn_group = match cs_insn_group.0 {
+                INSN_GRP_JUMP => InstructionGroup::Jump,
+                INSN_GRP_CALL => InstructionGroup::Call,
+                INSN_GRP_RET => InstructionGroup::Ret,
+                INSN_GRP_PRIV => InstructionGroup::Privileged,
+                INSN_GRP_INT => InstructionGroup::Int,
+                INSN_GRP_IRET => InstructionGroup::Iret,
+                _ => {
+                    continue;
+                }
+            };
+        }
+
+        let mnemonic = cs_insn.mnemonic()?.to_string();
+
+        let operands: Option<String> = cs_insn.op_str().map(|op| op.to_string());
+
+        if matches!(self.cpu_type, CpuType::ARM | CpuType::Thumb) {
+            insn_group = arm_group(&mnemonic, operands.as_deref().unwrap_or(""), insn_group);
+        }
+
+        let ops = self.cs_operands(&mnemonic, &detail);
+
+        Some(Instruction {
+            raw: cs_insn.bytes().to_vec(),
+            size: cs_insn.bytes().len(),
+            mnemonic,
+            operands,
+            address: cs_insn.address(),
+            group: insn_group,
+            ops,
+        })
+    }
+
+    fn cs_reg_name(&self, reg: RegId) -> Option<String> {
+        if reg.0 == 0 {
+            return None;
+        }
+        self.cs.reg_name(reg).map(|name| name.to_lowercase())
+    }
+
+    ///
+    /// Convert the architecture specific operands from capstone to `Operand`
+    ///
+    fn cs_operands(&self, mnemonic: &str, detail: &InsnDetail) -> Vec<Operand> {
+        let reg = |r: RegId| match self.cs_reg_name(r) {
+            Some(name) => Operand::Reg(name),
+            None => Operand::Unknown,
+        };
+
+        match detail.arch_detail() {
+            ArchDetail::X86Detail(x86) => x86
+                .operands()
+                .map(|op| match op.op_type {
+                    X86OperandType::Reg(r) => reg(r),
+                    X86OperandType::Imm(imm) => Operand::Imm(imm),
+                    X86OperandType::Mem(mem) => Operand::Mem {
+                        base: self.cs_reg_name(mem.base()),
+                        index: self.cs_reg_name(mem.index()),
+                        scale: mem.scale(),
+                        disp: mem.disp(),
+                        size: op.size as usize,
+                        writeback: false,
+                    },
+                    X86OperandType::Invalid => Operand::Unknown,
+                })
+                .collect(),
+
+            //
+            // capstone-rs cannot safely convert the system register operands of MRS/MSR
+            //
+            ArchDetail::Arm64Detail(_) if mnemonic == "mrs" || mnemonic == "msr" => {
+                vec![Operand::Unknown]
+            }
+
+            ArchDetail::Arm64Detail(arm64) => {
+                let writeback = arm64.writeback();
+                arm64
+                    .operands()
+                    .map(|op| match op.op_type {
+                        //
+                        // shifted and extended operands are not modeled
+                        //
+                        _ if op.shift != Arm64Shift::Invalid
+                            || op.ext != Arm64Extender::ARM64_EXT_INVALID =>
+                        {
+                            Operand::Unknown
+                        }
+                        Arm64OperandType::Reg(r) => reg(r),
+                        Arm64OperandType::Imm(imm) | Arm64OperandType::Cimm(imm) => {
+                            Operand::Imm(imm)
+                        }
+                        Arm64OperandType::Mem(mem) => Operand::Mem {
+                            base: self.cs_reg_name(mem.base()),
+                            index: self.cs_reg_name(mem.index()),
+                            scale: 1,
+                            disp: mem.disp() as i64,
+                            size: 0,
+                            writeback,
+                        },
+                        _ => Operand::Unknown,
+                    })
+                    .collect()
+            }
+
+            ArchDetail::ArmDetail(arm) => {
+                let writeback = arm.writeback();
+                arm.operands()
+                    .map(|op| match op.op_type {
+                        _ if op.shift != ArmShift::Invalid || op.subtracted => Operand::Unknown,
+                        ArmOperandType::Reg(r) => reg(r),
+                        ArmOperandType::Imm(imm)
+                        | ArmOperandType::Cimm(imm)
+                        | ArmOperandType::Pimm(imm) => Operand::Imm(imm as i64),
+                        ArmOperandType::Mem(mem) => Operand::Mem {
+                            base: self.cs_reg_name(mem.base()),
+                            index: self.cs_reg_name(mem.index()),
+                            scale: mem.scale(),
+                            disp: mem.disp() as i64,
+                            size: 0,
+                            writeback,
+                        },
+                        _ => Operand::Unknown,
+                    })
+                    .collect()
+            }
+
+            _ => Vec::new(),
+        }
+    }
+}
@@ -1,24 +1,154 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::cpu::CpuType;
+use crate::gadget::InstructionGroup;
+use crate::render::Syntax;
+
 #[derive(Debug)]
 pub enum Error {
     IoError(std::io::Error),
     ParsingError(goblin::error::Error),
     ThreadRuntimeError(std::boxed::Box<dyn std::any::Any + std::marker::Send>),
-    InvalidFileError,
-}
+    SerializationError(serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    DatabaseError(rusqlite::Error),
 
-#[derive(Debug)]
-pub struct GenericError {
-    msg: String,
+    /// The file cannot be read or parsed as an executable
+    InvalidFile {
+        path: PathBuf,
+        reason: String,
+    },
+
+    /// The file is a valid container, but not one which can be searched (archive, fat Mach-O...)
+    UnsupportedFormat {
+        path: PathBuf,
+        format: String,
+    },
+
+    /// The machine of the file is not supported, and no architecture was forced
+    UnsupportedArchitecture {
+        path: PathBuf,
+        machine: String,
+    },
+
+    /// Two files which must share an architecture (e.g. to be compared) do not
+    ArchitectureMismatch {
+        old: (PathBuf, CpuType),
+        new: (PathBuf, CpuType),
+    },
+
+    /// The data of a section lies outside of the file
+    InvalidSection {
+        path: PathBuf,
+        section: String,
+        offset: u64,
+    },
+
+    /// The disassembly engine could not be created
+    EngineError {
+        engine: String,
+        cpu: CpuType,
+        reason: String,
+    },
+
+    /// No terminating instruction is known for this type of gadget
+    UnsupportedGadgetType(InstructionGroup),
+
+    /// A gadget needs at least one instruction
+    EmptyGadget,
+
+    /// The syntax cannot be used to render the instructions of this architecture
+    UnsupportedSyntax {
+        syntax: Syntax,
+        cpu: CpuType,
+    },
+
+    /// The pattern to search for cannot be parsed
+    InvalidPattern {
+        pattern: String,
+        reason: String,
+    },
 }
 
-impl std::fmt::Display for GenericError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}'", self.msg)
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IoError(e) => write!(f, "I/O error: {}", e),
+            Error::ParsingError(e) => write!(f, "parsing error: {}", e),
+            Error::ThreadRuntimeError(e) => {
+                let msg = e
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("unknown reason"));
+                write!(f, "worker thread panicked: {}", msg)
+            }
+            Error::SerializationError(e) => write!(f, "serialization error: {}", e),
+            #[cfg(feature = "sqlite")]
+            Error::DatabaseError(e) => write!(f, "database error: {}", e),
+            Error::InvalidFile { path, reason } => {
+                write!(f, "invalid file '{}': {}", path.display(), reason)
+            }
+            Error::UnsupportedFormat { path, format } => {
+                write!(f, "unsupported format for '{}': {}", path.display(), format)
+            }
+            Error::UnsupportedArchitecture { path, machine } => write!(
+                f,
+                "unsupported architecture for '{}': {} (try forcing one)",
+                path.display(),
+                machine
+            ),
+            Error::ArchitectureMismatch { old, new } => write!(
+                f,
+                "'{}' ({}) and '{}' ({}) have different architectures",
+                old.0.display(),
+                old.1,
+                new.0.display(),
+                new.1
+            ),
+            Error::InvalidSection {
+                path,
+                section,
+                offset,
+            } => write!(
+                f,
+                "section '{}' of '{}' lies outside of the file (offset {:#x})",
+                section,
+                path.display(),
+                offset
+            ),
+            Error::EngineError {
+                engine,
+                cpu,
+                reason,
+            } => write!(f, "cannot create engine {} for {}: {}", engine, cpu, reason),
+            Error::UnsupportedGadgetType(group) => {
+                write!(f, "unsupported gadget type: {}", group)
+            }
+            Error::EmptyGadget => write!(f, "a gadget needs at least one instruction"),
+            Error::UnsupportedSyntax { syntax, cpu } => {
+                write!(f, "unsupported syntax for {}: {}", cpu, syntax)
+            }
+            Error::InvalidPattern { pattern, reason } => {
+                write!(f, "invalid pattern '{}': {}", pattern, reason)
+            }
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct GadgetBuildError {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            Error::ParsingError(e) => Some(e),
+            Error::SerializationError(e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            Error::DatabaseError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
@@ -32,19 +162,21 @@ impl From<goblin::error::Error> for Error {
     }
 }
 
-impl From<std::boxed::Box<dyn std::any::Any + std::marker::Send>> for Error {
-    fn from(error: std::boxed::Box<dyn std::any::Any + std::marker::Send>) -> Self {
-        Error::ThreadRuntimeError(error)
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::SerializationError(error)
     }
 }
 
-#[derive(Debug)]
-pub struct InvalidFormatError {
-    format: String,
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::DatabaseError(error)
+    }
 }
 
-impl std::fmt::Display for InvalidFormatError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Unknown/unsupported format: {}'", self.format)
+impl From<std::boxed::Box<dyn std::any::Any + std::marker::Send>> for Error {
+    fn from(error: std::boxed::Box<dyn std::any::Any + std::marker::Send>) -> Self {
+        Error::ThreadRuntimeError(error)
     }
 }
@@ -1,38 +1,44 @@
 use colored::Colorize;
 use goblin;
 use log::debug;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use memmap2::Mmap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
+use crate::common::GenericResult;
 use crate::cpu;
-use crate::section::Permission;
+use crate::symbols::{Symbol, SymbolTable};
 use crate::{format::FileFormat, section::Section};
 
-use super::ExecutableFileFormat;
+use super::{section_data, ExecutableFileFormat, SectionFilter};
 
 #[derive(Debug)]
 pub struct Elf {
     path: PathBuf,
     sections: Vec<Section>,
+    symbols: SymbolTable,
     // cpu: Box<dyn cpu::Cpu>,
     cpu_type: cpu::CpuType,
     entry_point: u64,
+    image_base: u64,
 }
 
 impl Elf {
-    pub fn new(path: PathBuf, obj: goblin::elf::Elf) -> Self {
-        let filepath = path.to_str().unwrap();
-
-        let mut executable_sections: Vec<Section> = Vec::new();
+    pub fn new(
+        path: PathBuf,
+        obj: goblin::elf::Elf,
+        mapping: &Arc<Mmap>,
+        filter: SectionFilter,
+    ) -> GenericResult<Self> {
+        let filepath = path.display().to_string();
+
+        let mut sections: Vec<Section> = Vec::new();
         debug!(
-            "looking for executable sections in ELF: '{}'",
+            "looking for {:?} sections in ELF: '{}'",
+            filter,
             filepath.bold()
         );
 
-        let file = File::open(&path).unwrap();
-        let mut reader = BufReader::new(file);
-
         for current_section in &obj.section_headers {
             // trace!("Testing section {:?}", s);
 
@@ -48,33 +54,25 @@ impl Elf {
             // let mut section = Section::from(s);
             // section.name = Some(String::from(&obj.shdr_strtab[s.sh_name]));
 
-            let mut sect =
+            let sect =
                 Section::from(current_section).name(&obj.shdr_strtab[current_section.sh_name]);
 
-            if !sect.permission.contains(Permission::EXECUTABLE) {
+            if !filter.keeps(&sect) {
                 continue;
             }
 
-            if reader
-                .seek(SeekFrom::Start(current_section.sh_addr))
-                .is_err()
-            {
-                panic!("Invalid offset {}", current_section.sh_addr,)
-            }
-
-            match reader.read_exact(&mut sect.data) {
-                Ok(_) => {}
-                Err(e) => panic!(
-                    "Failed to extract section '{}' (size={:#x}) at offset {:#x}: {:?}",
-                    &sect.name.clone().unwrap_or_default(),
-                    &sect.size(),
-                    sect.start_address,
-                    e
-                ),
+            //
+            // the sections without bits in the file (e.g. `.bss`) are zero-filled when mapped
+            //
+            let size = match current_section.sh_type {
+                goblin::elf::section_header::SHT_NOBITS => 0,
+                _ => sect.size(),
             };
+            let data = section_data(&path, mapping, &sect, current_section.sh_offset, size)?;
+            let sect = sect.data(data);
 
             debug!("Adding {}", sect);
-            executable_sections.push(sect);
+            sections.push(sect);
         }
 
         // let cpu_type = match obj.header.e_machine {
@@ -87,12 +85,53 @@ impl Elf {
         //     }
         // };
 
-        Self {
+        //
+        // the image base is the lowest loadable segment (0 for PIE)
+        //
+        let image_base = obj
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == goblin::elf::program_header::PT_LOAD)
+            .map(|ph| ph.p_vaddr)
+            .min()
+            .unwrap_or(0);
+
+        let cpu_type = cpu::CpuType::from(&obj.header);
+
+        //
+        // the functions of both the static and the dynamic symbol tables (the former is often
+        // stripped). the lowest bit of the address of thumb functions is set, but not part of it
+        //
+        let tables = [(&obj.syms, &obj.strtab), (&obj.dynsyms, &obj.dynstrtab)];
+        let mut symbols = Vec::new();
+        for (syms, strtab) in tables {
+            for sym in syms.iter() {
+                if !sym.is_function() || sym.st_shndx == 0 || sym.st_value == 0 {
+                    continue;
+                }
+
+                let address = match cpu_type {
+                    cpu::CpuType::ARM | cpu::CpuType::Thumb => sym.st_value & !1,
+                    _ => sym.st_value,
+                };
+                symbols.push(Symbol {
+                    name: strtab.get_at(sym.st_name).unwrap_or_default().to_string(),
+                    address,
+                    size: sym.st_size,
+                });
+            }
+        }
+        let symbols = SymbolTable::new(symbols);
+        debug!("{} function symbol(s) found", symbols.len());
+
+        Ok(Self {
             path: path.clone(),
-            sections: executable_sections,
-            cpu_type: cpu::CpuType::from(&obj.header),
+            sections,
+            symbols,
+            cpu_type,
             entry_point: obj.entry,
-        }
+            image_base,
+        })
     }
 }
 
@@ -109,6 +148,10 @@ impl ExecutableFileFormat for Elf {
         &self.sections
     }
 
+    fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
     // fn cpu(&self) -> &dyn cpu::Cpu {
     //     self.cpu.as_ref()
     // }
@@ -120,4 +163,8 @@ impl ExecutableFileFormat for Elf {
     fn entry_point(&self) -> u64 {
         self.entry_point
     }
+
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
 }
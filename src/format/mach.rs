@@ -1,35 +1,54 @@
 // use std::fs::File;
 // use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use colored::Colorize;
 use goblin;
+use goblin::mach::symbols::{N_SECT, N_STAB, N_TYPE};
 use log::debug;
+use memmap2::Mmap;
 
+use crate::common::GenericResult;
 use crate::cpu;
-use crate::{format::FileFormat, section::Permission, section::Section};
+use crate::error::Error;
+use crate::symbols::{Symbol, SymbolTable};
+use crate::{format::FileFormat, section::Section};
 
-use super::ExecutableFileFormat;
+use super::{section_data, ExecutableFileFormat, SectionFilter};
 
 pub struct Mach {
     path: PathBuf,
     sections: Vec<Section>,
+    symbols: SymbolTable,
     cpu_type: cpu::CpuType,
     entry_point: u64,
+    image_base: u64,
 }
 impl Mach {
-    pub fn new(path: PathBuf, obj: goblin::mach::Mach) -> Self {
+    pub fn new(
+        path: PathBuf,
+        obj: goblin::mach::Mach,
+        mapping: &Arc<Mmap>,
+        filter: SectionFilter,
+    ) -> GenericResult<Self> {
         let bin = match obj {
             goblin::mach::Mach::Binary(macho) => macho,
-            goblin::mach::Mach::Fat(_) => todo!(),
+            goblin::mach::Mach::Fat(_) => {
+                return Err(Error::UnsupportedFormat {
+                    path,
+                    format: String::from("fat Mach-O"),
+                })
+            }
         };
 
-        let filepath = path.to_str().unwrap();
+        let filepath = path.display().to_string();
 
-        let mut executable_sections: Vec<Section> = Vec::new();
+        let mut sections: Vec<Section> = Vec::new();
 
         debug!(
-            "looking for executables sections in MachO: '{}'",
+            "looking for {:?} sections in MachO: '{}'",
+            filter,
             filepath.bold()
         );
 
@@ -53,19 +72,23 @@ impl Mach {
             // let perm = Permission::EXECUTABLE | Permission::READABLE; // todo: fix later
             // section.permission = perm;
 
-            let section = Section::from(current_segment).data(current_segment.data.to_vec());
+            let section = Section::from(current_segment);
 
-            if !section.permission.contains(Permission::EXECUTABLE) {
+            if !filter.keeps(&section) {
                 continue;
             }
 
+            let size = current_segment.filesize as usize;
+            let data = section_data(&path, mapping, &section, current_segment.fileoff, size)?;
+            let section = section.data(data);
+
             // reader
             //     .seek(SeekFrom::Start(current_segment.fileoff as u64))
             //     .unwrap();
             // reader.read_exact(&mut section.data).unwrap();
 
             debug!("Adding {}", section);
-            executable_sections.push(section);
+            sections.push(section);
             // }
         }
 
@@ -79,12 +102,47 @@ impl Mach {
         //     }
         // };
 
-        Self {
+        let image_base = bin
+            .segments
+            .iter()
+            .find(|s| matches!(s.name(), Ok("__TEXT")))
+            .map(|s| s.vmaddr)
+            .unwrap_or(0);
+
+        //
+        // the symbols defined in an executable segment, debugging ones and the one of the mach
+        // header (at the start of `__TEXT`, it is no function) aside. their size is unknown, and
+        // their name keeps the leading underscore of the C symbols
+        //
+        let executable: Vec<Section> = bin
+            .segments
+            .iter()
+            .map(Section::from)
+            .filter(|s| s.is_executable())
+            .collect();
+        let symbols = bin
+            .symbols()
+            .filter_map(|s| s.ok())
+            .filter(|(_, nlist)| nlist.n_type & N_STAB == 0 && nlist.n_type & N_TYPE == N_SECT)
+            .filter(|(name, _)| *name != "__mh_execute_header")
+            .filter(|(_, nlist)| executable.iter().any(|s| s.contains(nlist.n_value)))
+            .map(|(name, nlist)| Symbol {
+                name: name.to_string(),
+                address: nlist.n_value,
+                size: 0,
+            })
+            .collect();
+        let symbols = SymbolTable::new(symbols);
+        debug!("{} symbol(s) found", symbols.len());
+
+        Ok(Self {
             path: path.clone(),
-            sections: executable_sections,
+            sections,
+            symbols,
             cpu_type: cpu::CpuType::from(&bin.header),
             entry_point: bin.entry,
-        }
+            image_base,
+        })
     }
 }
 
@@ -101,6 +159,10 @@ impl ExecutableFileFormat for Mach {
         &self.sections
     }
 
+    fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
     // fn cpu(&self) -> &dyn cpu::Cpu {
     //     self.cpu.as_ref()
     // }
@@ -112,4 +174,8 @@ impl ExecutableFileFormat for Mach {
     fn entry_point(&self) -> u64 {
         self.entry_point
     }
+
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
 }
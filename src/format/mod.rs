@@ -2,12 +2,23 @@ pub mod elf;
 pub mod mach;
 pub mod pe;
 
-use std::{fs, path::PathBuf};
-
-use crate::{common::GenericResult, cpu::CpuType, error::Error, section::Section};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    common::GenericResult,
+    cpu::CpuType,
+    error::Error,
+    section::{Section, SectionData},
+    symbols::SymbolTable,
+};
 
 use clap::ValueEnum;
 use goblin::Object;
+use memmap2::Mmap;
 
 #[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
 pub enum FileFormat {
@@ -25,13 +36,35 @@ impl std::fmt::Display for FileFormat {
             FileFormat::Pe => "PE",
             FileFormat::Elf => "ELF",
             FileFormat::MachO => "MachO",
-            _ => panic!("Invalid FileFormat"),
+            FileFormat::Auto => "Auto",
         };
 
         write!(f, "BinaryFormat={}", val)
     }
 }
 
+///
+/// Which sections of the executable are loaded
+///
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
+pub enum SectionFilter {
+    /// The executable sections only, which is all the gadget search needs
+    #[default]
+    Executable,
+    /// All the sections mapped in memory at runtime, with their permissions (e.g. to look for
+    /// strings or writable addresses)
+    Mapped,
+}
+
+impl SectionFilter {
+    pub fn keeps(&self, section: &Section) -> bool {
+        match self {
+            SectionFilter::Executable => section.is_executable(),
+            SectionFilter::Mapped => !section.permission.is_empty(),
+        }
+    }
+}
+
 /// Trait specific to executable files
 pub trait ExecutableFileFormat: Send + Sync {
     fn path(&self) -> &PathBuf;
@@ -40,35 +73,85 @@ pub trait ExecutableFileFormat: Send + Sync {
 
     fn sections(&self) -> &Vec<Section>;
 
+    /// The functions of the executable which are in its executable sections
+    fn symbols(&self) -> &SymbolTable;
+
     // fn cpu(&self) -> &dyn cpu::Cpu;
 
     fn cpu_type(&self) -> CpuType;
 
     fn entry_point(&self) -> u64;
+
+    /// The preferred load address of the executable
+    fn image_base(&self) -> u64;
+
+    /// Convert the address of a gadget to an address relative to the image base
+    fn rva(&self, address: u64) -> u64 {
+        address.saturating_sub(self.image_base())
+    }
+
+    /// Convert the address of a gadget to its virtual address, at the preferred image base
+    fn va(&self, address: u64) -> u64 {
+        address
+    }
 }
 
 /// Attempt to determine the file
-pub fn guess_file_format(file: &PathBuf) -> GenericResult<Box<dyn ExecutableFileFormat>> {
-    if !file.as_path().exists() {
-        return Err(Error::InvalidFileError);
-    }
+pub fn guess_file_format(
+    file: &Path,
+    filter: SectionFilter,
+) -> GenericResult<Box<dyn ExecutableFileFormat>> {
+    let invalid = |reason: String| Error::InvalidFile {
+        path: file.to_path_buf(),
+        reason,
+    };
 
-    let buffer = match fs::read(file.as_path()) {
-        Ok(buf) => buf,
-        Err(_) => return Err(Error::InvalidFileError),
+    //
+    // the file is mapped once, and its sections are slices of that mapping
+    //
+    let mapping = match fs::File::open(file).and_then(|f| {
+        // SAFETY: the mapping is read-only, and the file is not expected to be modified while it
+        // is being analyzed
+        unsafe { Mmap::map(&f) }
+    }) {
+        Ok(mapping) => Arc::new(mapping),
+        Err(e) => return Err(invalid(e.to_string())),
     };
 
-    let parsed = match Object::parse(&buffer) {
+    let parsed = match Object::parse(&mapping) {
         Ok(e) => e,
-        Err(_) => return Err(Error::InvalidFileError),
+        Err(e) => return Err(invalid(e.to_string())),
     };
 
+    let path = file.to_path_buf();
     match parsed {
-        Object::PE(obj) => Ok(Box::new(pe::Pe::new(file.to_path_buf(), obj))),
-        Object::Elf(obj) => Ok(Box::new(elf::Elf::new(file.to_path_buf(), obj))),
-        Object::Mach(obj) => Ok(Box::new(mach::Mach::new(file.to_path_buf(), obj))),
-        Object::Archive(_) => Err(Error::InvalidFileError),
-        Object::Unknown(_) => Err(Error::InvalidFileError),
-        _ => Err(Error::InvalidFileError),
+        Object::PE(obj) => Ok(Box::new(pe::Pe::new(path, obj, &mapping, filter)?)),
+        Object::Elf(obj) => Ok(Box::new(elf::Elf::new(path, obj, &mapping, filter)?)),
+        Object::Mach(obj) => Ok(Box::new(mach::Mach::new(path, obj, &mapping, filter)?)),
+        Object::Archive(_) => Err(Error::UnsupportedFormat {
+            path,
+            format: String::from("archive"),
+        }),
+        _ => Err(invalid(String::from("unknown format"))),
+    }
+}
+
+///
+/// Borrow the data of a section from the mapping of its file, checking that it starts inside
+///
+pub(crate) fn section_data(
+    path: &Path,
+    mapping: &Arc<Mmap>,
+    section: &Section,
+    offset: u64,
+    size: usize,
+) -> GenericResult<SectionData> {
+    if size > 0 && offset >= mapping.len() as u64 {
+        return Err(Error::InvalidSection {
+            path: path.to_path_buf(),
+            section: section.name.clone().unwrap_or_default(),
+            offset,
+        });
     }
+    Ok(SectionData::mapped(mapping, offset, size))
 }
@@ -1,23 +1,27 @@
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use goblin;
 use log::debug;
+use memmap2::Mmap;
 
+use crate::common::GenericResult;
 use crate::cpu::{self, CpuType};
+use crate::symbols::{Symbol, SymbolTable};
 // use crate::cpu;
-use crate::{format::FileFormat, section::Permission, section::Section};
+use crate::{format::FileFormat, section::Section};
 
-use super::ExecutableFileFormat;
+use super::{section_data, ExecutableFileFormat, SectionFilter};
 
 #[derive(Debug)]
 #[derive(Default)]
 pub struct Pe {
     path: PathBuf,
     pub sections: Vec<Section>,
+    pub symbols: SymbolTable,
     // cpu: Box<dyn cpu::Cpu>,
     pub entry_point: u64,
+    pub image_base: u64,
 
     cpu_type: cpu::CpuType,
 }
@@ -25,10 +29,13 @@ pub struct Pe {
 
 
 impl Pe {
-    pub fn new(path: PathBuf, obj: goblin::pe::PE<'_>) -> Self {
-        let mut executable_sections: Vec<Section> = Vec::new();
-        let file = File::open(&path).unwrap();
-        let mut reader = BufReader::new(file);
+    pub fn new(
+        path: PathBuf,
+        obj: goblin::pe::PE<'_>,
+        mapping: &Arc<Mmap>,
+        filter: SectionFilter,
+    ) -> GenericResult<Self> {
+        let mut sections: Vec<Section> = Vec::new();
 
         for current_section in &obj.sections {
             // if s.characteristics & goblin::pe::section_table::IMAGE_SCN_MEM_EXECUTE == 0 {
@@ -60,31 +67,59 @@ impl Pe {
 
             // let data = s.data();
 
-            let mut section = Section::from(current_section);
+            let section = Section::from(current_section);
 
-            if !section.permission.contains(Permission::EXECUTABLE) {
+            if !filter.keeps(&section) {
                 continue;
             }
 
-            match reader.seek(SeekFrom::Start(current_section.pointer_to_raw_data as u64)) {
-                Ok(_) => {}
-                Err(e) => panic!("Corrupted PE: {:?}", e),
-            };
-
-            reader.read_exact(&mut section.data).unwrap();
+            //
+            // past its raw data, a section is zero-filled when mapped
+            //
+            let offset = current_section.pointer_to_raw_data as u64;
+            let size = std::cmp::min(section.size(), current_section.size_of_raw_data as usize);
+            let data = section_data(&path, mapping, &section, offset, size)?;
+            let section = section.data(data);
 
             debug!("Adding {}", section);
-            executable_sections.push(section);
+            sections.push(section);
         }
 
-        Self {
+        //
+        // the exported functions, i.e. those in executable sections and not forwarded to another
+        // dll. their size is unknown
+        //
+        let executable: Vec<Section> = obj
+            .sections
+            .iter()
+            .map(Section::from)
+            .filter(|s| s.is_executable())
+            .collect();
+        let symbols = obj
+            .exports
+            .iter()
+            .filter(|e| e.reexport.is_none())
+            .filter(|e| executable.iter().any(|s| s.contains(e.rva as u64)))
+            .filter_map(|e| {
+                Some(Symbol {
+                    name: e.name?.to_string(),
+                    address: e.rva as u64,
+                    size: 0,
+                })
+            })
+            .collect();
+        let symbols = SymbolTable::new(symbols);
+        debug!("{} exported function(s) found", symbols.len());
+
+        Ok(Self {
             path: path.clone(),
-            sections: executable_sections,
+            sections,
+            symbols,
             // cpu,
             cpu_type: CpuType::from(&obj.header.coff_header),
             entry_point: obj.entry as u64,
-            ..Default::default()
-        }
+            image_base: obj.image_base as u64,
+        })
     }
 }
 
@@ -101,6 +136,10 @@ impl ExecutableFileFormat for Pe {
         &self.sections
     }
 
+    fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
     // fn cpu(&self) -> &dyn cpu::Cpu {
     //     self.cpu.as_ref()
     // }
@@ -112,4 +151,17 @@ impl ExecutableFileFormat for Pe {
     fn cpu_type(&self) -> cpu::CpuType {
         self.cpu_type
     }
+
+    fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    // PE sections are already mapped relative to the image base
+    fn rva(&self, address: u64) -> u64 {
+        address
+    }
+
+    fn va(&self, address: u64) -> u64 {
+        self.image_base + address
+    }
 }
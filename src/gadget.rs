@@ -1,24 +1,30 @@
 extern crate capstone;
 
-use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use std::{fmt, thread};
-use std::{
-    io::{Cursor, Read, Seek, SeekFrom},
-    sync::Arc,
-};
 
 use colored::*;
 use log::{debug, warn};
 
 use crate::common::GenericResult;
 use crate::cpu;
+use crate::emulator;
 use crate::engine::Disassembler;
+use crate::error::Error;
+use crate::matcher::PatternMatcher;
+use crate::search;
 use crate::section::Section;
+use crate::semantics::{summarize, Expr, Summary};
 use crate::session::{RopProfileStrategy, Session};
+use crate::symbols::SymbolOffset;
 
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
-#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[derive(
+    std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize,
+)]
 pub enum InstructionGroup {
     Undefined,
     Jump,
@@ -35,7 +41,26 @@ impl std::fmt::Display for InstructionGroup {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+///
+/// Architecture-independent view of an instruction operand, as decoded by the engine
+///
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operand {
+    Reg(String),
+    Imm(i64),
+    Mem {
+        base: Option<String>,
+        index: Option<String>,
+        scale: i32,
+        disp: i64,
+        size: usize,
+        writeback: bool,
+    },
+    Unknown,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Instruction {
     pub size: usize,
     pub raw: Vec<u8>,
@@ -44,6 +69,7 @@ pub struct Instruction {
 
     pub mnemonic: String,
     pub operands: Option<String>,
+    pub ops: Vec<Operand>,
 }
 
 impl Instruction {
@@ -70,6 +96,52 @@ impl Instruction {
 
         format!("{}{}", mnemo, op)
     }
+
+    ///
+    /// Syntax-independent representation of the instruction, built from the decoded operands
+    ///
+    pub fn normalized(&self) -> String {
+        if self.ops.is_empty() || self.ops.contains(&Operand::Unknown) {
+            return self.text(false).to_lowercase();
+        }
+
+        let ops: Vec<String> = self
+            .ops
+            .iter()
+            .map(|op| match op {
+                Operand::Reg(r) => r.clone(),
+                Operand::Imm(i) => format!("{:#x}", i),
+                Operand::Mem {
+                    base,
+                    index,
+                    scale,
+                    disp,
+                    size,
+                    writeback,
+                } => {
+                    let mut parts = Vec::new();
+                    if let Some(base) = base {
+                        parts.push(base.clone());
+                    }
+                    if let Some(index) = index {
+                        parts.push(format!("{}*{}", index, scale));
+                    }
+                    if *disp != 0 || parts.is_empty() {
+                        parts.push(format!("{:#x}", disp));
+                    }
+                    format!(
+                        "m{}[{}]{}",
+                        size,
+                        parts.join("+"),
+                        if *writeback { "!" } else { "" }
+                    )
+                }
+                Operand::Unknown => String::new(),
+            })
+            .collect();
+
+        format!("{} {}", self.mnemonic.to_lowercase(), ops.join(","))
+    }
 }
 
 impl fmt::Display for Instruction {
@@ -86,12 +158,15 @@ impl fmt::Display for Instruction {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Gadget {
     pub address: u64,
     pub insns: Vec<Instruction>,
     pub size: usize,  // sum() of sizeof(each_instruction)
     pub raw: Vec<u8>, // concat() of instruction.raw
+    pub addresses: Vec<u64>, // all the addresses of the equivalent gadgets, when grouped
+    #[serde(default)]
+    pub symbol: Option<SymbolOffset>, // the function containing the gadget, if known
 }
 
 impl fmt::Display for Gadget {
@@ -106,27 +181,27 @@ impl fmt::Display for Gadget {
 }
 
 impl Gadget {
-    pub fn new(insns: Vec<Instruction>) -> Self {
+    pub fn new(insns: Vec<Instruction>) -> GenericResult<Self> {
         //
-        // by nature, we should never be here if insns.len() is 0 (should at least have the
-        // ret insn) so we assert() to be notified
+        // by nature, a gadget has at least one instruction (its terminating one)
         //
-        if insns.is_empty() {
-            std::panic::panic_any("GadgetBuildError");
-        }
+        let address = match insns.first() {
+            Some(insn) => insn.address,
+            None => return Err(Error::EmptyGadget),
+        };
 
         let size = insns.iter().map(|x| x.size).sum();
 
         let raw = insns.iter().flat_map(|x| x.raw.clone()).collect();
 
-        let address = insns.first().unwrap().address;
-
-        Self {
+        Ok(Self {
             size,
             raw,
             address,
             insns,
-        }
+            addresses: vec![address],
+            symbol: None,
+        })
     }
 
     pub fn text(&self, use_color: bool) -> String {
@@ -135,176 +210,579 @@ impl Gadget {
             .map(|i| i.text(use_color).clone() + " ; ")
             .collect()
     }
+
+    pub fn normalized(&self) -> String {
+        self.insns
+            .iter()
+            .map(|i| i.normalized())
+            .collect::<Vec<String>>()
+            .join(" ; ")
+    }
+
+    ///
+    /// Identifier-friendly name of the gadget, built from its instructions (for instance
+    /// `POP_RDI_RET`)
+    ///
+    pub fn name(&self) -> String {
+        let mut name = String::new();
+        for insn in &self.insns {
+            let text = format!(
+                "{} {}",
+                insn.mnemonic,
+                insn.operands.as_deref().unwrap_or("")
+            );
+            for c in text.chars() {
+                if c.is_ascii_alphanumeric() {
+                    name.push(c.to_ascii_uppercase());
+                } else if !name.is_empty() && !name.ends_with('_') {
+                    name.push('_');
+                }
+            }
+            if !name.ends_with('_') {
+                name.push('_');
+            }
+        }
+        name.trim_end_matches('_').to_string()
+    }
+
+    pub fn key(&self, cpu: cpu::CpuType, semantic: bool) -> GadgetKey {
+        let summary = match semantic {
+            true => summarize(cpu, self),
+            false => None,
+        };
+        match summary {
+            Some(summary) => GadgetKey::Semantic(summary),
+            None => GadgetKey::Text(self.normalized()),
+        }
+    }
+
+    ///
+    /// Quality score of the gadget, the higher the better. Short gadgets without memory side
+    /// effects, few clobbered registers and a small stack delta score best; `leave`, memory
+    /// accesses through registers other than the stack pointer, indirect jumps and calls,
+    /// privileged instructions and far branches are penalized (see the `*_PENALTY` constants).
+    ///
+    pub fn score(&self, cpu: cpu::CpuType) -> i32 {
+        let mut score = MAX_SCORE;
+
+        score -= INSTRUCTION_PENALTY * (self.insns.len() as i32 - 1);
+
+        for insn in &self.insns {
+            if insn.mnemonic == "leave" {
+                score -= LEAVE_PENALTY;
+            }
+
+            if is_far_branch(cpu, insn) {
+                score -= FAR_BRANCH_PENALTY;
+            }
+
+            if insn.group == InstructionGroup::Privileged {
+                score -= PRIVILEGED_PENALTY;
+            }
+        }
+
+        if let Some(InstructionGroup::Jump | InstructionGroup::Call) =
+            self.insns.last().map(|i| i.group)
+        {
+            score -= INDIRECT_BRANCH_PENALTY;
+        }
+
+        let summary = match summarize(cpu, self) {
+            Some(summary) => summary,
+            None => return score - UNKNOWN_SEMANTICS_PENALTY,
+        };
+
+        let sp = emulator::stack_pointer(cpu);
+        let is_stack = |e: &Expr| e.registers().iter().all(|r| *r == sp);
+
+        for (addr, _, _) in &summary.writes {
+            score -= match is_stack(addr) {
+                true => STACK_WRITE_PENALTY,
+                false => MEMORY_WRITE_PENALTY,
+            };
+        }
+
+        for value in summary.registers.values() {
+            if let Expr::Load(addr, _) = value {
+                if !is_stack(addr) {
+                    score -= MEMORY_READ_PENALTY;
+                }
+            }
+        }
+
+        score -= CLOBBER_PENALTY * (summary.clobbers(cpu).len() as i32 - 1).max(0);
+
+        match summary.stack_delta(cpu) {
+            Some(delta) => {
+                let slots = delta.unsigned_abs() / emulator::ptrsize(cpu) as u64;
+                let slots = slots.min(i32::MAX as u64) as i32;
+                score = score.saturating_sub(STACK_SLOT_PENALTY.saturating_mul(slots));
+            }
+            None => score -= UNKNOWN_STACK_DELTA_PENALTY,
+        }
+
+        score
+    }
 }
 
-fn collect_previous_instructions(
-    session: &Arc<Session>,
-    group: &Vec<(Vec<u8>, Vec<u8>)>,
-    memory_chunk: &Vec<u8>,
-) -> GenericResult<Vec<(usize, usize)>> {
-    let mut res: Vec<(usize, usize)> = Vec::new();
-
-    for (opcodes, mask) in group {
-        let sz = opcodes.len();
-
-        let mut v: Vec<(usize, usize)> = memory_chunk
-            .windows(sz)
-            .enumerate()
-            .filter(|(_, y)| {
-                y.iter()
-                    .enumerate()
-                    .map(|(i, z)| z & mask[i])
-                    .cmp(opcodes.to_owned())
-                    == Ordering::Equal
-            })
-            .map(|(x, _)| (x, sz))
-            .collect();
+//
+// the score of a gadget starts at `MAX_SCORE`, and each of its defects costs a penalty
+//
+const MAX_SCORE: i32 = 100;
+/// Per instruction besides the terminating one
+const INSTRUCTION_PENALTY: i32 = 10;
+/// `leave` moves the stack pointer to the frame pointer, which is seldom controlled
+const LEAVE_PENALTY: i32 = 40;
+/// Far returns, jumps and calls also load a code segment selector
+const FAR_BRANCH_PENALTY: i32 = 50;
+/// Privileged instructions fault in user mode
+const PRIVILEGED_PENALTY: i32 = 100;
+/// Jumps and calls through a register or memory need it to be set up first
+const INDIRECT_BRANCH_PENALTY: i32 = 20;
+/// The effects of the gadget could not be summarized
+const UNKNOWN_SEMANTICS_PENALTY: i32 = 20;
+/// Per write to the stack
+const STACK_WRITE_PENALTY: i32 = 5;
+/// Per write through another register than the stack pointer, which must point to writable memory
+const MEMORY_WRITE_PENALTY: i32 = 30;
+/// Per read through another register than the stack pointer, which must point to readable memory
+const MEMORY_READ_PENALTY: i32 = 10;
+/// Per register modified, besides the first one (the one the gadget is usually looked for)
+const CLOBBER_PENALTY: i32 = 5;
+/// Per stack slot the stack pointer moves by, up or down
+const STACK_SLOT_PENALTY: i32 = 2;
+/// The stack pointer is not moved by a constant
+const UNKNOWN_STACK_DELTA_PENALTY: i32 = 40;
+
+///
+/// Whether the instruction is a far return, jump or call, i.e. also loads a code segment.
+/// The opcode is checked rather than the mnemonic, which depends on the engine (capstone writes
+/// `jmp ptr [rax]` for a far jump in Intel syntax)
+///
+fn is_far_branch(cpu: cpu::CpuType, insn: &Instruction) -> bool {
+    if !matches!(cpu, cpu::CpuType::X86 | cpu::CpuType::X64) {
+        return false;
+    }
+
+    let is_prefix = |b: &u8| match b {
+        0xf0 | 0xf2 | 0xf3 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 | 0x66 | 0x67 => true,
+        0x40..=0x4f => cpu == cpu::CpuType::X64,
+        _ => false,
+    };
+    let mut bytes = insn.raw.iter().skip_while(|b| is_prefix(b));
+
+    match (bytes.next(), bytes.next()) {
+        // retf, retf imm16, iret
+        (Some(0xca | 0xcb | 0xcf), _) => true,
+        // call ptr16:32, jmp ptr16:32
+        (Some(0x9a | 0xea), _) => true,
+        // call m16:32 (ff /3), jmp m16:32 (ff /5)
+        (Some(0xff), Some(modrm)) => matches!((modrm >> 3) & 7, 3 | 5),
+        _ => false,
+    }
+}
+
+///
+/// Key identifying a gadget independently of its address: its semantic summary when
+/// requested and available, its normalized instructions otherwise
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GadgetKey {
+    Text(String),
+    Semantic(Summary),
+}
+
+///
+/// Group the gadgets sharing the same normalized instructions, or the same semantic summary
+/// (register results, memory reads and writes, next pc) if `semantic` is set. Each group is
+/// represented by its best scoring gadget, the one with the lowest address among equals, which
+/// holds the addresses of all the members of the group.
+///
+pub fn group_gadgets(mut gadgets: Vec<Gadget>, cpu: cpu::CpuType, semantic: bool) -> Vec<Gadget> {
+    gadgets.sort_by_key(|g| g.address);
+
+    let mut groups: HashMap<GadgetKey, usize> = HashMap::new();
+    let mut res: Vec<(Gadget, i32)> = Vec::new();
+
+    for gadget in gadgets {
+        let key = gadget.key(cpu, semantic);
 
-        res.append(&mut v);
+        //
+        // the gadgets with the same text have the same score, only the semantic groups can mix
+        // gadgets of different quality
+        //
+        let score = match semantic {
+            true => gadget.score(cpu),
+            false => 0,
+        };
 
-        match session.profile_type {
-            RopProfileStrategy::Fast => {
-                break;
+        match groups.get(&key) {
+            Some(idx) => {
+                let (canonical, best) = &mut res[*idx];
+                let mut addresses = std::mem::take(&mut canonical.addresses);
+                addresses.extend(gadget.addresses.iter().copied());
+                if score > *best {
+                    *canonical = gadget;
+                    *best = score;
+                }
+                addresses.sort_unstable();
+                addresses.dedup();
+                canonical.addresses = addresses;
+            }
+            None => {
+                groups.insert(key, res.len());
+                res.push((gadget, score));
             }
-            _ => {}
         }
     }
 
-    Ok(res)
+    res.into_iter().map(|(gadget, _)| gadget).collect()
 }
 
+///
+/// Look for the terminating instructions starting in `section.data[start..end]`, and return
+/// their position (relative to the start of the section) and length. Instructions starting in
+/// the range but ending after it are included, so that consecutive ranges find exactly the same
+/// instructions as a single range covering them.
+///
 pub fn get_all_valid_positions_and_length(
     session: &Arc<Session>,
-    cpu: &Box<dyn cpu::Cpu>,
+    cpu: &dyn cpu::Cpu,
     section: &Section,
-    cursor: usize,
+    start: usize,
+    end: usize,
 ) -> GenericResult<Vec<(usize, usize)>> {
-    let data = &section.data[cursor..].to_vec();
-
-    let mut groups = Vec::new();
+    let mut patterns = Vec::new();
 
     for gadget_type in &session.gadget_types {
-        match gadget_type {
+        let mut insns = match gadget_type {
             InstructionGroup::Ret => {
                 debug!("inserting ret positions and length...");
-                groups.append(&mut cpu.ret_insns().clone());
+                cpu.ret_insns()
             }
             InstructionGroup::Call => {
                 debug!("inserting call positions and length...");
-                groups.append(&mut cpu.call_insns().clone());
+                cpu.call_insns()
             }
             InstructionGroup::Jump => {
                 debug!("inserting jump positions and length...");
-                groups.append(&mut cpu.jmp_insns().clone());
+                cpu.jmp_insns()
+            }
+            InstructionGroup::Int
+            | InstructionGroup::Iret
+            | InstructionGroup::Privileged
+            | InstructionGroup::Undefined => {
+                return Err(Error::UnsupportedGadgetType(*gadget_type));
             }
-            InstructionGroup::Int => todo!(),
-            InstructionGroup::Iret => todo!(),
-            InstructionGroup::Privileged => todo!(),
-            InstructionGroup::Undefined => todo!(),
+        };
+
+        //
+        // the fast profile only looks for the first terminating instruction of each type
+        //
+        if session.profile_type == RopProfileStrategy::Fast {
+            insns.truncate(1);
         }
+        patterns.append(&mut insns);
+    }
+
+    let matcher = PatternMatcher::new(&patterns);
+    Ok(search::find_in_range(&matcher, &section.data, start, end))
+}
+
+///
+/// Longest instruction of all the supported architectures
+///
+pub(crate) const MAX_INSN_SIZE: usize = 15;
+
+///
+/// Instructions decoded at each offset of a section. It is shared by all the terminating
+/// instructions of a chunk, so that each offset is decoded at most once even when the gadgets of
+/// close terminating instructions overlap.
+///
+#[derive(Debug, Default)]
+pub struct InstructionCache {
+    insns: BTreeMap<usize, Option<Instruction>>,
+}
+
+impl InstructionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(
+        &mut self,
+        engine: &dyn Disassembler,
+        section: &Section,
+        offset: usize,
+    ) -> Option<&Instruction> {
+        self.insns
+            .entry(offset)
+            .or_insert_with(|| {
+                let end = std::cmp::min(offset + MAX_INSN_SIZE, section.data.len());
+                let address = section.start_address + offset as u64;
+                engine.disassemble_one(&section.data[offset..end], address)
+            })
+            .as_ref()
     }
 
-    collect_previous_instructions(session, &groups, data)
+    ///
+    /// Forget the instructions before `offset`: the terminating instructions are processed in
+    /// order, so the next ones cannot reach them
+    ///
+    fn forget_before(&mut self, offset: usize) {
+        self.insns = self.insns.split_off(&offset);
+    }
+}
+
+///
+/// Backward walk from one terminating instruction, ending at `end` in the section
+///
+struct BackwardWalk<'a> {
+    session: &'a Session,
+    engine: &'a dyn Disassembler,
+    cache: &'a mut InstructionCache,
+    section: &'a Section,
+    end: usize,
+
+    //
+    // number of instructions from each offset visited to `end`, if it is a valid gadget start
+    //
+    lengths: HashMap<usize, Option<usize>>,
+}
+
+impl BackwardWalk<'_> {
+    fn length_from(&mut self, offset: usize) -> Option<usize> {
+        if let Some(length) = self.lengths.get(&offset) {
+            return *length;
+        }
+
+        let (next, group) = match self.cache.get(self.engine, self.section, offset) {
+            Some(insn) => (offset + insn.size, insn.group),
+            None => {
+                self.lengths.insert(offset, None);
+                return None;
+            }
+        };
+
+        let length = if next == self.end {
+            self.session.gadget_types.contains(&group).then_some(1)
+        } else if next > self.end
+            || matches!(
+                group,
+                InstructionGroup::Jump | InstructionGroup::Call | InstructionGroup::Ret
+            )
+        {
+            None
+        } else {
+            self.length_from(next)
+                .map(|n| n + 1)
+                .filter(|n| *n <= self.session.max_gadget_length)
+        };
+
+        self.lengths.insert(offset, length);
+        length
+    }
+
+    fn gadget_from(&mut self, start: usize) -> Option<Gadget> {
+        self.length_from(start)?;
+
+        let mut insns = Vec::new();
+        let mut offset = start;
+        while offset < self.end {
+            let insn = self.cache.get(self.engine, self.section, offset)?;
+            offset += insn.size;
+            insns.push(insn.clone());
+        }
+        Gadget::new(insns).ok()
+    }
 }
 
 ///
 /// from the section.data[pos], disassemble previous instructions
 ///
+/// Each offset before the terminating instruction is decoded once (and cached), and leads to the
+/// offset right after it: a gadget starts at each offset from which these edges fall exactly on
+/// the end of the terminating instruction, without any other branch on the way, within the
+/// limits of instructions (`max_gadget_length`) and bytes (`max_gadget_size`) of the session.
+///
 pub fn find_gadgets_from_position(
-    session: Arc<Session>,
+    session: &Session,
     engine: &dyn Disassembler,
+    cache: &mut InstructionCache,
     section: &Section,
     initial_position: usize,
     initial_len: usize,
-    cpu: &Box<dyn cpu::Cpu>,
+    cpu: &dyn cpu::Cpu,
 ) -> GenericResult<Vec<Gadget>> {
-    let max_invalid_size = match cpu.cpu_type() // todo: use session.max_gadget_length
-    {
-        cpu::CpuType::X86 => { 16 }
-        cpu::CpuType::X64 => { 16 }
-        cpu::CpuType::ARM64 => { 16 }
-        cpu::CpuType::ARM => { 16 }
-        cpu::CpuType::Unknown => panic!(),
-    };
+    let end = initial_position + initial_len;
+    if end > section.data.len() {
+        warn!(
+            "{:?}: Instruction at {:#x} overflows its section",
+            thread::current().id(),
+            initial_position
+        );
+        return Ok(Vec::new());
+    }
 
-    let start_address = section.start_address;
-    let s: usize = if initial_position < max_invalid_size {
-        0
-    } else {
-        initial_position - max_invalid_size
+    let step = std::cmp::max(cpu.insn_step(), 1);
+    let max_size = std::cmp::max(session.max_gadget_size, initial_len);
+    let lowest = end.saturating_sub(max_size);
+    cache.forget_before(lowest);
+
+    let mut walk = BackwardWalk {
+        session,
+        engine,
+        cache,
+        section,
+        end,
+        lengths: HashMap::new(),
     };
-    let data = &section.data[s..initial_position + initial_len];
-    let mut cur = Cursor::new(data);
 
     //
-    // browse the section for the largest gadget
+    // browse back from the terminating instruction, closest gadgets first
     //
-
-    let mut sz: usize = initial_len;
-    let mut nb_invalid = 0;
-    let step = cpu.insn_step();
     let mut gadgets: Vec<Gadget> = Vec::new();
+    let starts = (0..)
+        .map_while(|k: usize| initial_position.checked_sub(k * step))
+        .take_while(|start| *start >= lowest);
+
+    for start in starts {
+        if let Some(gadget) = walk.gadget_from(start) {
+            debug!(
+                "{:?}: pushing new gadget(address={:x}, sz={})",
+                thread::current().id(),
+                gadget.address,
+                gadget.raw.len()
+            );
+            gadgets.push(gadget);
+        }
+    }
 
-    loop {
-        let mut candidate: Vec<u8> = vec![0; sz];
+    Ok(gadgets)
+}
 
-        //
-        // ensure we're still within the boundaries of the cursor
-        //
-        if (sz - step) >= data.len() {
-            break;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{DisassemblyEngine, DisassemblyEngineType};
 
-        //
-        // jump to the position in the file
-        //
-        let current_position = -((sz - step) as i64);
+    fn score(cpu: &dyn cpu::Cpu, code: &[u8]) -> i32 {
+        let engine = DisassemblyEngine::new(&DisassemblyEngineType::Capstone, cpu).unwrap();
+        let gadget = Gadget::new(engine.disassembler.disassemble(code, 0x1000).unwrap()).unwrap();
+        gadget.score(cpu.cpu_type())
+    }
 
-        cur.seek(SeekFrom::End(current_position - step as i64))?;
-        if cur.read_exact(&mut candidate).is_err() {
-            warn!("{:?} Cursor reached EOF", std::thread::current().id());
-            break;
-        }
+    #[test]
+    fn semantic_groups() {
+        let x64 = cpu::x86::X64 {};
+        let engine = DisassemblyEngine::new(&DisassemblyEngineType::Capstone, &x64).unwrap();
+        let gadget = |code: &[u8], address: u64| {
+            Gadget::new(engine.disassembler.disassemble(code, address).unwrap()).unwrap()
+        };
 
-        //
-        // disassemble the code from given position
-        //
-        let addr = start_address + s as u64 + cur.position() - sz as u64;
-        let insns = engine.disassemble(&candidate, addr as u64);
+        let gadgets = vec![
+            // nop ; xor eax, eax ; ret
+            gadget(&[0x90, 0x31, 0xc0, 0xc3], 0x1000),
+            // xor eax, eax ; ret
+            gadget(&[0x31, 0xc0, 0xc3], 0x2000),
+            // mov rax, qword ptr [rbx] ; xor eax, eax ; ret
+            gadget(&[0x48, 0x8b, 0x03, 0x31, 0xc0, 0xc3], 0x3000),
+            // cmp qword ptr [rax], 0 ; ret
+            gadget(&[0x48, 0x83, 0x38, 0x00, 0xc3], 0x4000),
+            // ret
+            gadget(&[0xc3], 0x5000),
+        ];
+        let groups = group_gadgets(gadgets, cpu::CpuType::X64, true);
 
         //
-        // transform the Vec<Instruction> into a valid gadget
+        // the gadgets reading memory are kept apart, and the best gadget represents its group
         //
-        match insns {
-            Some(x) => {
-                nb_invalid = 0;
-                if !x.is_empty() {
-                    let last_insn = x.last().unwrap();
-                    if session.gadget_types.contains(&last_insn.group) {
-                        let gadget = Gadget::new(x);
-                        if gadgets.iter().all(|x| x.address != gadget.address) {
-                            debug!(
-                                "{:?}: pushing new gadget(address={:x}, sz={})",
-                                thread::current().id(),
-                                gadget.address,
-                                gadget.raw.len()
-                            );
-                            gadgets.push(gadget);
-                        }
-                    }
-                }
-            }
+        let addresses: Vec<(u64, Vec<u64>)> = groups
+            .iter()
+            .map(|g| (g.address, g.addresses.clone()))
+            .collect();
+        assert_eq!(
+            addresses,
+            vec![
+                (0x2000, vec![0x1000, 0x2000]),
+                (0x3000, vec![0x3000]),
+                (0x4000, vec![0x4000]),
+                (0x5000, vec![0x5000]),
+            ]
+        );
+    }
 
-            None => {
-                nb_invalid += 1;
-                if nb_invalid == max_invalid_size {
-                    break;
-                }
-            }
+    #[test]
+    fn pop_ret_ranks_first() {
+        let x64 = cpu::x86::X64 {};
+
+        // pop rdi ; ret
+        let pop_ret = score(&x64, &[0x5f, 0xc3]);
+        assert_eq!(
+            pop_ret,
+            MAX_SCORE - INSTRUCTION_PENALTY - 2 * STACK_SLOT_PENALTY
+        );
+
+        for worse in [
+            // leave ; ret
+            &[0xc9, 0xc3][..],
+            // mov qword ptr [rax], rdi ; ret
+            &[0x48, 0x89, 0x38, 0xc3],
+            // pop rdi ; mov rax, qword ptr [rbx] ; ret
+            &[0x5f, 0x48, 0x8b, 0x03, 0xc3],
+            // jmp rax
+            &[0xff, 0xe0],
+            // call rax
+            &[0xff, 0xd0],
+            // pop rdi ; retf
+            &[0x5f, 0xcb],
+            // jmp far [rax]
+            &[0xff, 0x28],
+            // call far [rax]
+            &[0xff, 0x18],
+        ] {
+            assert!(score(&x64, worse) < pop_ret, "{:02x?}", worse);
         }
+    }
+
+    #[test]
+    fn far_branches() {
+        let x64 = cpu::x86::X64 {};
+        let engine = DisassemblyEngine::new(&DisassemblyEngineType::Capstone, &x64).unwrap();
+        let far = |code: &[u8]| {
+            let insns = engine.disassembler.disassemble(code, 0x1000).unwrap();
+            is_far_branch(cpu::CpuType::X64, &insns[0])
+        };
 
-        sz += step;
+        assert!(far(&[0xcb]));
+        assert!(far(&[0xff, 0x28]));
+        assert!(far(&[0x48, 0xff, 0x18]));
+        assert!(!far(&[0xc3]));
+        assert!(!far(&[0xff, 0x20]));
+        assert!(!far(&[0xff, 0x10]));
+        assert!(!far(&[0xff, 0xe0]));
     }
 
-    Ok(gadgets)
+    #[test]
+    fn x86_stack_deltas() {
+        let x86 = cpu::x86::X86 {};
+
+        // pop edi ; ret
+        assert_eq!(
+            score(&x86, &[0x5f, 0xc3]),
+            score(&cpu::x86::X64 {}, &[0x5f, 0xc3])
+        );
+
+        // sub esp, 0x10 ; ret: the stack moves down by 3 slots
+        assert_eq!(
+            score(&x86, &[0x83, 0xec, 0x10, 0xc3]),
+            MAX_SCORE - INSTRUCTION_PENALTY - 3 * STACK_SLOT_PENALTY
+        );
+
+        // add esp, 0x10 ; ret: up by 5
+        assert_eq!(
+            score(&x86, &[0x83, 0xc4, 0x10, 0xc3]),
+            MAX_SCORE - INSTRUCTION_PENALTY - 5 * STACK_SLOT_PENALTY
+        );
+    }
 }
@@ -1,22 +1,32 @@
 #[macro_use]
 extern crate bitflags;
 
-use colored::Colorize;
 use gadget::Gadget;
-use std::fs;
-use std::io::Write as _;
 use std::sync::Arc;
 
 use log::{debug, error, info, warn};
 
+pub mod cache;
 pub mod common;
 pub mod cpu;
+pub mod diff;
+pub mod emulator;
 pub mod engine;
 pub mod error;
 pub mod format;
 pub mod gadget;
+pub mod matcher;
+pub mod multi;
+pub mod output;
+pub mod render;
+pub mod search;
 pub mod section;
+pub mod semantics;
 pub mod session;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stream;
+pub mod symbols;
 
 use crate::common::GenericResult;
 use crate::session::Session;
@@ -24,13 +34,17 @@ use crate::session::Session;
 pub fn collect_all_gadgets(sess: Session) -> GenericResult<Vec<Gadget>> {
     let info = &sess.info;
     let start_timestamp = std::time::Instant::now();
-    let sections = info.format.sections();
-    
-    let use_color = sess.use_color;
+    let sections: Vec<_> = info
+        .format
+        .sections()
+        .iter()
+        .filter(|s| s.is_executable())
+        .collect();
+
     let unique_only = sess.unique_only;
-    let chosen_output_format = sess.output.clone();
-    let entrypoint_address = info.format.entry_point();
-    let is_64b = info.is_64b();
+    let merge_equivalent = sess.merge_equivalent;
+    let order = sess.order;
+    let cpu_type = info.cpu.cpu_type();
 
     info!(
         "Looking for gadgets in {} executable section(s) (with {} threads)...'",
@@ -42,91 +56,87 @@ pub fn collect_all_gadgets(sess: Session) -> GenericResult<Vec<Gadget>> {
     // use an arc for the session to share between threads
     //
     let arc = Arc::new(sess);
-    match session::find_gadgets(arc.clone()) {
-        Ok(_) => {
-            dbg!("Done collecting gadgets");
+
+    //
+    // try the cache first, if any. cache failures are not fatal, the scan is simply done again
+    //
+    let cache = arc.cache.clone().map(cache::GadgetCache::new);
+    let cache_params = match cache {
+        Some(_) => cache::CacheParameters::new(&arc)
+            .map_err(|e| warn!("Cannot use the cache: {:?}", e))
+            .ok(),
+        None => None,
+    };
+    let cached = match (&cache, &cache_params) {
+        (Some(cache), Some(params)) => cache.load(params).unwrap_or_else(|e| {
+            warn!("Failed to load the cache: {:?}", e);
+            None
+        }),
+        _ => None,
+    };
+
+    match cached {
+        Some(gadgets) => {
+            *arc.gadgets.lock().unwrap() = gadgets;
         }
-        Err(e) => {
-            error!("An error occured while collecting gadgets: {:?}", e);
-            return Err(e);
+        None => {
+            match session::find_gadgets(arc.clone()) {
+                Ok(_) => {
+                    dbg!("Done collecting gadgets");
+                }
+                Err(e) => {
+                    error!("An error occured while collecting gadgets: {:?}", e);
+                    return Err(e);
+                }
+            }
+
+            if let (Some(cache), Some(params)) = (cache, cache_params) {
+                let gadgets = arc.gadgets.lock().unwrap();
+                if let Err(e) = cache.store(params, &gadgets) {
+                    warn!("Failed to store the gadgets in the cache: {:?}", e);
+                }
+            }
         }
     }
 
     let mut gadgets = arc.gadgets.lock().unwrap();
 
     //
-    // if unique, filter out doublons
+    // if unique, group the doublons
     //
     let total_gadgets_found: usize = gadgets.len();
-    if unique_only {
+    if unique_only || merge_equivalent {
         debug!(
-            "Filtering {} gadgets for deplicates ...",
-            total_gadgets_found
+            "Grouping {} gadgets (semantic={}) ...",
+            total_gadgets_found, merge_equivalent
         );
-        gadgets.sort_by_key(|a| a.text(false));
-        gadgets.dedup_by(|a, b| a.text(false).eq_ignore_ascii_case(b.text(false).as_str()));
+        *gadgets = gadget::group_gadgets(std::mem::take(&mut *gadgets), cpu_type, merge_equivalent);
         info!(
-            "{} duplicate gadgets removed",
+            "{} duplicate gadgets grouped",
             total_gadgets_found - gadgets.len()
         );
     }
 
     //
-    // sort by address
+    // sort by address, or by score (then address) if requested
     //
-    gadgets.sort_by(|a, b| a.address.cmp(&b.address));
+    match order {
+        session::RopGadgetOrder::Score => {
+            gadgets.sort_by_cached_key(|a| (std::cmp::Reverse(a.score(cpu_type)), a.address))
+        }
+        _ => gadgets.sort_by_key(|a| a.address),
+    }
 
     //
     // Write to given output
     //
-    match chosen_output_format {
-        session::RopGadgetOutput::None => {
-            warn!("No output specified");
+    match output::writer_for(&arc)? {
+        Some(mut writer) => {
+            info!("Dumping {} gadgets...", gadgets.len());
+            output::write_all(writer.as_mut(), &arc.info, gadgets.iter())?;
         }
-
-        session::RopGadgetOutput::Console => {
-            info!("Dumping {} gadgets to stdout...", gadgets.len());
-
-            for g in &*gadgets {
-                let addr = match is_64b {
-                    true => {
-                        format!("0x{:016x}", g.address)
-                    }
-                    _ => {
-                        format!("0x{:08x}", g.address)
-                    }
-                };
-
-                if use_color {
-                    println!("{} | {}", addr.red(), g.text(use_color));
-                } else {
-                    println!("{} | {}", addr, g.text(use_color));
-                }
-            }
-        }
-
-        session::RopGadgetOutput::File(filename) => {
-            dbg!(
-                "Dumping {} gadgets to '{}'...",
-                gadgets.len(),
-                filename.to_str().unwrap()
-            );
-
-            if use_color {
-                warn!("Disabling colors when writing to file");
-            }
-
-            let mut file = fs::File::create(&filename)?;
-            for gadget in &*gadgets {
-                let addr = entrypoint_address + gadget.address;
-                file.write_all((format!("{:#x} | {}\n", addr, gadget.text(false))).as_bytes())?;
-            }
-
-            info!(
-                "Written {} gadgets to '{}'",
-                gadgets.len(),
-                filename.to_str().unwrap()
-            );
+        None => {
+            warn!("No output specified");
         }
     }
 
@@ -143,7 +153,45 @@ pub fn collect_all_gadgets(sess: Session) -> GenericResult<Vec<Gadget>> {
         );
     }
 
-    Ok(gadgets.clone())
+    Ok(std::mem::take(&mut *gadgets))
+}
+
+///
+/// Stream the gadgets of the session as they are found, without grouping nor sorting them
+///
+pub fn stream_gadgets(sess: Session) -> stream::GadgetStream {
+    stream::GadgetStream::new(Arc::new(sess))
+}
+
+///
+/// Find the gadgets and write them to the output of the session. When the session requires no
+/// grouping, sorting nor caching, the gadgets are streamed to the output in constant memory;
+/// otherwise they are all collected first. Returns the number of gadgets written.
+///
+pub fn write_all_gadgets(sess: Session) -> GenericResult<usize> {
+    if sess.needs_all_gadgets() {
+        return collect_all_gadgets(sess).map(|gadgets| gadgets.len());
+    }
+
+    let mut writer = match output::writer_for(&sess)? {
+        Some(writer) => writer,
+        None => {
+            warn!("No output specified");
+            return Ok(0);
+        }
+    };
+
+    let mut stream = stream_gadgets(sess);
+    let session = Arc::clone(&stream.session);
+    writer.begin(&session.info)?;
+    for gadget in stream.by_ref() {
+        writer.write(&session.info, &gadget)?;
+    }
+    writer.end(&session.info)?;
+
+    let count = stream.finish()?;
+    info!("Streamed {} gadgets", count);
+    Ok(count)
 }
 
 #[cfg(test)]
@@ -153,13 +201,132 @@ mod tests {
 
     fn run_basic_test(sz: &str, arch: &str, fmt: &str) -> Vec<Gadget> {
         let input_fname = PathBuf::from(format!("tests/bin/{}-{}.{}", sz, arch, fmt));
-        let s = Session::new(input_fname).output(RopGadgetOutput::None);
+        let s = Session::new(input_fname)
+            .unwrap()
+            .output(RopGadgetOutput::None);
         match collect_all_gadgets(s) {
             Ok(gadgets) => gadgets,
             Err(e) => panic!("{:?}", e),
         }
     }
 
+    #[test]
+    fn unique_keeps_all_addresses() {
+        let input_fname = PathBuf::from("tests/bin/small-x64.elf");
+        let mut all: Vec<u64> = collect_all_gadgets(
+            Session::new(input_fname.clone())
+                .unwrap()
+                .unique_only(false),
+        )
+        .unwrap()
+        .iter()
+        .map(|g| g.address)
+        .collect();
+        all.sort_unstable();
+        all.dedup();
+
+        for semantic in [false, true] {
+            let s = Session::new(input_fname.clone())
+                .unwrap()
+                .merge_equivalent(semantic);
+            let grouped = collect_all_gadgets(s).unwrap();
+            assert!(grouped.len() < all.len());
+
+            let mut addresses: Vec<u64> =
+                grouped.iter().flat_map(|g| g.addresses.clone()).collect();
+            addresses.sort_unstable();
+            assert_eq!(addresses, all);
+        }
+    }
+
+    #[test]
+    fn sort_by_score() {
+        use crate::session::RopGadgetOrder;
+
+        let fname = PathBuf::from("tests/bin/small-x86.elf");
+        let sess = Session::new(fname).unwrap().order(RopGadgetOrder::Score);
+        let gadgets = collect_all_gadgets(sess).unwrap();
+        let cpu = crate::cpu::CpuType::X86;
+
+        //
+        // best score first, then lowest address
+        //
+        let keys: Vec<(i32, u64)> = gadgets.iter().map(|g| (-g.score(cpu), g.address)).collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        assert!(gadgets[0].score(cpu) > gadgets.last().unwrap().score(cpu));
+    }
+
+    #[test]
+    fn only_executable_sections_are_scanned() {
+        use crate::format::SectionFilter;
+
+        for fname in ["small-x64.elf", "small-x86.pe", "small-x64.macho"] {
+            let fname = PathBuf::from("tests/bin").join(fname);
+            let executable = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+            let sess = Session::load(fname, None, SectionFilter::Mapped).unwrap();
+            assert!(sess
+                .info
+                .format
+                .sections()
+                .iter()
+                .any(|s| !s.is_executable()));
+            let mapped = collect_all_gadgets(sess).unwrap();
+            assert_eq!(executable, mapped);
+        }
+    }
+
+    #[test]
+    fn gadgets_are_annotated_with_their_function() {
+        for (fname, pattern) in [("small-x64.elf", "_obstack_*"), ("big-x64.pe", "Rtl*Heap*")] {
+            let fname = PathBuf::from("tests/bin").join(fname);
+            let format = crate::format::guess_file_format(&fname, Default::default()).unwrap();
+            let all = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+            assert!(all.iter().any(|g| g.symbol.is_some()));
+            for gadget in &all {
+                assert_eq!(gadget.symbol, format.symbols().lookup(gadget.address));
+                if let Some(symbol) = &gadget.symbol {
+                    let start = gadget.address - symbol.offset;
+                    let function = format.symbols().iter().find(|s| s.address == start);
+                    assert_eq!(function.map(|f| &f.name), Some(&symbol.name));
+                }
+            }
+
+            let sess = Session::new(fname).unwrap().symbol_filter(pattern);
+            let filtered = collect_all_gadgets(sess).unwrap();
+            assert!(!filtered.is_empty() && filtered.len() < all.len());
+            let prefix = pattern.split('*').next().unwrap();
+            assert!(filtered.iter().all(|g| g
+                .symbol
+                .as_ref()
+                .is_some_and(|s| s.name.starts_with(prefix))));
+        }
+
+        //
+        // the mach-o test files are stripped: no gadget is in a known function
+        //
+        let fname = PathBuf::from("tests/bin/small-x64.macho");
+        let sess = Session::new(fname).unwrap().symbol_filter("*");
+        assert!(collect_all_gadgets(sess).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sections_are_read_at_their_file_offset() {
+        for fname in [
+            "small-x64.elf",
+            "small-x64.pe",
+            "small-x64.macho",
+            "small-arm64.elf",
+        ] {
+            let fname = PathBuf::from("tests/bin").join(fname);
+            let content = std::fs::read(&fname).unwrap();
+            let sess = Session::new(fname).unwrap();
+            for section in sess.info.format.sections() {
+                let start = section.file_offset as usize;
+                assert_eq!(&*section.data, &content[start..start + section.data.len()]);
+            }
+        }
+    }
+
     mod pe {
         use super::super::*;
         const FMT: &str = "pe";
@@ -168,7 +335,7 @@ mod tests {
         fn x86() {
             for sz in ["small", "big"] {
                 let res = tests::run_basic_test(sz, "x86", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
             }
         }
 
@@ -176,22 +343,23 @@ mod tests {
         fn x64() {
             for sz in ["small", "big"] {
                 let res = tests::run_basic_test(sz, "x64", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
+            }
+        }
+
+        #[test]
+        fn arm32() {
+            for sz in ["small", "big"] {
+                let res = tests::run_basic_test(sz, "arm32", FMT);
+                assert!(!res.is_empty());
             }
         }
 
-        // #[test]
-        // fn arm32() {
-        //     for sz in ["small", "big"] {
-        //         let res = tests::run_basic_test(sz, "arm32", FMT);
-        //         assert!(res.len() > 0);
-        //     }
-        // }
         #[test]
         fn arm64() {
             for sz in ["small", "big"] {
                 let res = tests::run_basic_test(sz, "arm64", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
             }
         }
     }
@@ -204,7 +372,7 @@ mod tests {
         fn x86() {
             for sz in ["small", "big"] {
                 let res = tests::run_basic_test(sz, "x86", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
             }
         }
 
@@ -212,22 +380,23 @@ mod tests {
         fn x64() {
             for sz in ["small", "big"] {
                 let res = tests::run_basic_test(sz, "x64", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
+            }
+        }
+
+        #[test]
+        fn arm32() {
+            for sz in ["big", "small"] {
+                let res = tests::run_basic_test(sz, "arm32", FMT);
+                assert!(!res.is_empty());
             }
         }
 
-        // #[test]
-        // fn arm32() {
-        //     for sz in ["big", "small"] {
-        //         let res = tests::run_basic_test(sz, "arm32", FMT);
-        //         assert!(res.len() > 0);
-        //     }
-        // }
         #[test]
         fn arm64() {
             for sz in ["small", "big"] {
                 let res = tests::run_basic_test(sz, "arm64", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
             }
         }
     }
@@ -238,17 +407,17 @@ mod tests {
 
         #[test]
         fn x86() {
-            for sz in vec!["small"] {
+            for sz in ["small"] {
                 let res = tests::run_basic_test(sz, "x86", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
             }
         }
 
         #[test]
         fn x64() {
-            for sz in vec!["small"] {
+            for sz in ["small"] {
                 let res = tests::run_basic_test(sz, "x64", FMT);
-                assert!(res.len() > 0);
+                assert!(!res.is_empty());
             }
         }
     }
@@ -0,0 +1,212 @@
+use memchr::{memchr2_iter, memchr3_iter, memchr_iter};
+
+///
+/// A byte pattern: each byte of the haystack is masked before being compared to the pattern,
+/// i.e. it matches at `pos` if `haystack[pos + i] & mask[i] == bytes[i]` for every `i`
+///
+pub type BytePattern = (Vec<u8>, Vec<u8>);
+
+///
+/// Matcher looking for several masked byte patterns in a single pass over a buffer.
+///
+/// Each pattern is anchored on one of its bytes, preferably one which cannot be 0x00 or 0xff and
+/// then a fully masked one, and the haystack is searched for the anchor values only, with one
+/// vectorized memchr pass per group of (at most) 3 values. The whole pattern is then checked at
+/// each candidate position.
+///
+#[derive(Debug, Clone)]
+pub struct PatternMatcher {
+    patterns: Vec<BytePattern>,
+
+    //
+    // for each byte value, the patterns anchored on it, and the offset of the anchor in them
+    //
+    anchors: Vec<Vec<(usize, usize)>>,
+    needles: Vec<u8>,
+}
+
+impl PatternMatcher {
+    pub fn new(patterns: &[BytePattern]) -> Self {
+        let mut anchors = vec![Vec::new(); 256];
+
+        for (idx, (bytes, mask)) in patterns.iter().enumerate() {
+            if bytes.is_empty() || bytes.len() != mask.len() {
+                continue;
+            }
+
+            let offset = Self::anchor_of(bytes, mask);
+            for value in 0..=255u8 {
+                if value & mask[offset] == bytes[offset] {
+                    anchors[value as usize].push((idx, offset));
+                }
+            }
+        }
+
+        let needles = (0..=255u8)
+            .filter(|value| !anchors[*value as usize].is_empty())
+            .collect();
+
+        Self {
+            patterns: patterns.to_vec(),
+            anchors,
+            needles,
+        }
+    }
+
+    ///
+    /// Pick the byte of the pattern to search for: one which cannot be 0x00 or 0xff, which are
+    /// very common in executable sections, and then the most constrained one
+    ///
+    fn anchor_of(bytes: &[u8], mask: &[u8]) -> usize {
+        let rank = |i: usize| {
+            //
+            // 0x00 matches when the expected bits are all clear, 0xff when they are all set
+            //
+            let common = bytes[i] == 0x00 || bytes[i] == mask[i];
+            (!common, mask[i].count_ones())
+        };
+
+        (0..bytes.len()).rev().max_by_key(|&i| rank(i)).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.needles.is_empty()
+    }
+
+    ///
+    /// Size of the longest pattern
+    ///
+    pub fn max_len(&self) -> usize {
+        self.patterns
+            .iter()
+            .map(|(bytes, _)| bytes.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn matches_at(&self, haystack: &[u8], idx: usize, start: usize) -> bool {
+        let (bytes, mask) = &self.patterns[idx];
+        match haystack.get(start..start + bytes.len()) {
+            Some(window) => window
+                .iter()
+                .zip(mask.iter().zip(bytes))
+                .all(|(b, (m, expected))| b & m == *expected),
+            None => false,
+        }
+    }
+
+    ///
+    /// Return the position and length of all the matches in the haystack, ordered by position
+    /// then by the order of the patterns
+    ///
+    pub fn find_all(&self, haystack: &[u8]) -> Vec<(usize, usize)> {
+        let mut matches: Vec<(usize, usize)> = Vec::new();
+
+        let mut check = |pos: usize| {
+            for &(idx, offset) in &self.anchors[haystack[pos] as usize] {
+                if let Some(start) = pos.checked_sub(offset) {
+                    if self.matches_at(haystack, idx, start) {
+                        matches.push((start, idx));
+                    }
+                }
+            }
+        };
+
+        //
+        // each value is in a single group, so each position is checked at most once
+        //
+        for group in self.needles.chunks(3) {
+            match *group {
+                [a] => memchr_iter(a, haystack).for_each(&mut check),
+                [a, b] => memchr2_iter(a, b, haystack).for_each(&mut check),
+                [a, b, c] => memchr3_iter(a, b, c, haystack).for_each(&mut check),
+                _ => unreachable!(),
+            }
+        }
+
+        matches.sort_unstable();
+        matches
+            .into_iter()
+            .map(|(start, idx)| (start, self.patterns[idx].0.len()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{self, Cpu, CpuType};
+
+    fn naive_find_all(patterns: &[BytePattern], haystack: &[u8]) -> Vec<(usize, usize)> {
+        let mut res = Vec::new();
+        for pos in 0..haystack.len() {
+            for (bytes, mask) in patterns {
+                let window = match haystack.get(pos..pos + bytes.len()) {
+                    Some(window) => window,
+                    None => continue,
+                };
+                if window
+                    .iter()
+                    .enumerate()
+                    .all(|(i, b)| b & mask[i] == bytes[i])
+                {
+                    res.push((pos, bytes.len()));
+                }
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn same_matches_as_naive_search() {
+        let content = std::fs::read("tests/bin/small-x64.pe").unwrap();
+        let cpus: [Box<dyn Cpu>; 3] = [
+            Box::new(cpu::x86::X64 {}),
+            Box::new(cpu::x86::X86 {}),
+            Box::new(cpu::arm::Arm64 {}),
+        ];
+
+        for cpu in cpus {
+            let mut patterns = cpu.ret_insns();
+            patterns.extend(cpu.call_insns());
+            patterns.extend(cpu.jmp_insns());
+
+            //
+            // one pattern (a single pass) and all the patterns (several passes)
+            //
+            for n in [1, patterns.len()] {
+                let matcher = PatternMatcher::new(&patterns[..n]);
+                let expected = naive_find_all(&patterns[..n], &content);
+                assert_eq!(matcher.find_all(&content), expected);
+            }
+            if cpu.cpu_type() != CpuType::ARM64 {
+                assert!(PatternMatcher::new(&patterns).needles.len() > 3);
+            }
+        }
+    }
+
+    #[test]
+    fn anchors_avoid_common_bytes() {
+        // jmp reg (ff e?): the 0xff opcode is fully masked, but very common
+        assert_eq!(PatternMatcher::anchor_of(&[0xff, 0xe0], &[0xff, 0xf8]), 1);
+        // ret imm16 (c2 ?? ??)
+        assert_eq!(PatternMatcher::anchor_of(&[0xc2, 0, 0], &[0xff, 0, 0]), 0);
+        // a masked byte which may be 0x00 is avoided too
+        assert_eq!(PatternMatcher::anchor_of(&[0x00, 0x48], &[0xf0, 0xff]), 1);
+    }
+
+    #[test]
+    fn match_on_the_edges() {
+        let matcher = PatternMatcher::new(&[
+            (vec![0x41, 0xff, 0xe0], vec![0xff, 0xff, 0xf8]),
+            (vec![0xc3], vec![0xff]),
+        ]);
+        assert_eq!(
+            matcher.find_all(&[0x41, 0xff, 0xe3, 0x90, 0xc3]),
+            vec![(0, 3), (4, 1)]
+        );
+        assert_eq!(matcher.find_all(&[0x90, 0x41, 0xff]), vec![]);
+        assert_eq!(matcher.max_len(), 3);
+        assert!(PatternMatcher::new(&[]).is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use log::{debug, info};
+
+use crate::common::GenericResult;
+use crate::error::Error;
+use crate::gadget::Gadget;
+use crate::session::{self, Session};
+
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
+pub enum MultiFileMode {
+    /// Only the gadgets found at the same RVA with the same bytes in all the files
+    #[default]
+    Intersection,
+    /// All the gadgets of all the files, with the files they were found in
+    Union,
+}
+
+///
+/// A gadget found at the same RVA with the same bytes in one or more files
+///
+#[derive(Debug, Clone)]
+pub struct SharedGadget {
+    pub rva: u64,
+    pub gadget: Gadget,
+    /// Indexes (in `MultiSession::sessions`) of the files where the gadget was found
+    pub files: Vec<usize>,
+}
+
+///
+/// A set of sessions, one per executable, to look for gadgets common to all of them
+///
+#[derive(Debug, Default)]
+pub struct MultiSession {
+    pub sessions: Vec<Session>,
+    pub mode: MultiFileMode,
+}
+
+impl MultiSession {
+    pub fn new(filepaths: Vec<PathBuf>) -> GenericResult<Self> {
+        Ok(Self {
+            sessions: filepaths
+                .into_iter()
+                .map(Session::new)
+                .collect::<GenericResult<_>>()?,
+            ..Default::default()
+        })
+    }
+
+    pub fn session(mut self, session: Session) -> Self {
+        self.sessions.push(session);
+        self
+    }
+
+    pub fn mode(self, mode: MultiFileMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub fn nb_thread(self, nb_thread: u8) -> Self {
+        Self {
+            sessions: self
+                .sessions
+                .into_iter()
+                .map(|s| s.nb_thread(nb_thread))
+                .collect(),
+            ..self
+        }
+    }
+
+    pub fn filepaths(&self) -> Vec<&PathBuf> {
+        self.sessions.iter().map(|s| s.filepath()).collect()
+    }
+}
+
+///
+/// Collect the gadgets of each file of the session, and merge them by RVA and raw bytes. All
+/// the files must be of the same architecture.
+///
+/// Files are processed one after the other on purpose: each scan already spreads the chunks of
+/// its file over all the threads of the pool (see `session::scan_gadgets`), so only the tail of
+/// each file leaves threads idle, and the gadgets of one file at most are held before merging.
+///
+pub fn collect_shared_gadgets(multi: MultiSession) -> GenericResult<Vec<SharedGadget>> {
+    let nb_files = multi.sessions.len();
+    let mode = multi.mode;
+
+    if let Some((first, others)) = multi.sessions.split_first() {
+        let cpu = first.info.cpu.cpu_type();
+        if let Some(other) = others.iter().find(|s| s.info.cpu.cpu_type() != cpu) {
+            return Err(Error::ArchitectureMismatch {
+                old: (first.filepath().clone(), cpu),
+                new: (other.filepath().clone(), other.info.cpu.cpu_type()),
+            });
+        }
+    }
+    let mut shared: BTreeMap<(u64, Vec<u8>), SharedGadget> = BTreeMap::new();
+
+    for (index, sess) in multi.sessions.into_iter().enumerate() {
+        //
+        // the session is kept around after the scan, as its format is needed for the RVAs
+        //
+        let arc = Arc::new(sess);
+        session::find_gadgets(arc.clone())?;
+
+        let gadgets = std::mem::take(&mut *arc.gadgets.lock().unwrap());
+        debug!(
+            "{} gadget(s) found in '{}'",
+            gadgets.len(),
+            arc.filepath().display()
+        );
+
+        for gadget in gadgets {
+            let rva = arc.info.format.rva(gadget.address);
+            let entry = shared
+                .entry((rva, gadget.raw.clone()))
+                .or_insert_with(|| SharedGadget {
+                    rva,
+                    gadget,
+                    files: Vec::new(),
+                });
+
+            if entry.files.last() != Some(&index) {
+                entry.files.push(index);
+            }
+        }
+    }
+
+    let mut res: Vec<SharedGadget> = shared.into_values().collect();
+    if mode == MultiFileMode::Intersection {
+        res.retain(|g| g.files.len() == nb_files);
+    }
+
+    info!(
+        "{} gadget(s) found in {:?} mode over {} file(s)",
+        res.len(),
+        mode,
+        nb_files
+    );
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CpuType;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+
+    #[test]
+    fn intersection_with_itself() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let union = collect_shared_gadgets(
+            MultiSession::new(vec![fname.clone(), fname.clone()])
+                .unwrap()
+                .mode(MultiFileMode::Union),
+        )
+        .unwrap();
+        let inter =
+            collect_shared_gadgets(MultiSession::new(vec![fname.clone(), fname]).unwrap()).unwrap();
+
+        assert!(!inter.is_empty());
+        assert_eq!(union.len(), inter.len());
+        assert!(inter.iter().all(|g| g.files == vec![0, 1]));
+    }
+
+    ///
+    /// The (RVA, bytes) of all the gadgets of a file, without any grouping
+    ///
+    fn keys_of(fname: &Path) -> BTreeSet<(u64, Vec<u8>)> {
+        let sess = Arc::new(Session::new(fname.to_path_buf()).unwrap());
+        session::find_gadgets(sess.clone()).unwrap();
+        let gadgets = sess.gadgets.lock().unwrap();
+        gadgets
+            .iter()
+            .map(|g| (sess.info.format.rva(g.address), g.raw.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn different_architectures() {
+        let files = vec![
+            PathBuf::from("tests/bin/small-x64.pe"),
+            PathBuf::from("tests/bin/small-x64.elf"),
+            PathBuf::from("tests/bin/small-x86.pe"),
+        ];
+        let res = collect_shared_gadgets(MultiSession::new(files).unwrap());
+        assert!(matches!(
+            res,
+            Err(Error::ArchitectureMismatch {
+                old: (_, CpuType::X64),
+                new: (_, CpuType::X86)
+            })
+        ));
+    }
+
+    #[test]
+    fn union_keeps_provenance() {
+        let files = vec![
+            PathBuf::from("tests/bin/small-x64.pe"),
+            PathBuf::from("tests/bin/big-x64.pe"),
+        ];
+        let union = collect_shared_gadgets(
+            MultiSession::new(files.clone())
+                .unwrap()
+                .mode(MultiFileMode::Union),
+        )
+        .unwrap();
+        let inter = collect_shared_gadgets(MultiSession::new(files.clone()).unwrap()).unwrap();
+
+        //
+        // the intersection is a strict subset of the union
+        //
+        let key = |g: &SharedGadget| (g.rva, g.gadget.raw.clone());
+        let union_keys: BTreeSet<_> = union.iter().map(key).collect();
+        let inter_keys: BTreeSet<_> = inter.iter().map(key).collect();
+        assert!(!inter_keys.is_empty());
+        assert!(inter_keys.is_subset(&union_keys));
+        assert!(inter_keys.len() < union_keys.len());
+        for g in &inter {
+            assert_eq!(g.files, vec![0, 1]);
+            assert_eq!(g.rva, g.gadget.address);
+        }
+
+        //
+        // every gadget of the union comes from the files, and only from the files, it was found in
+        //
+        let per_file: Vec<_> = files.iter().map(|f| keys_of(f)).collect();
+        for g in &union {
+            let expected: Vec<usize> = (0..files.len())
+                .filter(|&i| per_file[i].contains(&key(g)))
+                .collect();
+            assert_eq!(g.files, expected);
+        }
+        assert!(union.iter().any(|g| g.files == vec![0]));
+        assert!(union.iter().any(|g| g.files == vec![1]));
+    }
+}
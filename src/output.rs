@@ -0,0 +1,928 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use colored::Colorize;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::common::GenericResult;
+use crate::gadget::{Gadget, GadgetKey, Instruction, Operand};
+use crate::render::Renderer;
+use crate::session::{ExecutableDetails, RopGadgetOutput, Session};
+use crate::symbols::SymbolOffset;
+
+///
+/// Metadata of the executable the gadgets were found in
+///
+#[derive(Debug, Serialize)]
+pub struct FileRecord {
+    pub path: String,
+    pub format: String,
+    pub cpu: String,
+    pub ptrsize: usize,
+    pub entry_point: u64,
+    pub image_base: u64,
+}
+
+impl From<&ExecutableDetails> for FileRecord {
+    fn from(info: &ExecutableDetails) -> Self {
+        Self {
+            path: info.filepath.display().to_string(),
+            format: format!("{:?}", info.format.format()),
+            cpu: format!("{:?}", info.cpu.cpu_type()),
+            ptrsize: info.cpu.ptrsize(),
+            entry_point: info.format.entry_point(),
+            image_base: info.format.image_base(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstructionRecord<'a> {
+    pub address: u64,
+    pub raw: String,
+    pub mnemonic: &'a str,
+    pub operands: Option<&'a str>,
+    pub ops: &'a [Operand],
+}
+
+impl<'a> From<&'a Instruction> for InstructionRecord<'a> {
+    fn from(insn: &'a Instruction) -> Self {
+        Self {
+            address: insn.address,
+            raw: to_hex(&insn.raw),
+            mnemonic: &insn.mnemonic,
+            operands: insn.operands.as_deref(),
+            ops: &insn.ops,
+        }
+    }
+}
+
+///
+/// Structured view of a gadget, as written by the JSON and NDJSON outputs
+///
+#[derive(Debug, Serialize)]
+pub struct GadgetRecord<'a> {
+    pub address: u64,
+    pub rva: u64,
+    pub file_offset: Option<u64>,
+    pub section: Option<&'a str>,
+    pub raw: String,
+    pub terminator: String,
+    pub text: String,
+    pub addresses: &'a [u64],
+    pub symbol: Option<&'a SymbolOffset>,
+    pub instructions: Vec<InstructionRecord<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<&'a FileRecord>,
+}
+
+impl<'a> GadgetRecord<'a> {
+    pub fn new(info: &'a ExecutableDetails, gadget: &'a Gadget) -> Self {
+        let section = info
+            .format
+            .sections()
+            .iter()
+            .find(|s| s.contains(gadget.address));
+
+        Self {
+            address: gadget.address,
+            rva: info.format.rva(gadget.address),
+            file_offset: section.map(|s| s.file_offset_of(gadget.address)),
+            section: section.and_then(|s| s.name.as_deref()),
+            raw: to_hex(&gadget.raw),
+            terminator: gadget
+                .insns
+                .last()
+                .map(|i| i.group.to_string())
+                .unwrap_or_default(),
+            text: gadget.text(false),
+            addresses: &gadget.addresses,
+            symbol: gadget.symbol.as_ref(),
+            instructions: gadget.insns.iter().map(InstructionRecord::from).collect(),
+            file: None,
+        }
+    }
+
+    ///
+    /// Replace the canonical text of the gadget, e.g. by its rendering
+    ///
+    pub fn text(self, text: String) -> Self {
+        Self { text, ..self }
+    }
+
+    pub fn file(self, file: &'a FileRecord) -> Self {
+        Self {
+            file: Some(file),
+            ..self
+        }
+    }
+}
+
+///
+/// Function of a gadget in the comments of the Python module, e.g. ` in main+0x5a`
+///
+fn in_symbol(symbol: &Option<SymbolOffset>) -> String {
+    match symbol {
+        Some(symbol) => format!(" in {}", symbol),
+        None => String::new(),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+///
+/// Output backend, fed with the gadgets one at a time so that it can work in constant memory
+/// when the format allows it
+///
+pub trait GadgetWriter {
+    fn begin(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()>;
+
+    fn end(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        Ok(())
+    }
+}
+
+///
+/// Feed all the gadgets to the writer, and return how many were written
+///
+pub fn write_all<'a, I>(
+    writer: &mut dyn GadgetWriter,
+    info: &ExecutableDetails,
+    gadgets: I,
+) -> GenericResult<usize>
+where
+    I: IntoIterator<Item = &'a Gadget>,
+{
+    let mut count = 0;
+    writer.begin(info)?;
+    for gadget in gadgets {
+        writer.write(info, gadget)?;
+        count += 1;
+    }
+    writer.end(info)?;
+    Ok(count)
+}
+
+///
+/// Buffered writer to the given file, or to stdout if none
+///
+fn open_output(filename: Option<&PathBuf>) -> GenericResult<Box<dyn Write>> {
+    let writer: Box<dyn Write> = match filename {
+        Some(filename) => {
+            info!("Writing gadgets to '{}'", filename.display());
+            Box::new(BufWriter::new(fs::File::create(filename)?))
+        }
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    Ok(writer)
+}
+
+///
+/// Build the writer for the output of the session, if any
+///
+pub fn writer_for(session: &Session) -> GenericResult<Option<Box<dyn GadgetWriter>>> {
+    if matches!(session.output, RopGadgetOutput::None) {
+        return Ok(None);
+    }
+
+    let renderer = Renderer::new(session.render_options, session.info.cpu.cpu_type())?;
+    let writer: Box<dyn GadgetWriter> = match &session.output {
+        RopGadgetOutput::None => unreachable!(),
+        RopGadgetOutput::Console => {
+            Box::new(TextWriter::console(open_output(None)?, session.use_color).renderer(renderer))
+        }
+        RopGadgetOutput::File(filename) => {
+            if session.use_color {
+                warn!("Disabling colors when writing to file");
+            }
+            Box::new(TextWriter::file(open_output(Some(filename))?).renderer(renderer))
+        }
+        RopGadgetOutput::Json(filename) => {
+            Box::new(JsonWriter::new(open_output(filename.as_ref())?).renderer(renderer))
+        }
+        RopGadgetOutput::Ndjson(filename) => {
+            Box::new(NdjsonWriter::new(open_output(filename.as_ref())?).renderer(renderer))
+        }
+        RopGadgetOutput::Python(filename) => {
+            Box::new(PythonWriter::new(open_output(filename.as_ref())?).renderer(renderer))
+        }
+        RopGadgetOutput::RopGadget(filename) => {
+            Box::new(RopGadgetWriter::new(open_output(filename.as_ref())?).renderer(renderer))
+        }
+        RopGadgetOutput::RpPlusPlus(filename) => {
+            Box::new(RppWriter::new(open_output(filename.as_ref())?).renderer(renderer))
+        }
+        #[cfg(feature = "sqlite")]
+        RopGadgetOutput::Sqlite(filename) => {
+            Box::new(crate::sqlite::SqliteWriter::new(filename.clone()))
+        }
+    };
+    Ok(Some(writer))
+}
+
+///
+/// `address | gadget` lines, to the console (with colors) or to a file
+///
+pub struct TextWriter<W: Write> {
+    writer: W,
+    use_color: bool,
+    to_file: bool,
+    renderer: Renderer,
+}
+
+impl<W: Write> TextWriter<W> {
+    pub fn console(writer: W, use_color: bool) -> Self {
+        Self {
+            writer,
+            use_color,
+            to_file: false,
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn file(writer: W) -> Self {
+        Self {
+            writer,
+            use_color: false,
+            to_file: true,
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn renderer(self, renderer: Renderer) -> Self {
+        Self { renderer, ..self }
+    }
+}
+
+impl<W: Write> GadgetWriter for TextWriter<W> {
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        //
+        // the function containing the gadget, if known, follows its address: `0x1234 <main+0x5a>`
+        //
+        let symbol = match &gadget.symbol {
+            Some(symbol) => format!(" <{}>", symbol),
+            None => String::new(),
+        };
+
+        if self.to_file {
+            let addr = info.format.entry_point() + gadget.address;
+            writeln!(
+                self.writer,
+                "{:#x}{} | {}",
+                addr,
+                symbol,
+                self.renderer.gadget(gadget, false)
+            )?;
+            return Ok(());
+        }
+
+        let addr = match info.is_64b() {
+            true => format!("0x{:016x}", gadget.address),
+            false => format!("0x{:08x}", gadget.address),
+        };
+
+        if self.use_color {
+            let text = self.renderer.gadget(gadget, true);
+            writeln!(self.writer, "{}{} | {}", addr.red(), symbol.yellow(), text)?;
+        } else {
+            let text = self.renderer.gadget(gadget, false);
+            writeln!(self.writer, "{}{} | {}", addr, symbol, text)?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// A single JSON document, with the file metadata at the top
+///
+pub struct JsonWriter<W: Write> {
+    writer: W,
+    first: bool,
+    renderer: Renderer,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            first: true,
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn renderer(self, renderer: Renderer) -> Self {
+        Self { renderer, ..self }
+    }
+}
+
+impl<W: Write> GadgetWriter for JsonWriter<W> {
+    fn begin(&mut self, info: &ExecutableDetails) -> GenericResult<()> {
+        self.writer.write_all(b"{\"file\":")?;
+        serde_json::to_writer(&mut self.writer, &FileRecord::from(info))?;
+        self.writer.write_all(b",\"gadgets\":[")?;
+        Ok(())
+    }
+
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        if !self.first {
+            self.writer.write_all(b",")?;
+        }
+        self.first = false;
+        let record = GadgetRecord::new(info, gadget).text(self.renderer.gadget(gadget, false));
+        serde_json::to_writer(&mut self.writer, &record)?;
+        Ok(())
+    }
+
+    fn end(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        self.writer.write_all(b"]}\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// One JSON object per line and per gadget, each carrying the file metadata
+///
+pub struct NdjsonWriter<W: Write> {
+    writer: W,
+    file: Option<FileRecord>,
+    renderer: Renderer,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            file: None,
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn renderer(self, renderer: Renderer) -> Self {
+        Self { renderer, ..self }
+    }
+}
+
+impl<W: Write> GadgetWriter for NdjsonWriter<W> {
+    fn begin(&mut self, info: &ExecutableDetails) -> GenericResult<()> {
+        self.file = Some(FileRecord::from(info));
+        Ok(())
+    }
+
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        let mut record = GadgetRecord::new(info, gadget).text(self.renderer.gadget(gadget, false));
+        record.file = self.file.as_ref();
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn end(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct Candidate {
+    score: i32,
+    rva: u64,
+    name: String,
+    text: String,
+    symbol: Option<SymbolOffset>,
+}
+
+///
+/// A Python module defining one constant per primitive, e.g. `POP_RDI_RET = base + 0x1234`.
+/// Gadgets are grouped by their semantic summary and only the best scoring one of each group is
+/// named, the alternatives being listed in comments. Values are RVAs, so the script only needs to
+/// call `rebase()` once the actual image base is known. The module is written at the end, as it
+/// needs all the gadgets of each primitive.
+///
+pub struct PythonWriter<W: Write> {
+    writer: W,
+    primitives: HashMap<GadgetKey, Vec<Candidate>>,
+    renderer: Renderer,
+}
+
+impl<W: Write> PythonWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            primitives: HashMap::new(),
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn renderer(self, renderer: Renderer) -> Self {
+        Self { renderer, ..self }
+    }
+}
+
+impl<W: Write> GadgetWriter for PythonWriter<W> {
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        let cpu = info.cpu.cpu_type();
+        let score = gadget.score(cpu);
+        let name = gadget.name();
+        let text = self.renderer.gadget(gadget, false).trim_end().to_string();
+        let entry = self.primitives.entry(gadget.key(cpu, true)).or_default();
+        for address in &gadget.addresses {
+            entry.push(Candidate {
+                score,
+                rva: info.format.rva(*address),
+                name: name.clone(),
+                text: text.clone(),
+                symbol: info.format.symbols().lookup(*address),
+            });
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, info: &ExecutableDetails) -> GenericResult<()> {
+        //
+        // best score first (then lowest address), in each group and across the groups, so that
+        // the groups sharing a name are always suffixed the same way
+        //
+        let mut groups: Vec<Vec<Candidate>> = self.primitives.drain().map(|(_, c)| c).collect();
+        for candidates in &mut groups {
+            candidates.sort_by_key(|c| (std::cmp::Reverse(c.score), c.rva));
+        }
+        groups.sort_by(|a, b| {
+            let (a, b) = (&a[0], &b[0]);
+            (std::cmp::Reverse(a.score), a.rva, &a.text).cmp(&(
+                std::cmp::Reverse(b.score),
+                b.rva,
+                &b.text,
+            ))
+        });
+
+        let mut named: BTreeMap<String, Vec<Candidate>> = BTreeMap::new();
+        for candidates in groups {
+            let base_name = candidates[0].name.clone();
+            let mut name = base_name.clone();
+            let mut suffix = 1;
+            while named.contains_key(&name) {
+                suffix += 1;
+                name = format!("{}_{}", base_name, suffix);
+            }
+            named.insert(name, candidates);
+        }
+
+        let writer = &mut self.writer;
+        writeln!(writer, "# Generated by ropgadget-rs")?;
+        writeln!(writer, "# File: {}", info.filepath.display())?;
+        writeln!(
+            writer,
+            "# {:?} {:?}, image base {:#x}",
+            info.format.format(),
+            info.cpu.cpu_type(),
+            info.format.image_base()
+        )?;
+        writeln!(writer, "#")?;
+        writeln!(
+            writer,
+            "# Gadgets are relative to the image base, use `rebase(base)` once it is known."
+        )?;
+        writeln!(writer)?;
+        writeln!(writer, "base = 0")?;
+        writeln!(writer)?;
+
+        for (name, candidates) in &named {
+            let best = &candidates[0];
+            writeln!(writer, "# {}{}", best.text, in_symbol(&best.symbol))?;
+            for alt in &candidates[1..] {
+                writeln!(
+                    writer,
+                    "#   alt: {:#x}  {}  (score {}){}",
+                    alt.rva,
+                    alt.text,
+                    alt.score,
+                    in_symbol(&alt.symbol)
+                )?;
+            }
+            writeln!(writer, "{} = base + {:#x}", name, best.rva)?;
+        }
+
+        writeln!(writer)?;
+        writeln!(writer, "GADGETS = {{")?;
+        for (name, candidates) in &named {
+            writeln!(writer, "    \"{}\": {:#x},", name, candidates[0].rva)?;
+        }
+        writeln!(writer, "}}")?;
+        writeln!(writer)?;
+        writeln!(writer)?;
+        writeln!(writer, "def rebase(new_base):")?;
+        writeln!(
+            writer,
+            "    \"\"\"Set the image base, and update the gadget addresses accordingly.\"\"\""
+        )?;
+        writeln!(writer, "    global base")?;
+        writeln!(writer, "    base = new_base")?;
+        writeln!(writer, "    for name, rva in GADGETS.items():")?;
+        writeln!(writer, "        globals()[name] = base + rva")?;
+        writeln!(
+            writer,
+            "    return {{name: base + rva for name, rva in GADGETS.items()}}"
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// The format of ROPgadget, e.g. `0x0000000000401234 : pop rdi ; ret`
+///
+pub struct RopGadgetWriter<W: Write> {
+    writer: W,
+    count: usize,
+    renderer: Renderer,
+}
+
+impl<W: Write> RopGadgetWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            count: 0,
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn renderer(self, renderer: Renderer) -> Self {
+        Self { renderer, ..self }
+    }
+}
+
+impl<W: Write> GadgetWriter for RopGadgetWriter<W> {
+    fn begin(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        writeln!(self.writer, "Gadgets information")?;
+        writeln!(self.writer, "{}", "=".repeat(60))?;
+        Ok(())
+    }
+
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        let text: Vec<String> = gadget
+            .insns
+            .iter()
+            .map(|i| self.renderer.instruction(i, false).trim_end().to_string())
+            .collect();
+        writeln!(
+            self.writer,
+            "0x{:0width$x} : {}",
+            info.format.va(gadget.address),
+            text.join(" ; "),
+            width = 2 * info.cpu.ptrsize()
+        )?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn end(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        writeln!(self.writer)?;
+        writeln!(self.writer, "Unique gadgets found: {}", self.count)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// The format of rp++, e.g. `0x401234: pop rdi ; ret  ;  (1 found)`. Gadgets with the same text
+/// are written once, at their lowest address, with the number of addresses they were found at;
+/// so they are only written at the end. The gadgets are grouped by their canonical text, so the
+/// groups do not depend on the rendering (e.g. the raw bytes).
+///
+/// Like rp++, every instruction is written as `<mnemonic> <operands> ; `, the space being kept
+/// when there are no operands, whatever the disassembly engine.
+///
+pub struct RppWriter<W: Write> {
+    writer: W,
+    groups: Vec<(u64, String, usize)>,
+    indexes: HashMap<String, usize>,
+    renderer: Renderer,
+}
+
+impl<W: Write> RppWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            groups: Vec::new(),
+            indexes: HashMap::new(),
+            renderer: Renderer::default(),
+        }
+    }
+
+    pub fn renderer(self, renderer: Renderer) -> Self {
+        Self { renderer, ..self }
+    }
+
+    fn line(&self, gadget: &Gadget) -> String {
+        let mut line = String::new();
+        for insn in &gadget.insns {
+            let (mnemonic, operands) = self.renderer.mnemonic_and_operands(insn);
+            line += &format!("{} {}", mnemonic, operands.unwrap_or_default());
+            if let Some(raw) = self.renderer.raw_bytes(insn) {
+                line += &format!(" {}", raw);
+            }
+            line += " ; ";
+        }
+        line
+    }
+}
+
+impl<W: Write> GadgetWriter for RppWriter<W> {
+    fn write(&mut self, _info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        let text = gadget.text(false);
+        match self.indexes.get(&text) {
+            Some(idx) => {
+                let group = &mut self.groups[*idx];
+                group.0 = group.0.min(gadget.address);
+                group.2 += gadget.addresses.len();
+            }
+            None => {
+                self.indexes.insert(text, self.groups.len());
+                let line = self.line(gadget);
+                self.groups
+                    .push((gadget.address, line, gadget.addresses.len()));
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, info: &ExecutableDetails) -> GenericResult<()> {
+        for (address, text, count) in &self.groups {
+            let va = info.format.va(*address);
+            writeln!(self.writer, "{:#x}: {} ({} found)", va, text, count)?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+///
+/// Write all the gadgets as one JSON document
+///
+pub fn write_json<W: Write>(
+    writer: &mut W,
+    info: &ExecutableDetails,
+    gadgets: &[Gadget],
+) -> GenericResult<()> {
+    write_all(&mut JsonWriter::new(writer), info, gadgets)?;
+    Ok(())
+}
+
+///
+/// Write one JSON object per line and per gadget
+///
+pub fn write_ndjson<W: Write>(
+    writer: &mut W,
+    info: &ExecutableDetails,
+    gadgets: &[Gadget],
+) -> GenericResult<()> {
+    write_all(&mut NdjsonWriter::new(writer), info, gadgets)?;
+    Ok(())
+}
+
+///
+/// Write a Python module with the best gadget of each primitive
+///
+pub fn write_python<W: Write>(
+    writer: &mut W,
+    info: &ExecutableDetails,
+    gadgets: &[Gadget],
+) -> GenericResult<()> {
+    write_all(&mut PythonWriter::new(writer), info, gadgets)?;
+    Ok(())
+}
+
+///
+/// Write the gadgets the way ROPgadget does
+///
+pub fn write_ropgadget<W: Write>(
+    writer: &mut W,
+    info: &ExecutableDetails,
+    gadgets: &[Gadget],
+) -> GenericResult<()> {
+    write_all(&mut RopGadgetWriter::new(writer), info, gadgets)?;
+    Ok(())
+}
+
+///
+/// Write the gadgets the way rp++ does
+///
+pub fn write_rpp<W: Write>(
+    writer: &mut W,
+    info: &ExecutableDetails,
+    gadgets: &[Gadget],
+) -> GenericResult<()> {
+    write_all(&mut RppWriter::new(writer), info, gadgets)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+    use crate::{collect_all_gadgets, session::RopGadgetOutput};
+    use std::path::PathBuf;
+
+    #[test]
+    fn ndjson_one_gadget_per_line() {
+        let fname = PathBuf::from("tests/bin/small-x64.elf");
+        let gadgets = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+        let sess = Session::new(fname).unwrap().output(RopGadgetOutput::None);
+
+        let mut buf = Vec::new();
+        write_ndjson(&mut buf, &sess.info, &gadgets).unwrap();
+
+        let lines: Vec<&str> = std::str::from_utf8(&buf).unwrap().lines().collect();
+        assert_eq!(lines.len(), gadgets.len());
+
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["address"], gadgets[0].address);
+        assert!(first["section"].is_string());
+        assert_eq!(first["file"]["format"], "Elf");
+        assert_eq!(first["terminator"], "Ret");
+        let insns = first["instructions"].as_array().unwrap();
+        assert_eq!(insns.len(), gadgets[0].insns.len());
+
+        //
+        // the function of the gadget, or null
+        //
+        for (line, gadget) in lines.iter().zip(&gadgets) {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            match &gadget.symbol {
+                Some(symbol) => {
+                    assert_eq!(record["symbol"]["name"], symbol.name.as_str());
+                    assert_eq!(record["symbol"]["offset"], symbol.offset);
+                }
+                None => assert!(record["symbol"].is_null()),
+            }
+        }
+        assert!(gadgets.iter().any(|g| g.symbol.is_some()));
+    }
+
+    #[test]
+    fn python_module_is_stable() {
+        let fname = PathBuf::from("tests/bin/small-x64.elf");
+        let mut gadgets = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+        let sess = Session::new(fname).unwrap();
+
+        let mut first = Vec::new();
+        write_python(&mut first, &sess.info, &gadgets).unwrap();
+        gadgets.reverse();
+        let mut second = Vec::new();
+        write_python(&mut second, &sess.info, &gadgets).unwrap();
+
+        let first = String::from_utf8(first).unwrap();
+        assert!(first.contains("_2 = base + 0x"));
+        assert_eq!(first, String::from_utf8(second).unwrap());
+    }
+
+    #[test]
+    fn python_module() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let gadgets = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+        let sess = Session::new(fname).unwrap();
+
+        let mut buf = Vec::new();
+        write_python(&mut buf, &sess.info, &gadgets).unwrap();
+        let script = String::from_utf8(buf).unwrap();
+
+        assert!(script.contains("\nRET = base + 0x"));
+        assert!(script.contains("def rebase(new_base):"));
+
+        //
+        // each primitive is only defined once
+        //
+        let definitions: Vec<&str> = script
+            .lines()
+            .filter(|l| l.contains(" = base + "))
+            .map(|l| l.split(' ').next().unwrap())
+            .collect();
+        let mut unique = definitions.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(definitions.len(), unique.len());
+    }
+
+    #[test]
+    fn ropgadget_and_rpp_formats() {
+        let fname = PathBuf::from("tests/bin/small-x86.pe");
+        let gadgets =
+            collect_all_gadgets(Session::new(fname.clone()).unwrap().unique_only(false)).unwrap();
+        let sess = Session::new(fname).unwrap();
+
+        let mut buf = Vec::new();
+        write_ropgadget(&mut buf, &sess.info, &gadgets).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|l| l.contains(" : ")).collect();
+        assert_eq!(lines.len(), gadgets.len());
+        assert!(lines
+            .iter()
+            .all(|l| l.starts_with("0x") && l.find(" : ") == Some(10) && !l.ends_with(' ')));
+        assert!(text.ends_with(&format!("Unique gadgets found: {}\n", gadgets.len())));
+
+        //
+        // like ROPgadget and rp++, the PE gadgets are written at their VA (image base + RVA)
+        //
+        assert_eq!(sess.info.format.image_base(), 0x400000);
+        let known = "0x00404626 : stosd dword ptr es:[edi], eax ; daa ; add dword ptr [eax], eax ; pop ecx ; ret";
+        assert!(lines.contains(&known));
+
+        let mut buf = Vec::new();
+        write_rpp(&mut buf, &sess.info, &gadgets).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains(": ret  ;  ("));
+        let known = "0x404626: stosd dword ptr es:[edi], eax ; daa  ; add dword ptr [eax], eax ; pop ecx ; ret  ;  (1 found)";
+        assert!(text.lines().any(|l| l == known));
+
+        //
+        // the layout does not depend on how the engine writes the missing operands
+        //
+        let mut gadget = gadgets.iter().find(|g| g.insns.len() == 1).unwrap().clone();
+        gadget.insns[0].operands = None;
+        let mut buf = Vec::new();
+        write_rpp(&mut buf, &sess.info, &[gadget]).unwrap();
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .ends_with(": ret  ;  (1 found)\n"));
+
+        let total: usize = text
+            .lines()
+            .map(|l| {
+                let count = l.rsplit('(').next().unwrap();
+                count.trim_end_matches(" found)").parse::<usize>().unwrap()
+            })
+            .sum();
+        assert_eq!(total, gadgets.len());
+        assert!(text.lines().count() < gadgets.len());
+    }
+
+    #[test]
+    fn json_document() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let gadgets = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+        let sess = Session::new(fname).unwrap();
+
+        let mut buf = Vec::new();
+        write_json(&mut buf, &sess.info, &gadgets).unwrap();
+
+        let doc: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(doc["file"]["cpu"], "X64");
+        let all = doc["gadgets"].as_array().unwrap();
+        assert_eq!(all.len(), gadgets.len());
+        assert_eq!(all[0]["rva"], gadgets[0].address);
+        assert!(all[0].get("file").is_none());
+
+        let raw = all[0]["raw"].as_str().unwrap();
+        assert_eq!(raw.len(), gadgets[0].raw.len() * 2);
+    }
+
+    #[test]
+    fn rendering_does_not_change_the_groups() {
+        use crate::render::{RenderOptions, Syntax};
+
+        let fname = PathBuf::from("tests/bin/small-x64.elf");
+        let gadgets =
+            collect_all_gadgets(Session::new(fname.clone()).unwrap().unique_only(false)).unwrap();
+        let sess = Session::new(fname).unwrap();
+        let options = RenderOptions::default().syntax(Syntax::Att).raw_bytes(true);
+
+        let mut canonical = Vec::new();
+        write_rpp(&mut canonical, &sess.info, &gadgets).unwrap();
+        let mut rendered = Vec::new();
+        let renderer = Renderer::new(options, sess.info.cpu.cpu_type()).unwrap();
+        write_all(
+            &mut RppWriter::new(&mut rendered).renderer(renderer),
+            &sess.info,
+            &gadgets,
+        )
+        .unwrap();
+
+        let canonical = String::from_utf8(canonical).unwrap();
+        let rendered = String::from_utf8(rendered).unwrap();
+        assert_eq!(canonical.lines().count(), rendered.lines().count());
+        assert!(rendered.contains(": retq  (c3) ;  ("));
+
+        //
+        // the instructions of the records stay canonical
+        //
+        let renderer = Renderer::new(options, sess.info.cpu.cpu_type()).unwrap();
+        let record =
+            GadgetRecord::new(&sess.info, &gadgets[0]).text(renderer.gadget(&gadgets[0], false));
+        assert!(record.text.contains("retq  (c3)"));
+        assert_eq!(record.instructions.last().unwrap().mnemonic, "ret");
+    }
+}
@@ -0,0 +1,364 @@
+use capstone::prelude::*;
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::common::GenericResult;
+use crate::cpu::CpuType;
+use crate::error::Error;
+use crate::gadget::{Gadget, Instruction, InstructionGroup};
+
+/**
+ *
+ * Rendering of the gadgets in the outputs. The instructions are always decoded (and compared,
+ * grouped, emulated...) in their canonical form, i.e. Intel syntax in lowercase with hexadecimal
+ * numbers; only their text is rendered differently, when written out.
+ *
+ */
+
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum Syntax {
+    #[default]
+    Intel,
+    /// AT&T, for x86 and x64 only
+    Att,
+    /// MASM, for x86 and x64 only
+    Masm,
+}
+
+impl std::fmt::Display for Syntax {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let val = match self {
+            Syntax::Intel => "Intel",
+            Syntax::Att => "AT&T",
+            Syntax::Masm => "MASM",
+        };
+
+        write!(f, "Syntax={}", val)
+    }
+}
+
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum NumberBase {
+    #[default]
+    Hex,
+    Decimal,
+}
+
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum LetterCase {
+    #[default]
+    Lower,
+    Upper,
+}
+
+///
+/// How the instructions of the gadgets are written
+///
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct RenderOptions {
+    pub syntax: Syntax,
+
+    /// The base of the immediate values (the branch targets and displacements are left as is)
+    pub immediates: NumberBase,
+
+    pub case: LetterCase,
+
+    /// Show the bytes of each instruction after it
+    pub raw_bytes: bool,
+}
+
+impl RenderOptions {
+    pub fn syntax(self, syntax: Syntax) -> Self {
+        Self { syntax, ..self }
+    }
+
+    pub fn immediates(self, immediates: NumberBase) -> Self {
+        Self { immediates, ..self }
+    }
+
+    pub fn case(self, case: LetterCase) -> Self {
+        Self { case, ..self }
+    }
+
+    pub fn raw_bytes(self, raw_bytes: bool) -> Self {
+        Self { raw_bytes, ..self }
+    }
+}
+
+///
+/// Write the instructions according to the rendering options. The other syntaxes than Intel are
+/// obtained by disassembling the instructions again, so a renderer is meant to be used only on
+/// the gadgets written out.
+///
+#[derive(Default)]
+pub struct Renderer {
+    options: RenderOptions,
+    cs: Option<Capstone>,
+}
+
+impl Renderer {
+    pub fn new(options: RenderOptions, cpu_type: CpuType) -> GenericResult<Self> {
+        let syntax = match options.syntax {
+            Syntax::Intel => {
+                return Ok(Self { options, cs: None });
+            }
+            Syntax::Att => arch::x86::ArchSyntax::Att,
+            Syntax::Masm => arch::x86::ArchSyntax::Masm,
+        };
+
+        let mode = match cpu_type {
+            CpuType::X86 => arch::x86::ArchMode::Mode32,
+            CpuType::X64 => arch::x86::ArchMode::Mode64,
+            _ => {
+                return Err(Error::UnsupportedSyntax {
+                    syntax: options.syntax,
+                    cpu: cpu_type,
+                })
+            }
+        };
+
+        let cs = Capstone::new()
+            .x86()
+            .mode(mode)
+            .syntax(syntax)
+            .build()
+            .map_err(|e| Error::EngineError {
+                engine: String::from("Capstone"),
+                cpu: cpu_type,
+                reason: e.to_string(),
+            })?;
+
+        Ok(Self {
+            options,
+            cs: Some(cs),
+        })
+    }
+
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    ///
+    /// Mnemonic and operands of the instruction in the syntax of the renderer, or in the
+    /// canonical one if the instruction cannot be disassembled again
+    ///
+    fn syntax_of(&self, insn: &Instruction) -> (String, Option<String>) {
+        let canonical = || (insn.mnemonic.clone(), insn.operands.clone());
+
+        let cs = match &self.cs {
+            Some(cs) => cs,
+            None => return canonical(),
+        };
+
+        let cs_insns = match cs.disasm_count(&insn.raw, insn.address, 1) {
+            Ok(cs_insns) => cs_insns,
+            Err(_) => return canonical(),
+        };
+
+        match cs_insns.iter().next() {
+            Some(cs_insn) => match cs_insn.mnemonic() {
+                Some(mnemonic) => (
+                    mnemonic.to_string(),
+                    cs_insn.op_str().map(|op| op.to_string()),
+                ),
+                None => canonical(),
+            },
+            None => canonical(),
+        }
+    }
+
+    ///
+    /// Mnemonic and operands of the instruction, with the syntax, immediates and case applied
+    ///
+    pub fn mnemonic_and_operands(&self, insn: &Instruction) -> (String, Option<String>) {
+        let (mut mnemonic, mut operands) = self.syntax_of(insn);
+
+        let is_branch = matches!(
+            insn.group,
+            InstructionGroup::Jump | InstructionGroup::Call | InstructionGroup::Ret
+        );
+        if self.options.immediates == NumberBase::Decimal && !is_branch {
+            operands = operands.map(|op| decimal_immediates(&op, self.options.syntax));
+        }
+
+        if self.options.case == LetterCase::Upper {
+            mnemonic = mnemonic.to_uppercase();
+            operands = operands.map(|op| op.to_uppercase().replace("0X", "0x"));
+        }
+
+        (mnemonic, operands)
+    }
+
+    ///
+    /// The bytes of the instruction, e.g. `(5f)`, if they are to be written
+    ///
+    pub fn raw_bytes(&self, insn: &Instruction) -> Option<String> {
+        if !self.options.raw_bytes {
+            return None;
+        }
+
+        let raw: Vec<String> = insn.raw.iter().map(|b| format!("{:02x}", b)).collect();
+        Some(format!("({})", raw.join(" ")))
+    }
+
+    pub fn instruction(&self, insn: &Instruction, use_color: bool) -> String {
+        let (mnemonic, operands) = self.mnemonic_and_operands(insn);
+
+        let mut text = match (use_color, operands) {
+            (true, Some(op)) => format!("{} {}", mnemonic.cyan(), op.bold()),
+            (true, None) => format!("{}", mnemonic.cyan()),
+            (false, Some(op)) => format!("{} {}", mnemonic, op),
+            (false, None) => mnemonic,
+        };
+
+        if let Some(raw) = self.raw_bytes(insn) {
+            match use_color {
+                true => text = format!("{} {}", text, raw.dimmed()),
+                false => text = format!("{} {}", text, raw),
+            }
+        }
+
+        text
+    }
+
+    ///
+    /// Same layout as `Gadget::text()`, which it matches with the default options
+    ///
+    pub fn gadget(&self, gadget: &Gadget, use_color: bool) -> String {
+        gadget
+            .insns
+            .iter()
+            .map(|i| self.instruction(i, use_color) + " ; ")
+            .collect()
+    }
+}
+
+///
+/// Parse a hexadecimal number as written by capstone: `0x1f` or, in MASM syntax, `1fh`
+///
+fn parse_hex(token: &str) -> Option<u64> {
+    let digits = match token.strip_prefix("0x") {
+        Some(digits) => digits,
+        None => token.strip_suffix('h')?,
+    };
+    u64::from_str_radix(digits, 16).ok()
+}
+
+///
+/// Write the immediates of the operands in decimal. The numbers inside memory operands (between
+/// brackets, or not prefixed by `$` in AT&T syntax) are left as is.
+///
+fn decimal_immediates(operands: &str, syntax: Syntax) -> String {
+    let mut res = String::with_capacity(operands.len());
+    let mut depth = 0;
+    let mut chars = operands.char_indices().peekable();
+    let mut prev: Option<char> = None;
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ => {}
+        }
+
+        let is_immediate = match syntax {
+            Syntax::Att => prev == Some('$') || (prev == Some('-') && res.ends_with("$-")),
+            Syntax::Intel | Syntax::Masm => {
+                depth == 0 && !prev.is_some_and(|p| p.is_ascii_alphanumeric() || p == '_')
+            }
+        };
+
+        if c.is_ascii_digit() && is_immediate {
+            let mut end = idx + 1;
+            while let Some(&(next, n)) = chars.peek() {
+                if !n.is_ascii_alphanumeric() {
+                    break;
+                }
+                end = next + n.len_utf8();
+                chars.next();
+            }
+
+            let token = &operands[idx..end];
+            match parse_hex(token) {
+                Some(value) => res.push_str(&value.to_string()),
+                None => res.push_str(token),
+            }
+            prev = token.chars().last();
+            continue;
+        }
+
+        res.push(c);
+        prev = Some(c);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+    use crate::engine::{DisassemblyEngine, DisassemblyEngineType};
+
+    fn render(code: &[u8], options: RenderOptions) -> String {
+        let cpu = cpu::x86::X64 {};
+        let engine = DisassemblyEngine::new(&DisassemblyEngineType::Capstone, &cpu).unwrap();
+        let gadget = Gadget::new(engine.disassembler.disassemble(code, 0x1000).unwrap()).unwrap();
+        let renderer = Renderer::new(options, CpuType::X64).unwrap();
+        renderer.gadget(&gadget, false)
+    }
+
+    #[test]
+    fn syntaxes_and_options() {
+        // add rax, 0x20 ; mov rcx, qword ptr [rsp + 0x18] ; ret
+        let code = [0x48, 0x83, 0xc0, 0x20, 0x48, 0x8b, 0x4c, 0x24, 0x18, 0xc3];
+        let options = RenderOptions::default();
+
+        assert_eq!(
+            render(&code, options),
+            "add rax, 0x20 ; mov rcx, qword ptr [rsp + 0x18] ; ret  ; "
+        );
+        assert_eq!(
+            render(&code, options.syntax(Syntax::Att)),
+            "addq $0x20, %rax ; movq 0x18(%rsp), %rcx ; retq  ; "
+        );
+        assert_eq!(
+            render(&code, options.syntax(Syntax::Masm)),
+            "add rax, 20h ; mov rcx, qword ptr [rsp + 18h] ; ret  ; "
+        );
+        assert_eq!(
+            render(&code, options.immediates(NumberBase::Decimal)),
+            "add rax, 32 ; mov rcx, qword ptr [rsp + 0x18] ; ret  ; "
+        );
+        assert_eq!(
+            render(
+                &code,
+                options
+                    .syntax(Syntax::Att)
+                    .immediates(NumberBase::Decimal)
+                    .case(LetterCase::Upper)
+            ),
+            "ADDQ $32, %RAX ; MOVQ 0x18(%RSP), %RCX ; RETQ  ; "
+        );
+        assert_eq!(
+            render(&code[4..], options.raw_bytes(true)),
+            "mov rcx, qword ptr [rsp + 0x18] (48 8b 4c 24 18) ; ret  (c3) ; "
+        );
+    }
+
+    #[test]
+    fn decimal_immediates_only() {
+        assert_eq!(
+            decimal_immediates("dword ptr [rax - 0x10], -0x30", Syntax::Intel),
+            "dword ptr [rax - 0x10], -48"
+        );
+        assert_eq!(
+            decimal_immediates("$-0x30, 0x10(%rax)", Syntax::Att),
+            "$-48, 0x10(%rax)"
+        );
+        assert_eq!(decimal_immediates("r8, 0ffh", Syntax::Masm), "r8, 255");
+        assert!(
+            Renderer::new(RenderOptions::default().syntax(Syntax::Att), CpuType::ARM64).is_err()
+        );
+    }
+}
@@ -0,0 +1,672 @@
+use clap::ValueEnum;
+use log::debug;
+
+use crate::common::GenericResult;
+use crate::engine::{Disassembler, DisassemblyEngine};
+use crate::error::Error;
+use crate::gadget::{Instruction, MAX_INSN_SIZE};
+use crate::matcher::{BytePattern, PatternMatcher};
+use crate::section::{Permission, Section};
+use crate::session::Session;
+use crate::symbols::SymbolOffset;
+
+///
+/// Minimum number of instructions disassembled after a match, to preview it
+///
+const PREVIEW_LENGTH: usize = 3;
+
+///
+/// A match of a search in an executable section
+///
+#[derive(Debug, Clone)]
+pub struct SearchMatch {
+    pub address: u64,
+    pub section: Option<String>,
+    /// The bytes matched
+    pub raw: Vec<u8>,
+    /// The instructions disassembled from the address of the match, which cover it unless an
+    /// invalid instruction was met
+    pub preview: Vec<Instruction>,
+    /// The function containing the match, if known
+    pub symbol: Option<SymbolOffset>,
+}
+
+impl SearchMatch {
+    ///
+    /// The preview, written like the gadgets
+    ///
+    pub fn preview_text(&self, use_color: bool) -> String {
+        self.preview
+            .iter()
+            .map(|i| i.text(use_color) + " ; ")
+            .collect()
+    }
+}
+
+///
+/// Parse a byte pattern written as hexadecimal digits, where `?` matches any nibble, e.g.
+/// `ff e? ?? c3`. The spaces are optional.
+///
+pub fn parse_pattern(pattern: &str) -> GenericResult<BytePattern> {
+    let invalid = |reason: &str| Error::InvalidPattern {
+        pattern: pattern.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let nibbles: Vec<char> = pattern.chars().filter(|c| !c.is_whitespace()).collect();
+    if nibbles.is_empty() {
+        return Err(invalid("the pattern is empty"));
+    }
+    if !nibbles.len().is_multiple_of(2) {
+        return Err(invalid("the pattern must be made of whole bytes"));
+    }
+
+    let nibble = |c: char| -> GenericResult<(u8, u8)> {
+        match c {
+            '?' => Ok((0, 0)),
+            _ => match c.to_digit(16) {
+                Some(value) => Ok((value as u8, 0xf)),
+                None => Err(invalid(&format!("'{}' is neither a hex digit nor '?'", c))),
+            },
+        }
+    };
+
+    let mut bytes = Vec::with_capacity(nibbles.len() / 2);
+    let mut mask = Vec::with_capacity(nibbles.len() / 2);
+    for pair in nibbles.chunks(2) {
+        let (high, high_mask) = nibble(pair[0])?;
+        let (low, low_mask) = nibble(pair[1])?;
+        bytes.push(high << 4 | low);
+        mask.push(high_mask << 4 | low_mask);
+    }
+
+    if mask.iter().all(|m| *m == 0) {
+        return Err(invalid("the pattern only has wildcards"));
+    }
+
+    Ok((bytes, mask))
+}
+
+///
+/// Look for the patterns starting in `data[start..end]`, and return their position (relative to
+/// the start of `data`) and length. The matches starting in the range but ending after it are
+/// included, so that consecutive ranges find exactly the same matches as a single range covering
+/// them.
+///
+pub fn find_in_range(
+    matcher: &PatternMatcher,
+    data: &[u8],
+    start: usize,
+    end: usize,
+) -> Vec<(usize, usize)> {
+    let end = std::cmp::min(end, data.len());
+    let start = std::cmp::min(start, end);
+
+    //
+    // extend the range so the matches crossing its end can be found too
+    //
+    let overlap = matcher.max_len().saturating_sub(1);
+    let data = &data[start..std::cmp::min(end + overlap, data.len())];
+
+    matcher
+        .find_all(data)
+        .into_iter()
+        .filter(|(pos, _)| start + pos < end)
+        .map(|(pos, len)| (start + pos, len))
+        .collect()
+}
+
+///
+/// Disassemble the instructions from `offset` of the section, until at least `min_size` bytes
+/// and `min_count` instructions are covered (or an invalid instruction is met)
+///
+fn disassemble_from(
+    disassembler: &dyn Disassembler,
+    section: &Section,
+    offset: usize,
+    min_size: usize,
+    min_count: usize,
+) -> Vec<Instruction> {
+    let mut insns = Vec::new();
+    let mut pos = offset;
+
+    while pos - offset < min_size || insns.len() < min_count {
+        let code = match section.data.get(pos..) {
+            Some(code) if !code.is_empty() => &code[..std::cmp::min(code.len(), MAX_INSN_SIZE)],
+            _ => break,
+        };
+        match disassembler.disassemble_one(code, section.start_address + pos as u64) {
+            Some(insn) if insn.size > 0 => {
+                pos += insn.size;
+                insns.push(insn);
+            }
+            _ => break,
+        }
+    }
+
+    insns
+}
+
+///
+/// Look for any of the byte patterns in the executable sections of the session, in the functions
+/// passing its symbol filter if any
+///
+pub fn search_patterns(
+    session: &Session,
+    patterns: &[BytePattern],
+) -> GenericResult<Vec<SearchMatch>> {
+    let cpu = session.info.cpu.as_ref();
+    let engine = DisassemblyEngine::new(&session.engine_type, cpu)?;
+    let matcher = PatternMatcher::new(patterns);
+    let symbols = session.info.format.symbols();
+    let mut matches = Vec::new();
+
+    for section in session.info.format.sections() {
+        if !section.is_executable() {
+            continue;
+        }
+
+        debug!("Searching {} pattern(s) in {}", patterns.len(), section);
+
+        for (pos, len) in find_in_range(&matcher, &section.data, 0, section.data.len()) {
+            let address = section.start_address + pos as u64;
+            let symbol = symbols.lookup(address);
+            if !session.keeps_symbol(symbol.as_ref()) {
+                continue;
+            }
+
+            let preview = disassemble_from(
+                engine.disassembler.as_ref(),
+                section,
+                pos,
+                len,
+                PREVIEW_LENGTH,
+            );
+            matches.push(SearchMatch {
+                address,
+                section: section.name.clone(),
+                raw: section.data[pos..pos + len].to_vec(),
+                preview,
+                symbol,
+            });
+        }
+    }
+
+    Ok(matches)
+}
+
+///
+/// Look for a byte pattern (see `parse_pattern`) in the executable sections of the session
+///
+pub fn search_bytes(session: &Session, pattern: &str) -> GenericResult<Vec<SearchMatch>> {
+    search_patterns(session, &[parse_pattern(pattern)?])
+}
+
+///
+/// Whether the text matches the glob pattern, where `*` matches any (possibly empty) string and
+/// `?` any character
+///
+pub(crate) fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+///
+/// Lowercase text of an instruction, with single spaces
+///
+fn canonical_text(text: &str) -> Vec<char> {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+        .chars()
+        .collect()
+}
+
+///
+/// Look for a sequence of instructions in the executable sections of the session. The query is
+/// written like the gadgets, e.g. `pop r?? ; ret`: each instruction is a glob pattern (`*` and
+/// `?`) matched against the canonical (Intel) text of the instructions decoded at each offset.
+/// As for the gadgets, the matches outside of the functions of the symbol filter are discarded.
+///
+pub fn search_instructions(session: &Session, query: &str) -> GenericResult<Vec<SearchMatch>> {
+    let queries: Vec<Vec<char>> = query
+        .split(';')
+        .map(canonical_text)
+        .filter(|q| !q.is_empty())
+        .collect();
+    if queries.is_empty() {
+        return Err(Error::InvalidPattern {
+            pattern: query.to_string(),
+            reason: String::from("no instruction to look for"),
+        });
+    }
+
+    let cpu = session.info.cpu.as_ref();
+    let engine = DisassemblyEngine::new(&session.engine_type, cpu)?;
+    let disassembler = engine.disassembler.as_ref();
+    let step = cpu.insn_step();
+    let symbols = session.info.format.symbols();
+    let mut matches = Vec::new();
+
+    for section in session.info.format.sections() {
+        if !section.is_executable() {
+            continue;
+        }
+
+        debug!("Searching '{}' in {}", query, section);
+
+        for offset in (0..section.data.len()).step_by(step) {
+            let insns = disassemble_from(disassembler, section, offset, 0, 1);
+            let first = match insns.first() {
+                Some(first) => first,
+                None => continue,
+            };
+            if !glob_matches(&queries[0], &canonical_text(&first.text(false))) {
+                continue;
+            }
+
+            let insns = disassemble_from(disassembler, section, offset, 0, queries.len());
+            if insns.len() < queries.len()
+                || !queries
+                    .iter()
+                    .zip(&insns)
+                    .all(|(q, i)| glob_matches(q, &canonical_text(&i.text(false))))
+            {
+                continue;
+            }
+
+            let address = section.start_address + offset as u64;
+            let symbol = symbols.lookup(address);
+            if !session.keeps_symbol(symbol.as_ref()) {
+                continue;
+            }
+
+            let size: usize = insns.iter().map(|i| i.size).sum();
+            matches.push(SearchMatch {
+                address,
+                section: section.name.clone(),
+                raw: section.data[offset..offset + size].to_vec(),
+                preview: insns,
+                symbol,
+            });
+        }
+    }
+
+    Ok(matches)
+}
+
+//
+// Data search, in all the sections loaded (see `format::SectionFilter`)
+//
+
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Default)]
+pub enum StringEncoding {
+    #[default]
+    Ascii,
+    /// Little-endian UTF-16, as the wide strings of Windows
+    Utf16,
+}
+
+///
+/// A match of a data search
+///
+#[derive(Debug, Clone)]
+pub struct DataMatch {
+    /// Virtual address of the match, at the preferred image base
+    pub address: u64,
+    pub section: Option<String>,
+    pub permission: Permission,
+    /// Number of bytes available from the address
+    pub size: usize,
+}
+
+///
+/// Parse bytes written as hexadecimal digits, e.g. the bad chars `00 0a 0d`
+///
+pub fn parse_bytes(text: &str) -> GenericResult<Vec<u8>> {
+    let (bytes, mask) = parse_pattern(text)?;
+    if mask.iter().any(|m| *m != 0xff) {
+        return Err(Error::InvalidPattern {
+            pattern: text.to_string(),
+            reason: String::from("wildcards are not allowed"),
+        });
+    }
+    Ok(bytes)
+}
+
+///
+/// Whether the address, written as a pointer, is free of bad chars
+///
+fn is_clean(address: u64, ptrsize: usize, bad_chars: &[u8]) -> bool {
+    address.to_le_bytes()[..ptrsize]
+        .iter()
+        .all(|b| !bad_chars.contains(b))
+}
+
+///
+/// Look for the bytes in the sections of the session, at virtual addresses free of bad chars
+///
+fn search_data(session: &Session, bytes: &[u8], bad_chars: &[u8]) -> GenericResult<Vec<DataMatch>> {
+    let ptrsize = session.info.cpu.ptrsize();
+    let matcher = PatternMatcher::new(&[(bytes.to_vec(), vec![0xff; bytes.len()])]);
+    let mut matches = Vec::new();
+
+    for section in session.info.format.sections() {
+        for (pos, len) in find_in_range(&matcher, &section.data, 0, section.data.len()) {
+            let address = session.info.format.va(section.start_address + pos as u64);
+            if !is_clean(address, ptrsize, bad_chars) {
+                continue;
+            }
+            matches.push(DataMatch {
+                address,
+                section: section.name.clone(),
+                permission: section.permission,
+                size: len,
+            });
+        }
+    }
+
+    Ok(matches)
+}
+
+///
+/// Look for a NUL-terminated string, e.g. `/bin/sh`, in the sections of the session. Only the
+/// matches at addresses free of bad chars are returned.
+///
+pub fn search_strings(
+    session: &Session,
+    text: &str,
+    encoding: StringEncoding,
+    bad_chars: &[u8],
+) -> GenericResult<Vec<DataMatch>> {
+    if text.is_empty() {
+        return Err(Error::InvalidPattern {
+            pattern: text.to_string(),
+            reason: String::from("the string is empty"),
+        });
+    }
+
+    let bytes: Vec<u8> = match encoding {
+        StringEncoding::Ascii => text.bytes().chain([0]).collect(),
+        StringEncoding::Utf16 => text
+            .encode_utf16()
+            .chain([0])
+            .flat_map(|c| c.to_le_bytes())
+            .collect(),
+    };
+
+    search_data(session, &bytes, bad_chars)
+}
+
+///
+/// Look for an integer constant of the size of a pointer, e.g. the address of a function, in the
+/// sections of the session. Only the matches at addresses free of bad chars are returned.
+///
+pub fn search_constants(
+    session: &Session,
+    value: u64,
+    bad_chars: &[u8],
+) -> GenericResult<Vec<DataMatch>> {
+    let ptrsize = session.info.cpu.ptrsize();
+    if ptrsize < 8 && value >> (8 * ptrsize) != 0 {
+        return Err(Error::InvalidPattern {
+            pattern: format!("{:#x}", value),
+            reason: format!("the value does not fit in {} bytes", ptrsize),
+        });
+    }
+
+    search_data(session, &value.to_le_bytes()[..ptrsize], bad_chars)
+}
+
+///
+/// Look for at least `size` zero bytes in the writable sections of the session, e.g. to use as
+/// scratch memory. The sections are zero-filled past their data in the file (e.g. `.bss`). Each
+/// run of zeros is returned once, from its first address free of bad chars.
+///
+pub fn search_writable(
+    session: &Session,
+    size: usize,
+    bad_chars: &[u8],
+) -> GenericResult<Vec<DataMatch>> {
+    let ptrsize = session.info.cpu.ptrsize();
+    let format = session.info.format.as_ref();
+    let size = std::cmp::max(size, 1);
+    let mut matches = Vec::new();
+
+    for section in session.info.format.sections() {
+        if !section.permission.contains(Permission::WRITABLE) {
+            continue;
+        }
+
+        let byte_at = |pos: usize| section.data.get(pos).copied().unwrap_or(0);
+        let mut pos = 0;
+        while pos < section.size() {
+            if byte_at(pos) != 0 {
+                pos += 1;
+                continue;
+            }
+
+            let start = pos;
+            while pos < section.size() && byte_at(pos) == 0 {
+                pos += 1;
+            }
+
+            let first = (start..=pos.saturating_sub(size))
+                .take_while(|offset| pos - offset >= size)
+                .find(|offset| {
+                    let address = format.va(section.start_address + *offset as u64);
+                    is_clean(address, ptrsize, bad_chars)
+                });
+
+            if let Some(offset) = first {
+                matches.push(DataMatch {
+                    address: format.va(section.start_address + offset as u64),
+                    section: section.name.clone(),
+                    permission: section.permission,
+                    size: pos - offset,
+                });
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn patterns() {
+        assert_eq!(
+            parse_pattern("ff e? ?? c3").unwrap(),
+            (vec![0xff, 0xe0, 0x00, 0xc3], vec![0xff, 0xf0, 0x00, 0xff])
+        );
+        assert_eq!(
+            parse_pattern("5fC3").unwrap(),
+            parse_pattern("5f c3").unwrap()
+        );
+        for invalid in ["", "f", "ff e", "?? ??", "zz"] {
+            assert!(matches!(
+                parse_pattern(invalid),
+                Err(Error::InvalidPattern { .. })
+            ));
+        }
+
+        let globs = [
+            ("pop r??", "pop rdi", true),
+            ("pop r*", "pop r15", true),
+            ("mov *, qword ptr [*]", "mov rax, qword ptr [rsp + 8]", true),
+            ("pop r??", "pop r15d", false),
+            ("ret", "ret 8", false),
+        ];
+        for (pattern, text, expected) in globs {
+            let (pattern, text) = (canonical_text(pattern), canonical_text(text));
+            assert_eq!(glob_matches(&pattern, &text), expected, "{:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn search_bytes_and_instructions() {
+        let sess = Session::new(PathBuf::from("tests/bin/small-x64.elf")).unwrap();
+
+        //
+        // `push` or `pop` of a 64-bit register (`50` to `5f`) followed by `ret`
+        //
+        let by_bytes = search_bytes(&sess, "5? c3").unwrap();
+        assert!(!by_bytes.is_empty());
+        for m in &by_bytes {
+            assert_eq!(m.raw.len(), 2);
+            assert_eq!(m.raw[1], 0xc3);
+            assert!(m.preview.len() >= 2);
+            assert!(m.preview_text(false).starts_with("p"));
+        }
+
+        let by_insns = search_instructions(&sess, "pop r?? ; ret").unwrap();
+        assert!(!by_insns.is_empty());
+        for m in &by_insns {
+            assert_eq!(m.preview.len(), 2);
+            assert_eq!(m.preview[1].mnemonic, "ret");
+            assert_eq!(m.raw.len(), m.preview.iter().map(|i| i.size).sum::<usize>());
+        }
+
+        //
+        // the pops found by pattern (`58` to `5f`) are found by text too
+        //
+        for m in by_bytes.iter().filter(|m| m.raw[0] >= 0x58) {
+            assert!(by_insns.iter().any(|i| i.address == m.address));
+        }
+    }
+
+    #[test]
+    fn search_data_in_mapped_sections() {
+        use crate::format::SectionFilter;
+        use std::convert::TryInto;
+
+        let fname = PathBuf::from("tests/bin/small-x64.elf");
+        let sess = Session::load(fname.clone(), None, SectionFilter::Mapped).unwrap();
+        let interp = "/lib64/ld-linux-x86-64.so.2";
+
+        let strings = search_strings(&sess, interp, StringEncoding::Ascii, &[]).unwrap();
+        assert_eq!(strings.len(), 1);
+        assert_eq!(strings[0].address, 0x318);
+        assert_eq!(strings[0].section.as_deref(), Some(".interp"));
+        assert_eq!(strings[0].permission, Permission::READABLE);
+
+        //
+        // the executable sections only are loaded by default, and bad chars filter the addresses
+        //
+        let exec_only = Session::new(fname).unwrap();
+        assert!(
+            search_strings(&exec_only, interp, StringEncoding::Ascii, &[])
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            search_strings(&sess, interp, StringEncoding::Ascii, &[0x18])
+                .unwrap()
+                .is_empty()
+        );
+
+        //
+        // the pointer stored at the start of `.data` is found there
+        //
+        let data = sess
+            .info
+            .format
+            .sections()
+            .iter()
+            .find(|s| s.name.as_deref() == Some(".data"))
+            .unwrap();
+        let value = u64::from_le_bytes(data.data[..8].try_into().unwrap());
+        let constants = search_constants(&sess, value, &[]).unwrap();
+        assert!(constants.iter().any(|m| m.address == data.start_address));
+
+        //
+        // `.bss` is zero-filled, though it has no bytes in the file
+        //
+        let writable = search_writable(&sess, 0x1000, &[0x80]).unwrap();
+        assert!(!writable.is_empty());
+        for m in &writable {
+            assert!(m.permission.contains(Permission::WRITABLE));
+            assert!(m.size >= 0x1000);
+            assert!(is_clean(m.address, 8, &[0x80]));
+        }
+        assert!(writable
+            .iter()
+            .any(|m| m.section.as_deref() == Some(".bss")));
+
+        let wide = Session::load(
+            PathBuf::from("tests/bin/small-x64.pe"),
+            None,
+            SectionFilter::Mapped,
+        )
+        .unwrap();
+        let query = "ENABLEDELAYEDEXPANSION";
+        assert!(!search_strings(&wide, query, StringEncoding::Utf16, &[])
+            .unwrap()
+            .is_empty());
+
+        let x86 = Session::new(PathBuf::from("tests/bin/small-x86.pe")).unwrap();
+        assert!(search_constants(&x86, 1 << 32, &[]).is_err());
+        assert!(parse_bytes("00 0?").is_err());
+    }
+
+    #[test]
+    fn bad_chars_apply_to_the_virtual_address() {
+        use crate::format::SectionFilter;
+
+        //
+        // the PE sections are mapped at their RVA, but the pointers written are VAs
+        //
+        let fname = PathBuf::from("tests/bin/small-x86.pe");
+        let sess = Session::load(fname, None, SectionFilter::Mapped).unwrap();
+        let image_base = sess.info.format.image_base();
+        assert_eq!(image_base, 0x400000);
+
+        let writable = search_writable(&sess, 0x10, &[]).unwrap();
+        assert!(!writable.is_empty());
+        for m in &writable {
+            let rva = m.address - image_base;
+            assert!(sess
+                .info
+                .format
+                .sections()
+                .iter()
+                .any(|s| s.start_address <= rva && rva < s.end_address));
+        }
+
+        //
+        // `.data` starts at 0x42e191 (RVA 0x2e191): with 0x42 as a bad char, the first clean
+        // address of its zeros is 0x430000, while the RVA itself has no 0x42 byte
+        //
+        assert_eq!(writable[0].address, 0x42e191);
+        let clean = search_writable(&sess, 0x10, &[0x42]).unwrap();
+        assert_eq!(clean[0].address, 0x430000);
+    }
+}
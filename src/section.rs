@@ -1,7 +1,9 @@
-use std::{borrow::Borrow, fmt};
+use std::{borrow::Borrow, fmt, ops::Deref, ops::Range, sync::Arc};
+
+use memmap2::Mmap;
 
 bitflags! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Permission: u8
     {
         const NONE = 0;
@@ -20,14 +22,80 @@ impl Default for Permission {
     }
 }
 
+///
+/// The bytes of a section: a range of a buffer shared by all the sections of a file, usually the
+/// memory mapping of the file itself, so that loading a section copies nothing
+///
+#[derive(Clone)]
+pub struct SectionData {
+    backing: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    range: Range<usize>,
+}
+
+impl SectionData {
+    ///
+    /// Borrow `size` bytes at `offset` of the mapping, truncated to the end of the file
+    ///
+    pub fn mapped(mapping: &Arc<Mmap>, offset: u64, size: usize) -> Self {
+        let len = mapping.len();
+        let start = std::cmp::min(offset as usize, len);
+        let end = std::cmp::min(start.saturating_add(size), len);
+        Self {
+            backing: mapping.clone(),
+            range: start..end,
+        }
+    }
+
+    ///
+    /// Offset of the data in the backing buffer
+    ///
+    pub fn offset(&self) -> usize {
+        self.range.start
+    }
+}
+
+impl From<Vec<u8>> for SectionData {
+    fn from(data: Vec<u8>) -> Self {
+        let range = 0..data.len();
+        Self {
+            backing: Arc::new(data),
+            range,
+        }
+    }
+}
+
+impl Default for SectionData {
+    fn default() -> Self {
+        Self::from(Vec::new())
+    }
+}
+
+impl Deref for SectionData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(*self.backing).as_ref()[self.range.clone()]
+    }
+}
+
+impl fmt::Debug for SectionData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SectionData")
+            .field("offset", &self.range.start)
+            .field("len", &self.range.len())
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 #[derive(Default)]
 pub struct Section {
     pub start_address: u64,
     pub end_address: u64,
+    pub file_offset: u64,
     pub name: Option<String>,
     pub permission: Permission,
-    pub data: Vec<u8>,
+    pub data: SectionData,
 }
 
 impl fmt::Display for Section {
@@ -55,9 +123,10 @@ impl Section {
         Self {
             start_address,
             end_address,
+            file_offset: 0,
             name: None,
             permission: Permission::NONE,
-            data: vec![0; sz],
+            data: SectionData::from(vec![0; sz]),
         }
     }
 
@@ -72,8 +141,26 @@ impl Section {
         }
     }
 
-    pub fn data(self, data: Vec<u8>) -> Self {
-        Self { data, ..self }
+    pub fn data(self, data: impl Into<SectionData>) -> Self {
+        Self {
+            data: data.into(),
+            ..self
+        }
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.permission.contains(Permission::EXECUTABLE)
+    }
+
+    pub fn contains(&self, address: u64) -> bool {
+        self.start_address <= address && address < self.end_address
+    }
+
+    ///
+    /// Convert an address inside the section to its offset in the file
+    ///
+    pub fn file_offset_of(&self, address: u64) -> u64 {
+        self.file_offset + (address - self.start_address)
     }
 }
 
@@ -83,6 +170,10 @@ impl From<&goblin::elf::section_header::SectionHeader> for Section {
     fn from(value: &goblin::elf::section_header::SectionHeader) -> Self {
         let mut perm = Permission::NONE;
 
+        if value.is_alloc() {
+            perm |= Permission::READABLE;
+        }
+
         if value.is_executable() {
             perm |= Permission::READABLE | Permission::EXECUTABLE;
         }
@@ -96,22 +187,20 @@ impl From<&goblin::elf::section_header::SectionHeader> for Section {
         Self {
             start_address: value.sh_addr,
             end_address: value.sh_addr + sz as u64,
+            file_offset: value.sh_offset,
             permission: perm,
             name: None,
-            data: vec![0; sz],
+            data: SectionData::default(),
         }
     }
 }
 
 impl From<&goblin::mach::segment::Segment<'_>> for Section {
     fn from(value: &goblin::mach::segment::Segment) -> Self {
-        let mut perm = Permission::READABLE;
-
-        if value.flags & goblin::mach::constants::S_ATTR_PURE_INSTRUCTIONS == 0
-            || value.flags & goblin::mach::constants::S_ATTR_SOME_INSTRUCTIONS == 0
-        {
-            perm |= Permission::EXECUTABLE;
-        }
+        //
+        // the initial protection of the segment uses the same bits as `Permission`
+        //
+        let perm = Permission::from_bits_truncate(value.initprot as u8);
 
         let section_name = match std::str::from_utf8(&value.segname) {
             Ok(v) => String::from(v).replace('\0', ""),
@@ -123,9 +212,10 @@ impl From<&goblin::mach::segment::Segment<'_>> for Section {
         Self {
             start_address: value.vmaddr,
             end_address: value.vmaddr + sz as u64,
+            file_offset: value.fileoff,
             name: Some(section_name),
             permission: perm,
-            data: vec![0; sz],
+            data: SectionData::default(),
         }
     }
 }
@@ -150,14 +240,13 @@ impl From<&goblin::pe::section_table::SectionTable> for Section {
             perm |= Permission::EXECUTABLE;
         }
 
-        let sz = value.virtual_size as usize;
-
         Self {
             start_address: value.virtual_address as u64,
             end_address: (value.virtual_address + value.virtual_size) as u64,
+            file_offset: value.pointer_to_raw_data as u64,
             name: Some(section_name),
             permission: perm,
-            data: vec![0; sz],
+            data: SectionData::default(),
         }
     }
 }
@@ -0,0 +1,466 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::cpu::CpuType;
+use crate::emulator::{self, mask, BinOp, Fault, Machine, Outcome, UnOp};
+use crate::gadget::Gadget;
+
+/**
+ *
+ * Symbolic summaries of gadgets: each gadget is described by a set of
+ * postconditions, expressed over the initial register file and memory, e.g.
+ *
+ *   rdi' = [rsp] ; rsp' = rsp+0x10 ; pc' = [rsp+0x8]
+ *
+ * The summaries reuse the instruction semantics of the emulator, by running it
+ * over a symbolic `Machine`.
+ *
+ */
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Expr {
+    Const(u64),
+    /// Initial value of a register
+    Reg(String),
+    /// Initial value of the memory at the given address, of the given size (in bytes)
+    Load(Box<Expr>, usize),
+    Unary(UnOp, Box<Expr>, u32),
+    Binary(BinOp, Box<Expr>, Box<Expr>, u32),
+}
+
+impl Expr {
+    pub fn reg(name: &str) -> Self {
+        Expr::Reg(name.to_string())
+    }
+
+    pub fn as_const(&self) -> Option<u64> {
+        match self {
+            Expr::Const(c) => Some(*c),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Decompose the expression as `base + offset`
+    ///
+    pub fn as_offset(&self) -> (Option<&Expr>, u64) {
+        match self {
+            Expr::Const(c) => (None, *c),
+            Expr::Binary(BinOp::Add, a, b, _) => match b.as_ref() {
+                Expr::Const(c) => (Some(a.as_ref()), *c),
+                _ => (Some(self), 0),
+            },
+            _ => (Some(self), 0),
+        }
+    }
+
+    ///
+    /// List all the registers the expression depends on
+    ///
+    pub fn registers(&self) -> Vec<&str> {
+        let mut regs = Vec::new();
+        self.collect_registers(&mut regs);
+        regs.sort_unstable();
+        regs.dedup();
+        regs
+    }
+
+    fn collect_registers<'a>(&'a self, regs: &mut Vec<&'a str>) {
+        match self {
+            Expr::Const(_) => {}
+            Expr::Reg(r) => regs.push(r),
+            Expr::Load(a, _) => a.collect_registers(regs),
+            Expr::Unary(_, a, _) => a.collect_registers(regs),
+            Expr::Binary(_, a, b, _) => {
+                a.collect_registers(regs);
+                b.collect_registers(regs);
+            }
+        }
+    }
+
+    pub fn is_memory_dependent(&self) -> bool {
+        match self {
+            Expr::Const(_) | Expr::Reg(_) => false,
+            Expr::Load(_, _) => true,
+            Expr::Unary(_, a, _) => a.is_memory_dependent(),
+            Expr::Binary(_, a, b, _) => a.is_memory_dependent() || b.is_memory_dependent(),
+        }
+    }
+}
+
+fn signed(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits.min(64);
+    ((value << shift) as i64) >> shift
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Const(c) => write!(f, "{:#x}", c),
+            Expr::Reg(r) => write!(f, "{}", r),
+            Expr::Load(a, _) => write!(f, "[{}]", a),
+            Expr::Unary(UnOp::Not, a, _) => write!(f, "~{}", a),
+            Expr::Unary(UnOp::Neg, a, _) => write!(f, "-{}", a),
+            Expr::Unary(UnOp::SignExtend(from), a, _) => write!(f, "sext{}({})", from, a),
+            Expr::Binary(BinOp::Add, a, b, bits) => match b.as_ref() {
+                Expr::Const(c) if signed(*c, *bits) < 0 => {
+                    write!(f, "{}-{:#x}", a, signed(*c, *bits).unsigned_abs())
+                }
+                Expr::Const(c) => write!(f, "{}+{:#x}", a, c),
+                _ => write!(f, "({} + {})", a, b),
+            },
+            Expr::Binary(op, a, b, _) => {
+                let op = match op {
+                    BinOp::Add => "+",
+                    BinOp::Sub => "-",
+                    BinOp::Mul => "*",
+                    BinOp::And => "&",
+                    BinOp::Or => "|",
+                    BinOp::Xor => "^",
+                    BinOp::Shl => "<<",
+                    BinOp::Shr => ">>",
+                    BinOp::Sar => ">>>",
+                };
+                write!(f, "({} {} {})", a, op, b)
+            }
+        }
+    }
+}
+
+///
+/// Machine over symbolic values. Memory is modeled as the list of stores performed by the
+/// gadget: a load is forwarded from the last store to the same address, and otherwise reads
+/// the initial memory. Aliasing between different symbolic addresses is not considered.
+///
+#[derive(Debug, Clone)]
+pub struct SymbolicState {
+    bits: u32,
+    pub registers: BTreeMap<String, Expr>,
+    pub writes: Vec<(Expr, Expr, usize)>,
+    pub reads: BTreeSet<(Expr, usize)>,
+}
+
+impl SymbolicState {
+    pub fn new(cpu: CpuType) -> Self {
+        let bits = match cpu {
+            CpuType::X64 | CpuType::ARM64 => 64,
+            _ => 32,
+        };
+        Self {
+            bits,
+            registers: BTreeMap::new(),
+            writes: Vec::new(),
+            reads: BTreeSet::new(),
+        }
+    }
+
+    fn truncate(&self, e: Expr, bits: u32) -> Expr {
+        if bits >= self.bits {
+            return e;
+        }
+        self.simplify_binary(BinOp::And, e, Expr::Const(mask(bits)), self.bits)
+    }
+
+    fn simplify_binary(&self, op: BinOp, a: Expr, b: Expr, bits: u32) -> Expr {
+        let m = mask(bits);
+
+        //
+        // constant folding
+        //
+        if let (Some(x), Some(y)) = (a.as_const(), b.as_const()) {
+            let value = match op {
+                BinOp::Add => x.wrapping_add(y),
+                BinOp::Sub => x.wrapping_sub(y),
+                BinOp::Mul => x.wrapping_mul(y),
+                BinOp::And => x & y,
+                BinOp::Or => x | y,
+                BinOp::Xor => x ^ y,
+                BinOp::Shl => x.checked_shl(y as u32).unwrap_or(0),
+                BinOp::Shr => (x & m).checked_shr(y as u32).unwrap_or(0),
+                BinOp::Sar => (signed(x & m, bits) >> (y as u32).min(63)) as u64,
+            };
+            return Expr::Const(value & m);
+        }
+
+        //
+        // canonical forms: constants on the right, and subtraction of a constant is an addition
+        //
+        let (a, b) = match op {
+            BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or | BinOp::Xor
+                if a.as_const().is_some() || (b.as_const().is_none() && b < a) =>
+            {
+                (b, a)
+            }
+            _ => (a, b),
+        };
+
+        if op == BinOp::Sub {
+            if let Some(c) = b.as_const() {
+                return self.simplify_binary(
+                    BinOp::Add,
+                    a,
+                    Expr::Const(c.wrapping_neg() & m),
+                    bits,
+                );
+            }
+            if a == b {
+                return Expr::Const(0);
+            }
+        }
+
+        match (op, b.as_const()) {
+            (
+                BinOp::Add | BinOp::Or | BinOp::Xor | BinOp::Shl | BinOp::Shr | BinOp::Sar,
+                Some(0),
+            ) => return a,
+            (BinOp::Mul | BinOp::And, Some(0)) => return Expr::Const(0),
+            (BinOp::Mul, Some(1)) => return a,
+            (BinOp::And, Some(c)) if c & m == m => return a,
+            _ => {}
+        };
+
+        if a == b {
+            match op {
+                BinOp::Xor => return Expr::Const(0),
+                BinOp::And | BinOp::Or => return a,
+                _ => {}
+            }
+        }
+
+        //
+        // fold nested constants: (x + c1) + c2 => x + (c1 + c2), (x & c1) & c2 => x & (c1 & c2)
+        //
+        if let (Some(c2), Expr::Binary(inner, x, c1, _)) = (b.as_const(), &a) {
+            if let Some(c1) = c1.as_const() {
+                match (op, inner) {
+                    (BinOp::Add, BinOp::Add) => {
+                        return self.simplify_binary(
+                            BinOp::Add,
+                            *x.clone(),
+                            Expr::Const(c1.wrapping_add(c2) & m),
+                            bits,
+                        )
+                    }
+                    (BinOp::And, BinOp::And) => {
+                        return self.simplify_binary(
+                            BinOp::And,
+                            *x.clone(),
+                            Expr::Const(c1 & c2),
+                            bits,
+                        )
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Expr::Binary(op, Box::new(a), Box::new(b), bits)
+    }
+}
+
+impl Machine for SymbolicState {
+    type Value = Expr;
+
+    fn constant(&self, value: u64) -> Expr {
+        Expr::Const(value & mask(self.bits))
+    }
+
+    fn concrete(&self, value: &Expr) -> Option<u64> {
+        value.as_const()
+    }
+
+    fn register(&mut self, name: &str) -> Expr {
+        self.registers
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Expr::reg(name))
+    }
+
+    fn set_register(&mut self, name: &str, value: Expr) {
+        self.registers.insert(name.to_string(), value);
+    }
+
+    fn load(&mut self, address: &Expr, size: usize) -> Result<Expr, Fault> {
+        for (addr, value, sz) in self.writes.iter().rev() {
+            if addr == address && *sz == size {
+                return Ok(value.clone());
+            }
+        }
+        self.reads.insert((address.clone(), size));
+        Ok(Expr::Load(Box::new(address.clone()), size))
+    }
+
+    fn store(&mut self, address: &Expr, value: &Expr, size: usize) -> Result<(), Fault> {
+        let value = self.truncate(value.clone(), size as u32 * 8);
+        self.writes.push((address.clone(), value, size));
+        Ok(())
+    }
+
+    fn unop(&self, op: UnOp, a: &Expr, bits: u32) -> Expr {
+        let m = mask(bits);
+        match (op, a) {
+            (UnOp::Not, Expr::Const(c)) => Expr::Const(!c & m),
+            (UnOp::Neg, Expr::Const(c)) => Expr::Const(c.wrapping_neg() & m),
+            (UnOp::SignExtend(from), Expr::Const(c)) => Expr::Const(signed(*c, from) as u64 & m),
+            (UnOp::Not, Expr::Unary(UnOp::Not, x, _))
+            | (UnOp::Neg, Expr::Unary(UnOp::Neg, x, _)) => self.truncate(*x.clone(), bits),
+            _ => self.truncate(Expr::Unary(op, Box::new(a.clone()), bits), bits),
+        }
+    }
+
+    fn binop(&self, op: BinOp, a: &Expr, b: &Expr, bits: u32) -> Expr {
+        let value = self.simplify_binary(op, a.clone(), b.clone(), bits);
+        self.truncate(value, bits)
+    }
+}
+
+///
+/// Set of postconditions of a gadget
+///
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Summary {
+    /// Final value of each register modified by the gadget
+    pub registers: BTreeMap<String, Expr>,
+    /// Memory writes as (address, value, size)
+    pub writes: Vec<(Expr, Expr, usize)>,
+    /// Memory reads, of values not written by the gadget, as (address, size). A read may fault
+    /// even when its value is discarded (e.g. `cmp qword ptr [rax], 0`)
+    pub reads: BTreeSet<(Expr, usize)>,
+    /// Where the execution goes after the gadget
+    pub next_pc: Expr,
+}
+
+impl Summary {
+    pub fn stack_delta(&self, cpu: CpuType) -> Option<i64> {
+        let sp = emulator::stack_pointer(cpu);
+        match self.registers.get(sp) {
+            None => Some(0),
+            Some(e) => match e.as_offset() {
+                //
+                // the offset is masked to the width of the stack pointer, e.g. `esp-4` is
+                // `esp+0xfffffffc` on x86
+                //
+                (Some(Expr::Reg(r)), off) if r == sp => {
+                    Some(signed(off, emulator::ptrsize(cpu) as u32 * 8))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    ///
+    /// Registers modified by the gadget, besides the stack pointer
+    ///
+    pub fn clobbers(&self, cpu: CpuType) -> Vec<&str> {
+        let sp = emulator::stack_pointer(cpu);
+        self.registers
+            .keys()
+            .map(|r| r.as_str())
+            .filter(|r| *r != sp)
+            .collect()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut conds: Vec<String> = self
+            .registers
+            .iter()
+            .map(|(r, e)| format!("{}' = {}", r, e))
+            .collect();
+        for (addr, value, _) in &self.writes {
+            conds.push(format!("[{}]' = {}", addr, value));
+        }
+        conds.push(format!("pc' = {}", self.next_pc));
+        write!(f, "{}", conds.join(" ; "))
+    }
+}
+
+///
+/// Build the summary of a gadget, `None` if any of its instructions cannot be modeled
+///
+pub fn summarize(cpu: CpuType, gadget: &Gadget) -> Option<Summary> {
+    let mut state = SymbolicState::new(cpu);
+
+    let next_pc = match emulator::execute(cpu, &mut state, gadget) {
+        Outcome::Completed { next_pc } => next_pc,
+        _ => return None,
+    };
+
+    let registers = state
+        .registers
+        .into_iter()
+        .filter(|(r, e)| *e != Expr::reg(r))
+        .collect();
+
+    Some(Summary {
+        registers,
+        writes: state.writes,
+        reads: state.reads,
+        next_pc,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+    use crate::engine::{DisassemblyEngine, DisassemblyEngineType};
+
+    fn summary(code: &[u8]) -> Summary {
+        summary_for(&cpu::x86::X64 {}, code)
+    }
+
+    fn summary_for(cpu: &dyn cpu::Cpu, code: &[u8]) -> Summary {
+        let engine = DisassemblyEngine::new(&DisassemblyEngineType::Capstone, cpu).unwrap();
+        let gadget = Gadget::new(engine.disassembler.disassemble(code, 0x1000).unwrap()).unwrap();
+        summarize(cpu.cpu_type(), &gadget).unwrap()
+    }
+
+    #[test]
+    fn pop_ret() {
+        // pop rdi ; ret
+        let s = summary(&[0x5f, 0xc3]);
+        assert_eq!(
+            s.to_string(),
+            "rdi' = [rsp] ; rsp' = rsp+0x10 ; pc' = [rsp+0x8]"
+        );
+        assert_eq!(s.stack_delta(CpuType::X64), Some(16));
+        assert_eq!(s.clobbers(CpuType::X64), vec!["rdi"]);
+    }
+
+    #[test]
+    fn x86_stack_delta() {
+        let x86 = cpu::x86::X86 {};
+
+        // pop edi ; ret
+        let s = summary_for(&x86, &[0x5f, 0xc3]);
+        assert_eq!(s.stack_delta(CpuType::X86), Some(8));
+
+        // sub esp, 4 ; ret
+        let s = summary_for(&x86, &[0x83, 0xec, 0x04, 0xc3]);
+        assert_eq!(s.stack_delta(CpuType::X86), Some(0));
+
+        // sub esp, 0x10 ; ret
+        let s = summary_for(&x86, &[0x83, 0xec, 0x10, 0xc3]);
+        assert_eq!(s.stack_delta(CpuType::X86), Some(-12));
+
+        // sub rsp, 0x10 ; ret
+        let s = summary(&[0x48, 0x83, 0xec, 0x10, 0xc3]);
+        assert_eq!(s.stack_delta(CpuType::X64), Some(-8));
+    }
+
+    #[test]
+    fn arithmetic() {
+        // add rax, rbx ; ret
+        let s = summary(&[0x48, 0x01, 0xd8, 0xc3]);
+        assert_eq!(s.registers["rax"].to_string(), "(rax + rbx)");
+
+        // xor eax, eax ; ret == sub eax, eax ; ret
+        assert_eq!(summary(&[0x31, 0xc0, 0xc3]), summary(&[0x29, 0xc0, 0xc3]));
+        assert_eq!(
+            summary(&[0x31, 0xc0, 0xc3]).registers["rax"],
+            Expr::Const(0)
+        );
+    }
+}
@@ -1,26 +1,34 @@
-use std::borrow::Borrow;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
 use clap::ValueEnum;
 use colored::*;
+use crossbeam_deque::{Injector, Steal};
 use log::{debug, info, warn, Level, LevelFilter, Metadata, Record};
 
 use crate::common::GenericResult;
 use crate::cpu;
+use crate::emulator::Emulator;
 use crate::engine::{DisassemblyEngine, DisassemblyEngineType};
-use crate::format::{self, guess_file_format};
+use crate::error::Error;
+use crate::format::{self, guess_file_format, SectionFilter};
 use crate::gadget::{
-    find_gadgets_from_position, get_all_valid_positions_and_length, Gadget, InstructionGroup,
+    find_gadgets_from_position, get_all_valid_positions_and_length, Gadget, InstructionCache,
+    InstructionGroup,
 };
+use crate::render::{RenderOptions, Syntax};
+use crate::search;
+use crate::symbols::SymbolOffset;
 
 #[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
 pub enum RopProfileStrategy {
     #[default]
-    /// Strategy Fast
+    /// Only the first terminating instruction of each gadget type
     Fast,
-    /// Strategy Complete
+    /// All the terminating instructions of each gadget type
     Complete,
 }
 
@@ -30,6 +38,17 @@ impl std::fmt::Display for RopProfileStrategy {
     }
 }
 
+#[derive(std::fmt::Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Default)]
+pub enum RopGadgetOrder {
+    #[default]
+    /// Sort by address
+    Address,
+    /// Sort by quality score, best first
+    Score,
+    /// Keep the order in which they are found, which allows streaming them to the output
+    Found,
+}
+
 pub struct ExecutableDetails {
     pub filepath: PathBuf,
     pub format: Box<dyn format::ExecutableFileFormat>,
@@ -69,23 +88,71 @@ impl Default for ExecutableDetails {
 }
 
 impl ExecutableDetails {
-    pub fn new(filepath: PathBuf) -> Self {
-        let fpath = filepath.clone();
-        let format = guess_file_format(&fpath).unwrap();
-
-        let cpu: Box<dyn cpu::Cpu> = match format.cpu_type() {
-            cpu::CpuType::X86 => Box::new(cpu::x86::X86 {}),
-            cpu::CpuType::X64 => Box::new(cpu::x86::X64 {}),
-            cpu::CpuType::ARM64 => Box::new(cpu::arm::Arm64 {}),
-            cpu::CpuType::ARM => Box::new(cpu::arm::Arm {}),
-            _ => panic!("CPU type is invalid"),
+    pub fn new(filepath: PathBuf) -> GenericResult<Self> {
+        Self::load(filepath, None, SectionFilter::Executable)
+    }
+
+    fn cpu_of(cpu_type: cpu::CpuType) -> Option<Box<dyn cpu::Cpu>> {
+        match cpu_type {
+            cpu::CpuType::X86 => Some(Box::new(cpu::x86::X86 {})),
+            cpu::CpuType::X64 => Some(Box::new(cpu::x86::X64 {})),
+            cpu::CpuType::ARM64 => Some(Box::new(cpu::arm::Arm64 {})),
+            cpu::CpuType::ARM => Some(Box::new(cpu::arm::Arm {})),
+            cpu::CpuType::Thumb => Some(Box::new(cpu::arm::Thumb {})),
+            cpu::CpuType::Unknown => None,
+        }
+    }
+
+    ///
+    /// Parse the executable, but disassemble it as the given architecture whatever its header
+    /// says: x86 code in a 64-bit PE, Thumb code, or a machine which is not supported otherwise
+    ///
+    pub fn with_architecture(filepath: PathBuf, cpu_type: cpu::CpuType) -> GenericResult<Self> {
+        Self::load(filepath, Some(cpu_type), SectionFilter::Executable)
+    }
+
+    ///
+    /// Parse the executable, keeping the given sections, and disassemble it as the given
+    /// architecture if any (see `with_architecture`), or as the one of its header
+    ///
+    pub fn load(
+        filepath: PathBuf,
+        cpu_type: Option<cpu::CpuType>,
+        sections: SectionFilter,
+    ) -> GenericResult<Self> {
+        let format = guess_file_format(&filepath, sections)?;
+        let cpu = match Self::cpu_of(cpu_type.unwrap_or(format.cpu_type())) {
+            Some(cpu) => cpu,
+            None => {
+                let machine = match cpu_type {
+                    Some(cpu_type) => cpu_type.to_string(),
+                    None => format!("{} file for an unknown machine", format.format()),
+                };
+                return Err(Error::UnsupportedArchitecture {
+                    path: filepath,
+                    machine,
+                });
+            }
         };
-        // let cpu = Box::new( Cpu::from(format.cpu_type()) );
 
-        ExecutableDetails {
-            filepath: fpath,
+        Ok(ExecutableDetails {
+            filepath,
             cpu,
             format,
+        })
+    }
+
+    ///
+    /// Disassemble the executable as the given architecture, instead of the one of its header.
+    /// Forcing an unknown architecture has no effect.
+    ///
+    pub fn architecture(self, cpu_type: cpu::CpuType) -> Self {
+        match Self::cpu_of(cpu_type) {
+            Some(cpu) => Self { cpu, ..self },
+            None => {
+                warn!("Cannot force an unknown architecture, ignoring");
+                self
+            }
         }
     }
 
@@ -100,8 +167,22 @@ pub enum RopGadgetOutput {
     None,
     Console,
     File(PathBuf),
+    /// A single JSON document, to the given file or stdout
+    Json(Option<PathBuf>),
+    /// One JSON object per gadget and per line, to the given file or stdout
+    Ndjson(Option<PathBuf>),
+    /// A Python module with the best gadget of each primitive, to the given file or stdout
+    Python(Option<PathBuf>),
+    /// The format of ROPgadget (`0x... : pop rdi ; ret`), to the given file or stdout
+    RopGadget(Option<PathBuf>),
+    /// The format of rp++ (`0x...: pop rdi ; ret  ;  (1 found)`), to the given file or stdout
+    RpPlusPlus(Option<PathBuf>),
+    /// A SQLite database, possibly shared with other files
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
 }
 
+#[allow(dead_code)]
 struct RpLogger;
 
 impl log::Log for RpLogger {
@@ -147,19 +228,54 @@ pub struct Session {
     //
     pub engine_type: DisassemblyEngineType,
     pub max_gadget_length: usize,
+    pub max_gadget_size: usize,
     pub gadgets: Mutex<Vec<Gadget>>,
     pub unique_only: bool,
+    pub merge_equivalent: bool,
     pub use_color: bool,
+    pub render_options: RenderOptions,
     pub gadget_types: Vec<InstructionGroup>,
     pub profile_type: RopProfileStrategy,
+    pub emulate: bool,
+    pub emulate_strict: bool,
+    pub order: RopGadgetOrder,
+    pub cache: Option<PathBuf>,
+    pub chunk_size: usize,
+    pub symbol_filter: Option<String>,
 }
 
 impl Session {
-    pub fn new(filepath: PathBuf) -> Self {
-        Session {
-            info: ExecutableDetails::new(filepath),
+    pub fn new(filepath: PathBuf) -> GenericResult<Self> {
+        Ok(Session {
+            info: ExecutableDetails::new(filepath)?,
             ..Default::default()
-        }
+        })
+    }
+
+    ///
+    /// Create a session for an executable whose architecture is forced, see
+    /// `ExecutableDetails::with_architecture`
+    ///
+    pub fn with_architecture(filepath: PathBuf, cpu_type: cpu::CpuType) -> GenericResult<Self> {
+        Ok(Session {
+            info: ExecutableDetails::with_architecture(filepath, cpu_type)?,
+            ..Default::default()
+        })
+    }
+
+    ///
+    /// Create a session keeping the given sections of the executable, see
+    /// `ExecutableDetails::load`. The gadgets are only looked for in the executable ones.
+    ///
+    pub fn load(
+        filepath: PathBuf,
+        cpu_type: Option<cpu::CpuType>,
+        sections: SectionFilter,
+    ) -> GenericResult<Self> {
+        Ok(Session {
+            info: ExecutableDetails::load(filepath, cpu_type, sections)?,
+            ..Default::default()
+        })
     }
 
     pub fn nb_thread(self, nb_thread: u8) -> Self {
@@ -180,17 +296,188 @@ impl Session {
         }
     }
 
+    ///
+    /// Also group the gadgets which are semantically equivalent (implies `unique_only`)
+    ///
+    pub fn merge_equivalent(self, merge_equivalent: bool) -> Self {
+        Self {
+            merge_equivalent,
+            ..self
+        }
+    }
+
+    pub fn order(self, order: RopGadgetOrder) -> Self {
+        Self { order, ..self }
+    }
+
     pub fn use_color(self, use_color: bool) -> Self {
         Self { use_color, ..self }
     }
 
+    ///
+    /// How the gadgets are written in the output. The gadgets found, and how they are grouped,
+    /// do not depend on it.
+    ///
+    pub fn render_options(self, render_options: RenderOptions) -> Self {
+        Self {
+            render_options,
+            ..self
+        }
+    }
+
+    ///
+    /// Shortcut to only change the syntax of the render options
+    ///
+    pub fn syntax(self, syntax: Syntax) -> Self {
+        Self {
+            render_options: self.render_options.syntax(syntax),
+            ..self
+        }
+    }
+
     pub fn verbosity(self, verbosity: LevelFilter) -> Self {
         Self { verbosity, ..self }
     }
 
+    ///
+    /// Maximum number of instructions of a gadget, including the terminating one
+    ///
+    pub fn max_gadget_length(self, max_gadget_length: usize) -> Self {
+        Self {
+            max_gadget_length,
+            ..self
+        }
+    }
+
+    ///
+    /// Maximum size of a gadget in bytes, including the terminating instruction
+    ///
+    pub fn max_gadget_size(self, max_gadget_size: usize) -> Self {
+        Self {
+            max_gadget_size,
+            ..self
+        }
+    }
+
+    ///
+    /// Types of the terminating instructions to look for. An empty list keeps the default (`ret`).
+    /// With the fast profile, only the first terminating instruction of each type is looked for.
+    ///
+    pub fn gadget_types(self, gadget_types: Vec<InstructionGroup>) -> Self {
+        if gadget_types.is_empty() {
+            return self;
+        }
+        Self {
+            gadget_types,
+            ..self
+        }
+    }
+
+    pub fn profile_type(self, profile_type: RopProfileStrategy) -> Self {
+        Self {
+            profile_type,
+            ..self
+        }
+    }
+
+    ///
+    /// Disassemble the executable as the given architecture, instead of the one of its header.
+    /// The terminating instructions and the disassembly engine of the workers both derive from
+    /// the CPU of the session, so they follow.
+    ///
+    pub fn architecture(self, cpu_type: cpu::CpuType) -> Self {
+        Self {
+            info: self.info.architecture(cpu_type),
+            ..self
+        }
+    }
+
+    ///
+    /// Select the disassembly engine (capstone by default)
+    ///
+    pub fn engine_type(self, engine_type: DisassemblyEngineType) -> Self {
+        Self {
+            engine_type,
+            ..self
+        }
+    }
+
+    ///
+    /// Emulate each gadget once found, and discard those which fault or loop. The gadgets with an
+    /// instruction the emulator does not model are kept, unless `emulate_strict` is set.
+    ///
+    pub fn emulate(self, emulate: bool) -> Self {
+        Self { emulate, ..self }
+    }
+
+    ///
+    /// When emulating, also discard the gadgets with an instruction the emulator does not model.
+    /// By default they are kept, as they could not be shown to fail.
+    ///
+    pub fn emulate_strict(self, emulate_strict: bool) -> Self {
+        Self {
+            emulate_strict,
+            ..self
+        }
+    }
+
+    ///
+    /// Load the gadgets from (and store them to) an on-disk cache in the given directory
+    ///
+    pub fn cache(self, directory: PathBuf) -> Self {
+        Self {
+            cache: Some(directory),
+            ..self
+        }
+    }
+
+    ///
+    /// Size of the chunks the sections are split into for the workers (rounded up to the
+    /// instruction alignment of the CPU). The gadgets found do not depend on it.
+    ///
+    pub fn chunk_size(self, chunk_size: usize) -> Self {
+        Self { chunk_size, ..self }
+    }
+
+    ///
+    /// Only keep the gadgets inside a function whose name matches the glob pattern (`*` and `?`),
+    /// e.g. `__libc_csu_*`. The gadgets outside of any known function are discarded.
+    ///
+    pub fn symbol_filter(self, pattern: &str) -> Self {
+        Self {
+            symbol_filter: Some(pattern.to_string()),
+            ..self
+        }
+    }
+
+    ///
+    /// Whether an address inside the given function (if any) passes the symbol filter
+    ///
+    pub fn keeps_symbol(&self, symbol: Option<&SymbolOffset>) -> bool {
+        match (&self.symbol_filter, symbol) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(pattern), Some(symbol)) => {
+                let pattern: Vec<char> = pattern.chars().collect();
+                let name: Vec<char> = symbol.name.chars().collect();
+                search::glob_matches(&pattern, &name)
+            }
+        }
+    }
+
     pub fn filepath(&self) -> &PathBuf {
         &self.info.filepath
     }
+
+    ///
+    /// Whether all the gadgets must be known before being written (to group, sort or cache them)
+    ///
+    pub fn needs_all_gadgets(&self) -> bool {
+        self.unique_only
+            || self.merge_equivalent
+            || self.order != RopGadgetOrder::Found
+            || self.cache.is_some()
+    }
 }
 
 impl Default for Session {
@@ -200,10 +487,19 @@ impl Default for Session {
             nb_thread: 4,
             output: RopGadgetOutput::None,
             unique_only: true,
+            merge_equivalent: false,
             use_color: true,
+            render_options: RenderOptions::default(),
             max_gadget_length: 6,
+            max_gadget_size: 32,
             gadget_types: vec![InstructionGroup::Ret],
             profile_type: RopProfileStrategy::Fast,
+            emulate: false,
+            emulate_strict: false,
+            order: RopGadgetOrder::Address,
+            cache: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            symbol_filter: None,
             gadgets: Mutex::new(Vec::new()),
             engine_type: DisassemblyEngineType::Capstone,
             info: ExecutableDetails::default(),
@@ -226,104 +522,230 @@ impl std::fmt::Display for Session {
         let gadget_types: Vec<String> = self.gadget_types.iter().map(|x| x.to_string()).collect();
         write!(
             f,
-            "Session(File='{}', {}, Profile={}, GadgetTypes=[{}])",
-            self.filepath().to_str().unwrap(),
+            "Session(File='{}', {}, Profile={}, GadgetTypes=[{}], MaxInsns={}, MaxSize={})",
+            self.filepath().display(),
             self.info,
             self.profile_type,
             gadget_types.join(", "),
+            self.max_gadget_length,
+            self.max_gadget_size,
         )
     }
 }
 
 ///
-/// This function manages the thread pool to look for gadget
+/// Default size of the chunks the executable sections are split into. A chunk is the unit of
+/// work of the scanner: small enough to keep all the threads busy until the end of the scan, big
+/// enough for the scheduling cost to be negligible
 ///
-pub fn find_gadgets(session: Arc<Session>) -> GenericResult<()> {
-    let info = &session.info;
-    let number_of_sections = info.format.sections().len();
-    let nb_thread = session.nb_thread as usize;
+pub const DEFAULT_CHUNK_SIZE: usize = 0x10000;
 
-    debug!("Using {nb_thread} threads over {number_of_sections} section(s) of executable code...");
+///
+/// Number of chunks a worker can be ahead of the oldest chunk not yet passed to the consumer,
+/// per thread. This bounds the number of chunks waiting to be reordered, hence the memory used
+/// by a scan
+///
+const CHUNKS_AHEAD_PER_THREAD: usize = 4;
 
-    //
-    // Multithread parsing of each section
-    //
-    for section_idx in 0..number_of_sections {
-        if info.format.sections().get(section_idx).is_none() {
+///
+/// A range of a section to look for gadgets in. `index` is the position of the chunk in the
+/// whole scan, and gives the order in which the results are merged
+///
+#[derive(Debug, Copy, Clone)]
+struct Chunk {
+    index: usize,
+    section: usize,
+    start: usize,
+    end: usize,
+}
+
+///
+/// Sliding window over the chunk indexes, which keeps the workers from getting too far ahead of
+/// the consumer
+///
+struct ChunkWindow {
+    size: usize,
+    state: Mutex<(usize, bool)>,
+    cond: Condvar,
+}
+
+impl ChunkWindow {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            state: Mutex::new((0, false)),
+            cond: Condvar::new(),
+        }
+    }
+
+    ///
+    /// Block until the chunk at `index` can be processed. Returns false if the scan was stopped.
+    ///
+    fn wait_for(&self, index: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.1 && index >= state.0 + self.size {
+            state = self.cond.wait(state).unwrap();
+        }
+        !state.1
+    }
+
+    fn advance(&self, next: usize) {
+        self.state.lock().unwrap().0 = next;
+        self.cond.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.cond.notify_all();
+    }
+}
+
+///
+/// Split all the executable sections in chunks of at most `chunk_size` bytes, and queue them.
+/// Chunks start on an instruction boundary, so each terminating instruction belongs to exactly
+/// one chunk.
+///
+fn queue_chunks(info: &ExecutableDetails, chunk_size: usize) -> Injector<Chunk> {
+    let step = std::cmp::max(info.cpu.insn_step(), 1);
+    let chunk_size = std::cmp::max(chunk_size, 1).div_ceil(step) * step;
+    let queue = Injector::new();
+    let mut index = 0;
+    for (section_idx, section) in info.format.sections().iter().enumerate() {
+        if !section.is_executable() {
             continue;
         }
 
-        let section = info.format.sections().get(section_idx).unwrap();
-        let chunk_size = section.data.len() / nb_thread;
+        let mut start = 0;
+        while start < section.data.len() {
+            let end = std::cmp::min(start.saturating_add(chunk_size), section.data.len());
+            queue.push(Chunk {
+                index,
+                section: section_idx,
+                start,
+                end,
+            });
+            index += 1;
+            start = end;
+        }
+    }
+    queue
+}
+
+///
+/// This function manages the thread pool to look for gadget, and passes each of them to
+/// `on_gadget` as soon as it is found. All the sections are split in fixed-size chunks put in a
+/// single queue, from which a fixed set of workers steal until it is empty. The results of the
+/// chunks are reordered, so gadgets are always passed in the same order (section by section,
+/// chunk by chunk) whatever the number of threads. The scan stops early if `on_gadget` returns
+/// false. Returns the number of gadgets found.
+///
+pub fn scan_gadgets<F>(session: Arc<Session>, mut on_gadget: F) -> GenericResult<usize>
+where
+    F: FnMut(Gadget) -> bool,
+{
+    let number_of_sections = session
+        .info
+        .format
+        .sections()
+        .iter()
+        .filter(|s| s.is_executable())
+        .count();
+    let nb_thread = std::cmp::max(session.nb_thread as usize, 1);
+    let chunks = queue_chunks(&session.info, session.chunk_size);
+    let window = ChunkWindow::new(nb_thread * CHUNKS_AHEAD_PER_THREAD);
+
+    debug!(
+        "Using {} threads over {} chunk(s) of {} section(s) of executable code...",
+        nb_thread,
+        chunks.len(),
+        number_of_sections
+    );
+
+    thread::scope(|scope| {
+        let (sender, receiver) = sync_channel(nb_thread);
+
+        let workers: Vec<_> = (0..nb_thread)
+            .map(|_| {
+                let sender = sender.clone();
+                let session = &session;
+                let chunks = &chunks;
+                let window = &window;
+                scope.spawn(move || thread_worker(session, chunks, window, sender))
+            })
+            .collect();
+        drop(sender);
 
         //
-        // Fill the thread pool
+        // the results of the chunks completed out of order wait here until their turn comes
         //
-        let mut threads: Vec<std::thread::JoinHandle<Vec<Gadget>>> = Vec::new();
-        let mut pos = 0;
-        let mut thread_pool_size = 0;
-        let mut force_flush = false;
+        let mut pending: BTreeMap<usize, Vec<Gadget>> = BTreeMap::new();
+        let mut next = 0;
+        let mut nb_gadgets = 0;
 
-        loop {
-            //
-            // Empty the thread pool if necessary
-            //
-            if thread_pool_size == nb_thread || force_flush {
-                for curthread in threads {
-                    debug!("Joining {:?}...", curthread.thread().id());
-                    let gadgets = curthread.join().unwrap();
-                    {
-                        let mut data = session.gadgets.lock().unwrap();
-                        data.extend(gadgets);
+        'receive: for (index, gadgets) in receiver.iter() {
+            pending.insert(index, gadgets);
+            while let Some(gadgets) = pending.remove(&next) {
+                next += 1;
+                window.advance(next);
+                for gadget in gadgets {
+                    nb_gadgets += 1;
+                    if !on_gadget(gadget) {
+                        break 'receive;
                     }
                 }
-
-                threads = Vec::new();
-                thread_pool_size = 0;
-
-                if force_flush {
-                    break;
-                }
             }
+        }
 
-            //
-            // Is there still some data to parse?
-            //
-            if pos >= section.data.len() {
-                force_flush = true;
-                continue;
-            }
+        //
+        // make the workers stop at their next chunk if they were not done
+        //
+        window.stop();
+        drop(receiver);
 
-            //
-            // If so, spawn more workers
-            //
-            let rc_session = Arc::clone(&session);
-            let thread = thread::spawn(move || thread_worker(rc_session, section_idx, pos));
-            debug!(
-                "Spawning {:?} (pos={} section_index={})...",
-                thread.thread().id(),
-                pos,
-                section_idx
-            );
-            threads.push(thread);
-            thread_pool_size += 1;
-            pos += chunk_size;
+        for worker in workers {
+            debug!("Joining {:?}...", worker.thread().id());
+            worker.join()??;
         }
-    }
 
-    info!(
-        "Total gadgets found => {}",
-        session.gadgets.lock().unwrap().len()
-    );
+        Ok(nb_gadgets)
+    })
+}
+
+///
+/// Look for all the gadgets, and store them in the session
+///
+pub fn find_gadgets(session: Arc<Session>) -> GenericResult<()> {
+    let mut gadgets = Vec::new();
+    scan_gadgets(session.clone(), |gadget| {
+        gadgets.push(gadget);
+        true
+    })?;
+
+    let mut data = session.gadgets.lock().unwrap();
+    data.extend(gadgets);
+    info!("Total gadgets found => {}", data.len());
     Ok(())
 }
 
 ///
-/// Worker routine to search for gadgets
+/// Worker routine to search for gadgets: steal chunks from the queue until it is empty, and send
+/// back the gadgets of each of them. The disassembly engine is created once for all the chunks.
 ///
-fn thread_worker(session: Arc<Session>, index: usize, cursor: usize) -> Vec<Gadget> {
+fn thread_worker(
+    session: &Arc<Session>,
+    chunks: &Injector<Chunk>,
+    window: &ChunkWindow,
+    sender: SyncSender<(usize, Vec<Gadget>)>,
+) -> GenericResult<()> {
     let cpu = session.info.cpu.as_ref();
-    let engine = DisassemblyEngine::new(&session.engine_type, cpu);
+    let engine = match DisassemblyEngine::new(&session.engine_type, cpu) {
+        Ok(engine) => engine,
+        Err(e) => {
+            window.stop();
+            return Err(e);
+        }
+    };
+    let emulator = Emulator::new(cpu.cpu_type()).keep_unsupported(!session.emulate_strict);
     debug!(
         "{:?}: Initialized engine {} for {:?}",
         thread::current().id(),
@@ -331,55 +753,415 @@ fn thread_worker(session: Arc<Session>, index: usize, cursor: usize) -> Vec<Gadg
         cpu.cpu_type()
     );
 
-    let mut gadgets: Vec<Gadget> = Vec::new();
-    let sections = session.info.format.sections();
-    if let Some(section) = sections.get(index) {
-        let section_name = section
-            .name
-            .as_ref()
-            .unwrap_or(String::from("N/A").borrow())
-            .clone();
-
-        debug!(
-            "{:?}: Processing section '{}'",
-            thread::current().id(),
-            section_name
-        );
+    loop {
+        let chunk = match chunks.steal() {
+            Steal::Success(chunk) => chunk,
+            Steal::Retry => continue,
+            Steal::Empty => break,
+        };
 
-        let cpu = &session.info.cpu;
-        let disass = engine.disassembler.as_ref();
+        if !window.wait_for(chunk.index) {
+            debug!("{:?}: Scan was stopped, ending...", thread::current().id());
+            return Ok(());
+        }
 
-        for (pos, len) in
-            get_all_valid_positions_and_length(&session, cpu, section, cursor).unwrap()
-        {
-            debug!(
-                "{:?}: Processing Section {}[..{:x}+{:x}] (size={:x})",
+        //
+        // on error, the other workers are stopped too as the chunk will never be sent
+        //
+        let gadgets = match scan_chunk(session, &engine, &emulator, &chunk) {
+            Ok(gadgets) => gadgets,
+            Err(e) => {
+                window.stop();
+                return Err(e);
+            }
+        };
+
+        if sender.send((chunk.index, gadgets)).is_err() {
+            debug!("{:?}: Consumer is gone, ending...", thread::current().id());
+            return Ok(());
+        }
+    }
+
+    debug!("{:?}: No more chunk to process", thread::current().id());
+    Ok(())
+}
+
+///
+/// Collect the gadgets whose terminating instruction is in the given chunk
+///
+fn scan_chunk(
+    session: &Arc<Session>,
+    engine: &DisassemblyEngine,
+    emulator: &Emulator,
+    chunk: &Chunk,
+) -> GenericResult<Vec<Gadget>> {
+    let cpu = session.info.cpu.as_ref();
+    let disass = engine.disassembler.as_ref();
+    let symbols = session.info.format.symbols();
+    let mut gadgets = Vec::new();
+    let mut cache = InstructionCache::new();
+
+    let section = match session.info.format.sections().get(chunk.section) {
+        Some(section) => section,
+        None => {
+            warn!(
+                "{:?}: No section at index {}, skipping...",
                 thread::current().id(),
-                section_name,
-                pos,
-                len,
-                section.size(),
+                chunk.section,
             );
+            return Ok(gadgets);
+        }
+    };
+
+    let section_name = section.name.as_deref().unwrap_or("N/A");
+    debug!(
+        "{:?}: Processing section '{}'[{:x}..{:x}] (chunk #{})",
+        thread::current().id(),
+        section_name,
+        chunk.start,
+        chunk.end,
+        chunk.index,
+    );
+
+    let positions =
+        get_all_valid_positions_and_length(session, cpu, section, chunk.start, chunk.end)?;
+    for (pos, len) in positions {
+        let found =
+            find_gadgets_from_position(session, disass, &mut cache, section, pos, len, cpu)?;
+        gadgets.extend(
+            found
+                .into_iter()
+                .map(|gadget| Gadget {
+                    symbol: symbols.lookup(gadget.address),
+                    ..gadget
+                })
+                .filter(|gadget| session.keeps_symbol(gadget.symbol.as_ref()))
+                .filter(|gadget| !session.emulate || emulator.verify(gadget)),
+        );
+    }
+
+    Ok(gadgets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINARIES: [&str; 4] = [
+        "tests/bin/small-x86.pe",
+        "tests/bin/small-x64.elf",
+        "tests/bin/small-arm64.pe",
+        "tests/bin/small-x64.macho",
+    ];
+
+    fn scan(fname: &str, nb_thread: u8, chunk_size: usize) -> Vec<Gadget> {
+        //
+        // look for all the (multi-byte) terminating instructions, which may cross chunks
+        //
+        let sess = Session::new(PathBuf::from(fname))
+            .unwrap()
+            .nb_thread(nb_thread)
+            .chunk_size(chunk_size)
+            .profile_type(RopProfileStrategy::Complete)
+            .gadget_types(vec![
+                InstructionGroup::Ret,
+                InstructionGroup::Jump,
+                InstructionGroup::Call,
+            ]);
+
+        let mut gadgets = Vec::new();
+        scan_gadgets(Arc::new(sess), |gadget| {
+            gadgets.push(gadget);
+            true
+        })
+        .unwrap();
+        gadgets
+    }
+
+    fn find(sess: Session) -> Vec<Gadget> {
+        let sess = Arc::new(sess);
+        find_gadgets(sess.clone()).unwrap();
+        let gadgets = sess.gadgets.lock().unwrap().clone();
+        gadgets
+    }
 
-            let res = find_gadgets_from_position(session.clone(), disass, section, pos, len, cpu);
-            if res.is_ok() {
-                let mut gadget = res.unwrap();
-                gadgets.append(&mut gadget);
+    #[test]
+    fn limits_change_the_results() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let default = find(Session::new(fname.clone()).unwrap());
+        assert!(default.iter().any(|g| g.insns.len() > 2));
+        assert!(default.iter().any(|g| g.size > 8));
+
+        let short = find(Session::new(fname.clone()).unwrap().max_gadget_length(2));
+        assert!(!short.is_empty() && short.len() < default.len());
+        assert!(short.iter().all(|g| g.insns.len() <= 2));
+
+        let small = find(Session::new(fname.clone()).unwrap().max_gadget_size(8));
+        assert!(!small.is_empty() && small.len() < default.len());
+        assert!(small.iter().all(|g| g.size <= 8));
+
+        //
+        // raising the limits finds longer gadgets
+        //
+        let long = find(
+            Session::new(fname)
+                .unwrap()
+                .max_gadget_length(12)
+                .max_gadget_size(64),
+        );
+        assert!(long.len() > default.len());
+        assert!(long.iter().any(|g| g.insns.len() > 6));
+    }
+
+    #[test]
+    fn types_and_profile_change_the_results() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let is_ret = |g: &Gadget| g.insns.last().unwrap().group == InstructionGroup::Ret;
+
+        let fast = find(Session::new(fname.clone()).unwrap());
+        assert!(fast.iter().all(is_ret));
+
+        let complete = find(
+            Session::new(fname.clone())
+                .unwrap()
+                .profile_type(RopProfileStrategy::Complete),
+        );
+        assert!(complete.len() > fast.len());
+        assert!(complete.iter().all(is_ret));
+
+        let jumps = find(
+            Session::new(fname)
+                .unwrap()
+                .profile_type(RopProfileStrategy::Complete)
+                .gadget_types(vec![InstructionGroup::Jump, InstructionGroup::Call]),
+        );
+        assert!(!jumps.is_empty());
+        assert!(!jumps.iter().any(is_ret));
+
+        //
+        // the fast profile looks for the first terminating instruction of every type
+        //
+        let fast_jumps = find(
+            Session::new(PathBuf::from("tests/bin/small-x64.pe"))
+                .unwrap()
+                .gadget_types(vec![InstructionGroup::Ret, InstructionGroup::Jump]),
+        );
+        assert!(fast_jumps.iter().any(is_ret));
+        assert!(!fast_jumps.iter().all(is_ret));
+    }
+
+    #[test]
+    fn same_gadgets_whatever_the_number_of_threads() {
+        for fname in BINARIES {
+            let reference = scan(fname, 1, 0x200);
+            assert!(!reference.is_empty(), "no gadget in {}", fname);
+            for nb_thread in [2, 3, 8] {
+                assert_eq!(
+                    reference,
+                    scan(fname, nb_thread, 0x200),
+                    "{} with {} threads",
+                    fname,
+                    nb_thread
+                );
             }
         }
+    }
 
-        debug!(
-            "{:?}: Finished processing section '{}'",
-            thread::current().id(),
-            section_name,
-        );
-    } else {
-        warn!(
-            "{:?}: No section at index {}, ending...",
-            thread::current().id(),
-            index,
+    #[test]
+    fn gadgets_are_linear_sequences() {
+        for fname in BINARIES {
+            let sess = Session::new(PathBuf::from(fname)).unwrap();
+            let engine = DisassemblyEngine::new(&sess.engine_type, sess.info.cpu.as_ref()).unwrap();
+            for gadget in scan(fname, 4, DEFAULT_CHUNK_SIZE) {
+                //
+                // decoding the gadget from its start gives back the same instructions, the last
+                // one being the only branch
+                //
+                let insns = engine
+                    .disassembler
+                    .disassemble(&gadget.raw, gadget.address)
+                    .unwrap();
+                assert_eq!(insns, gadget.insns, "{} at {:#x}", fname, gadget.address);
+                assert!(gadget.insns.len() <= sess.max_gadget_length);
+                assert!(gadget.size <= sess.max_gadget_size);
+            }
+        }
+    }
+
+    #[test]
+    fn architecture_override() {
+        let fname = PathBuf::from("tests/bin/small-x64.pe");
+        let native = find(Session::new(fname.clone()).unwrap());
+        let forced = Session::new(fname.clone())
+            .unwrap()
+            .architecture(cpu::CpuType::X86);
+        assert_eq!(forced.info.cpu.cpu_type(), cpu::CpuType::X86);
+        let forced = find(forced);
+        assert!(!forced.is_empty());
+        assert_ne!(native, forced);
+        assert_eq!(
+            forced,
+            find(Session::with_architecture(fname, cpu::CpuType::X86).unwrap())
         );
+
+        //
+        // Windows on ARM is Thumb only, so are its gadgets
+        //
+        let fname = PathBuf::from("tests/bin/small-arm32.pe");
+        let thumb = Session::new(fname.clone()).unwrap();
+        assert_eq!(thumb.info.cpu.cpu_type(), cpu::CpuType::Thumb);
+        assert!(!find(thumb).is_empty());
+        assert!(find(Session::with_architecture(fname, cpu::CpuType::ARM).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn unsupported_machine_with_override() {
+        let fname = PathBuf::from("tests/bin/small-x64.elf");
+        let mut content = std::fs::read(&fname).unwrap();
+
+        //
+        // pretend to be a MIPS executable
+        //
+        content[18..20].copy_from_slice(&goblin::elf::header::EM_MIPS.to_le_bytes());
+        let patched =
+            std::env::temp_dir().join(format!("ropgadget-rs-mips-{}", std::process::id()));
+        std::fs::write(&patched, content).unwrap();
+
+        //
+        // without the override, the machine is reported instead of panicking
+        //
+        match Session::new(patched.clone()) {
+            Err(e @ Error::UnsupportedArchitecture { .. }) => {
+                assert!(e.to_string().contains(&patched.display().to_string()));
+            }
+            res => panic!("unexpected result: {:?}", res.map(|s| s.to_string())),
+        }
+
+        let native = find(Session::new(fname).unwrap());
+        let forced = find(Session::with_architecture(patched.clone(), cpu::CpuType::X64).unwrap());
+        assert_eq!(native, forced);
+
+        std::fs::remove_file(patched).unwrap();
+    }
+
+    #[cfg(feature = "iced")]
+    #[test]
+    fn same_gadgets_with_iced_and_capstone() {
+        let x86_binaries = [
+            "tests/bin/small-x86.pe",
+            "tests/bin/small-x86.elf",
+            "tests/bin/small-x64.elf",
+            "tests/bin/small-x64.pe",
+        ];
+
+        for fname in x86_binaries {
+            let scan_with = |engine_type| {
+                let sess = Session::new(PathBuf::from(fname))
+                    .unwrap()
+                    .engine_type(engine_type)
+                    .profile_type(RopProfileStrategy::Complete)
+                    .gadget_types(vec![
+                        InstructionGroup::Ret,
+                        InstructionGroup::Jump,
+                        InstructionGroup::Call,
+                    ]);
+                let gadgets: BTreeMap<(u64, Vec<u8>), Gadget> = find(sess)
+                    .into_iter()
+                    .map(|g| ((g.address, g.raw.clone()), g))
+                    .collect();
+                gadgets
+            };
+            let capstone = scan_with(DisassemblyEngineType::Capstone);
+            let iced = scan_with(DisassemblyEngineType::Iced);
+
+            //
+            // the decoders only disagree on a few undocumented or invalid encodings
+            //
+            let common: Vec<_> = capstone.keys().filter(|k| iced.contains_key(*k)).collect();
+            assert!(common.len() * 100 >= capstone.len() * 99, "{}", fname);
+            assert!(common.len() * 100 >= iced.len() * 99, "{}", fname);
+
+            //
+            // for almost all of them, the instructions are the same, and so are the operands of
+            // those printed the same way
+            //
+            let sizes = |g: &Gadget| g.insns.iter().map(|i| i.size).collect::<Vec<_>>();
+            let terminator = |g: &Gadget| g.insns.last().map(|i| i.group);
+            let same_insns = common
+                .iter()
+                .filter(|key| sizes(&capstone[key]) == sizes(&iced[key]))
+                .filter(|key| terminator(&capstone[key]) == terminator(&iced[key]))
+                .count();
+            assert!(same_insns * 100 >= common.len() * 99, "{}", fname);
+
+            let same_text: Vec<_> = common
+                .iter()
+                .flat_map(|key| capstone[key].insns.iter().zip(&iced[key].insns))
+                .filter(|(a, b)| a.mnemonic == b.mnemonic && a.operands == b.operands)
+                .collect();
+            let same_ops = same_text.iter().filter(|(a, b)| a.ops == b.ops).count();
+            assert!(same_ops * 1000 >= same_text.len() * 999, "{}", fname);
+        }
+
+        let sess = Session::new(PathBuf::from("tests/bin/small-arm64.elf"))
+            .unwrap()
+            .engine_type(DisassemblyEngineType::Iced);
+        assert!(matches!(
+            find_gadgets(Arc::new(sess)),
+            Err(Error::EngineError { .. })
+        ));
+    }
+
+    #[test]
+    fn errors_instead_of_panics() {
+        assert!(matches!(
+            Session::new(PathBuf::from("tests/bin/does-not-exist")),
+            Err(Error::InvalidFile { .. })
+        ));
+        assert!(matches!(
+            Session::new(PathBuf::from("Cargo.toml")),
+            Err(Error::InvalidFile { .. })
+        ));
+
+        let sess = Session::new(PathBuf::from("tests/bin/small-x64.elf"))
+            .unwrap()
+            .gadget_types(vec![InstructionGroup::Ret, InstructionGroup::Int]);
+        assert!(matches!(
+            find_gadgets(Arc::new(sess)),
+            Err(Error::UnsupportedGadgetType(InstructionGroup::Int))
+        ));
+
+        assert!(matches!(Gadget::new(Vec::new()), Err(Error::EmptyGadget)));
+        assert!(matches!(
+            DisassemblyEngine::new(&DisassemblyEngineType::Invalid, &cpu::x86::X64 {}),
+            Err(Error::EngineError { .. })
+        ));
     }
 
-    gadgets
+    #[test]
+    fn same_gadgets_whatever_the_chunk_size() {
+        let sorted = |gadgets: Vec<Gadget>| {
+            let mut gadgets: Vec<(u64, Vec<u8>)> =
+                gadgets.into_iter().map(|g| (g.address, g.raw)).collect();
+            gadgets.sort();
+            gadgets
+        };
+
+        for fname in BINARIES {
+            //
+            // a single chunk per section is the reference: no gadget may be lost or duplicated
+            // at the boundaries of smaller chunks, even those not aligned on instructions
+            //
+            let reference = sorted(scan(fname, 1, 1 << 30));
+            for chunk_size in [7, 0x41, 0x1000] {
+                assert_eq!(
+                    reference,
+                    sorted(scan(fname, 4, chunk_size)),
+                    "{} with chunks of {:#x} bytes",
+                    fname,
+                    chunk_size
+                );
+            }
+        }
+    }
 }
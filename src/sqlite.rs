@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+
+use log::info;
+use rusqlite::{params, Connection};
+
+use crate::common::GenericResult;
+use crate::error::Error;
+use crate::gadget::Gadget;
+use crate::output::{write_all, GadgetWriter};
+use crate::session::ExecutableDetails;
+
+/**
+ *
+ * Export gadgets to a SQLite database, so that large binaries can be queried instead of grepped.
+ * Several files can share the same database: exporting a file again replaces its previous rows.
+ *
+ * Schema (addresses are stored as signed 64-bit integers, as SQLite has no unsigned type):
+ *
+ *  - `files`: one row per exported executable (path, format, cpu, pointer size, entry point,
+ *    image base)
+ *  - `sections`: the executable sections of each file (name, addresses, file offset,
+ *    permission bits as in `section::Permission`)
+ *  - `gadgets`: one row per gadget, with its canonical address, RVA, raw bytes, text, terminator
+ *    group, size and score. `section_id` is NULL if the section could not be determined,
+ *    `symbol` and `symbol_offset` if the gadget is in no known function.
+ *  - `gadget_addresses`: all the addresses (and RVAs) a gadget was found at when grouped
+ *  - `instructions`: the instructions of each gadget, in order (`position` starts at 0)
+ *
+ * Indexes exist on instruction mnemonic and operands, gadget address, terminator and symbol, and
+ * on `gadget_addresses.address`. For instance, to find a `pop rdi`:
+ *
+ * ```sql
+ * SELECT printf('0x%x', g.address), g.text FROM gadgets g
+ *   JOIN instructions i ON i.gadget_id = g.id
+ *   WHERE i.position = 0 AND i.mnemonic = 'pop' AND i.operands = 'rdi'
+ *   ORDER BY g.score DESC;
+ * ```
+ *
+ */
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS files (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    format TEXT NOT NULL,
+    cpu TEXT NOT NULL,
+    ptrsize INTEGER NOT NULL,
+    entry_point INTEGER NOT NULL,
+    image_base INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS sections (
+    id INTEGER PRIMARY KEY,
+    file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    name TEXT,
+    start_address INTEGER NOT NULL,
+    end_address INTEGER NOT NULL,
+    file_offset INTEGER NOT NULL,
+    permission INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS gadgets (
+    id INTEGER PRIMARY KEY,
+    file_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+    section_id INTEGER REFERENCES sections(id) ON DELETE CASCADE,
+    address INTEGER NOT NULL,
+    rva INTEGER NOT NULL,
+    raw BLOB NOT NULL,
+    text TEXT NOT NULL,
+    terminator TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    score INTEGER NOT NULL,
+    symbol TEXT,
+    symbol_offset INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS gadget_addresses (
+    gadget_id INTEGER NOT NULL REFERENCES gadgets(id) ON DELETE CASCADE,
+    address INTEGER NOT NULL,
+    rva INTEGER NOT NULL,
+    PRIMARY KEY (gadget_id, address)
+);
+
+CREATE TABLE IF NOT EXISTS instructions (
+    gadget_id INTEGER NOT NULL REFERENCES gadgets(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    address INTEGER NOT NULL,
+    raw BLOB NOT NULL,
+    mnemonic TEXT NOT NULL,
+    operands TEXT,
+    PRIMARY KEY (gadget_id, position)
+);
+
+CREATE INDEX IF NOT EXISTS idx_gadgets_file ON gadgets(file_id);
+CREATE INDEX IF NOT EXISTS idx_gadgets_address ON gadgets(address);
+CREATE INDEX IF NOT EXISTS idx_gadgets_terminator ON gadgets(terminator);
+CREATE INDEX IF NOT EXISTS idx_gadgets_symbol ON gadgets(symbol);
+CREATE INDEX IF NOT EXISTS idx_gadget_addresses_address ON gadget_addresses(address);
+CREATE INDEX IF NOT EXISTS idx_instructions_mnemonic ON instructions(mnemonic);
+CREATE INDEX IF NOT EXISTS idx_instructions_operands ON instructions(operands);
+";
+
+///
+/// Writer inserting the gadgets in the database at the given path, in a single transaction
+///
+pub struct SqliteWriter {
+    dbpath: PathBuf,
+    conn: Option<Connection>,
+    file_id: i64,
+    section_ids: Vec<i64>,
+    count: usize,
+}
+
+impl SqliteWriter {
+    pub fn new(dbpath: PathBuf) -> Self {
+        Self {
+            dbpath,
+            conn: None,
+            file_id: 0,
+            section_ids: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn connection(&self) -> GenericResult<&Connection> {
+        self.conn
+            .as_ref()
+            .ok_or_else(|| Error::DatabaseError(rusqlite::Error::InvalidQuery))
+    }
+}
+
+impl GadgetWriter for SqliteWriter {
+    fn begin(&mut self, info: &ExecutableDetails) -> GenericResult<()> {
+        let conn = Connection::open(&self.dbpath)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute_batch("BEGIN;")?;
+
+        //
+        // replace the previous export of the same file, if any
+        //
+        let path = info.filepath.display().to_string();
+        conn.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+        conn.execute(
+            "INSERT INTO files (path, format, cpu, ptrsize, entry_point, image_base)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                path,
+                format!("{:?}", info.format.format()),
+                format!("{:?}", info.cpu.cpu_type()),
+                info.cpu.ptrsize() as i64,
+                info.format.entry_point() as i64,
+                info.format.image_base() as i64,
+            ],
+        )?;
+        self.file_id = conn.last_insert_rowid();
+
+        self.section_ids.clear();
+        for section in info.format.sections() {
+            conn.execute(
+                "INSERT INTO sections (file_id, name, start_address, end_address, file_offset, permission)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    self.file_id,
+                    section.name,
+                    section.start_address as i64,
+                    section.end_address as i64,
+                    section.file_offset as i64,
+                    section.permission.bits(),
+                ],
+            )?;
+            self.section_ids.push(conn.last_insert_rowid());
+        }
+
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    fn write(&mut self, info: &ExecutableDetails, gadget: &Gadget) -> GenericResult<()> {
+        self.count += 1;
+        let conn = self.connection()?;
+        let section_id = info
+            .format
+            .sections()
+            .iter()
+            .position(|s| s.contains(gadget.address))
+            .map(|idx| self.section_ids[idx]);
+        let terminator = gadget
+            .insns
+            .last()
+            .map(|i| i.group.to_string())
+            .unwrap_or_default();
+
+        conn.prepare_cached(
+            "INSERT INTO gadgets (file_id, section_id, address, rva, raw, text, terminator, size, score,
+                symbol, symbol_offset)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        )?
+        .execute(params![
+            self.file_id,
+            section_id,
+            gadget.address as i64,
+            info.format.rva(gadget.address) as i64,
+            gadget.raw,
+            gadget.text(false),
+            terminator,
+            gadget.size as i64,
+            gadget.score(info.cpu.cpu_type()),
+            gadget.symbol.as_ref().map(|s| &s.name),
+            gadget.symbol.as_ref().map(|s| s.offset as i64),
+        ])?;
+        let gadget_id = conn.last_insert_rowid();
+
+        let mut insert_address = conn.prepare_cached(
+            "INSERT OR IGNORE INTO gadget_addresses (gadget_id, address, rva) VALUES (?1, ?2, ?3)",
+        )?;
+        for address in &gadget.addresses {
+            insert_address.execute(params![
+                gadget_id,
+                *address as i64,
+                info.format.rva(*address) as i64
+            ])?;
+        }
+
+        let mut insert_insn = conn.prepare_cached(
+            "INSERT INTO instructions (gadget_id, position, address, raw, mnemonic, operands)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for (position, insn) in gadget.insns.iter().enumerate() {
+            insert_insn.execute(params![
+                gadget_id,
+                position as i64,
+                insn.address as i64,
+                insn.raw,
+                insn.mnemonic,
+                insn.operands,
+            ])?;
+        }
+
+        Ok(())
+    }
+
+    fn end(&mut self, _info: &ExecutableDetails) -> GenericResult<()> {
+        self.connection()?.execute_batch("COMMIT;")?;
+        self.conn = None;
+        info!(
+            "Written {} gadgets to database '{}'",
+            self.count,
+            self.dbpath.display()
+        );
+        Ok(())
+    }
+}
+
+///
+/// Write the gadgets of the given executable to the database at `dbpath`, creating it if needed
+///
+pub fn write_sqlite(
+    dbpath: &Path,
+    info: &ExecutableDetails,
+    gadgets: &[Gadget],
+) -> GenericResult<()> {
+    write_all(&mut SqliteWriter::new(dbpath.to_path_buf()), info, gadgets)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect_all_gadgets;
+    use crate::session::Session;
+    use std::path::PathBuf;
+
+    #[test]
+    fn export_two_files() {
+        let dir = std::env::temp_dir().join(format!("ropgadget-rs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dbpath = dir.join("gadgets.db");
+        let _ = std::fs::remove_file(&dbpath);
+
+        let mut expected = 0;
+        for fname in ["tests/bin/small-x64.elf", "tests/bin/small-x86.pe"] {
+            let fname = PathBuf::from(fname);
+            let gadgets = collect_all_gadgets(Session::new(fname.clone()).unwrap()).unwrap();
+            let sess = Session::new(fname).unwrap();
+            write_sqlite(&dbpath, &sess.info, &gadgets).unwrap();
+            // exporting twice must not duplicate the rows
+            write_sqlite(&dbpath, &sess.info, &gadgets).unwrap();
+            expected += gadgets.len();
+        }
+
+        let conn = Connection::open(&dbpath).unwrap();
+        let count = |sql: &str| -> i64 { conn.query_row(sql, [], |r| r.get(0)).unwrap() };
+        assert_eq!(count("SELECT COUNT(*) FROM files"), 2);
+        assert_eq!(count("SELECT COUNT(*) FROM gadgets"), expected as i64);
+        assert!(count("SELECT COUNT(*) FROM instructions WHERE mnemonic = 'ret'") > 0);
+        assert_eq!(
+            count("SELECT COUNT(*) FROM gadgets WHERE id NOT IN (SELECT gadget_id FROM gadget_addresses)"),
+            0
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
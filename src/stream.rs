@@ -0,0 +1,105 @@
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+use crate::common::GenericResult;
+use crate::gadget::Gadget;
+use crate::session::{self, Session};
+
+///
+/// Number of gadgets found but not consumed yet, after which the scan is paused
+///
+const STREAM_CAPACITY: usize = 4096;
+
+///
+/// Iterator over the gadgets of a session, yielded as the workers find them. The scan runs in
+/// the background and is paused whenever the consumer falls behind, so memory stays bounded
+/// whatever the size of the binary. Gadgets are neither grouped nor sorted.
+///
+pub struct GadgetStream {
+    pub(crate) session: Arc<Session>,
+    receiver: Option<Receiver<Gadget>>,
+    scanner: Option<thread::JoinHandle<GenericResult<usize>>>,
+}
+
+impl GadgetStream {
+    pub fn new(session: Arc<Session>) -> Self {
+        let (sender, receiver) = sync_channel(STREAM_CAPACITY);
+        let rc_session = Arc::clone(&session);
+        let scanner = thread::spawn(move || {
+            session::scan_gadgets(rc_session, |gadget| sender.send(gadget).is_ok())
+        });
+
+        Self {
+            session,
+            receiver: Some(receiver),
+            scanner: Some(scanner),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    ///
+    /// Stop the scan if it is not over, and return the number of gadgets found, or the error
+    /// which interrupted the scan
+    ///
+    pub fn finish(mut self) -> GenericResult<usize> {
+        self.receiver = None;
+        match self.scanner.take() {
+            Some(scanner) => scanner.join()?,
+            None => Ok(0),
+        }
+    }
+}
+
+impl Iterator for GadgetStream {
+    type Item = Gadget;
+
+    fn next(&mut self) -> Option<Gadget> {
+        self.receiver.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for GadgetStream {
+    fn drop(&mut self) {
+        self.receiver = None;
+        if let Some(scanner) = self.scanner.take() {
+            let _ = scanner.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn stream_matches_collect() {
+        let fname = PathBuf::from("tests/bin/small-x86.pe");
+
+        let sess = Arc::new(Session::new(fname.clone()).unwrap().nb_thread(3));
+        session::find_gadgets(sess.clone()).unwrap();
+        let collected = sess.gadgets.lock().unwrap().clone();
+
+        let mut stream = GadgetStream::new(Arc::new(Session::new(fname).unwrap().nb_thread(3)));
+        let streamed: Vec<Gadget> = stream.by_ref().collect();
+        assert_eq!(stream.finish().unwrap(), streamed.len());
+
+        //
+        // same gadgets, in the same order
+        //
+        assert_eq!(collected, streamed);
+    }
+
+    #[test]
+    fn stream_can_stop_early() {
+        let fname = PathBuf::from("tests/bin/small-x64.elf");
+        let mut stream = GadgetStream::new(Arc::new(Session::new(fname).unwrap()));
+        let first: Vec<Gadget> = stream.by_ref().take(10).collect();
+        assert_eq!(first.len(), 10);
+        assert!(stream.finish().is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+///
+/// A function of the executable, as found in its symbol or export tables
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    /// The size of the function, or 0 if unknown (e.g. PE exports and Mach-O symbols)
+    pub size: u64,
+}
+
+///
+/// The location of an address relative to the function containing it, e.g.
+/// `__libc_csu_init+0x5a`
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SymbolOffset {
+    pub name: String,
+    pub offset: u64,
+}
+
+impl fmt::Display for SymbolOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            0 => write!(f, "{}", self.name),
+            offset => write!(f, "{}+{:#x}", self.name, offset),
+        }
+    }
+}
+
+///
+/// The functions of an executable, sorted by address
+///
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    ///
+    /// Build the table from the symbols of all the tables of the executable. When several
+    /// symbols share an address (aliases, or the same function in `.symtab` and `.dynsym`), the
+    /// first one with a size is kept.
+    ///
+    pub fn new(symbols: Vec<Symbol>) -> Self {
+        let mut symbols: Vec<Symbol> = symbols
+            .into_iter()
+            .filter(|s| !s.name.is_empty() && s.address != 0)
+            .collect();
+
+        symbols.sort_by_key(|s| (s.address, s.size == 0));
+        symbols.dedup_by_key(|s| s.address);
+
+        Self { symbols }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    ///
+    /// The function containing the address: the closest symbol before it, as long as the
+    /// address is inside it when its size is known. The bytes after the end of a function (e.g.
+    /// padding, or data) belong to none.
+    ///
+    pub fn lookup(&self, address: u64) -> Option<SymbolOffset> {
+        let idx = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols.get(idx.checked_sub(1)?)?;
+
+        let offset = address - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        Some(SymbolOffset {
+            name: symbol.name.clone(),
+            offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, address: u64, size: u64) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            address,
+            size,
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let table = SymbolTable::new(vec![
+            symbol("exported", 0x3000, 0),
+            symbol("alias", 0x1000, 0),
+            symbol("main", 0x1000, 0x20),
+            symbol("", 0x2000, 0x10),
+            symbol("undefined", 0, 0),
+        ]);
+        assert_eq!(table.len(), 2);
+
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(table.lookup(0x1000).unwrap().to_string(), "main");
+        assert_eq!(table.lookup(0x101f).unwrap().to_string(), "main+0x1f");
+        assert_eq!(table.lookup(0x1020), None);
+        assert_eq!(table.lookup(0x3456).unwrap().to_string(), "exported+0x456");
+    }
+}